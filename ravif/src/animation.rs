@@ -0,0 +1,116 @@
+use crate::av1encoder::{AnimationState, EncodedImage, Encoder};
+use crate::error::Error;
+use imgref::Img;
+use rgb::{RGBA16, RGBA8};
+use std::time::Duration;
+
+/// Units per second of frame durations in the file
+const TIMESCALE: u32 = 1000;
+
+/// Encoder for animated AVIF (image sequence)
+///
+/// Frames are compressed as they're added, using the previous frames as references, so they don't need to be kept in memory.
+/// The settings of the [`Encoder`] apply to every frame, except target size, target SSIM, thumbnails, film grain,
+/// stats, reconstruction, and depth and gain maps, which aren't supported for animations and make adding the first frame fail.
+///
+/// Frames are always encoded in color, even if they're all gray.
+///
+/// ```rust
+/// use ravif::*;
+/// use std::time::Duration;
+/// # fn example(frames: &[(Vec<RGBA8>, Duration)], width: usize, height: usize) -> Result<(), Error> {
+/// let mut anim = AnimationEncoder::new(Encoder::new().with_quality(70.));
+/// for (pixels, delay) in frames {
+///     anim.add_frame(Img::new(pixels, width, height), *delay)?;
+/// }
+/// std::fs::write("hello.avif", anim.finish()?.avif_file);
+/// # Ok(()) }
+/// ```
+pub struct AnimationEncoder {
+    encoder: Encoder,
+    /// Started by the first frame
+    state: Option<AnimationState>,
+    /// Time since the start of the animation when each frame ends
+    end_times: Vec<Duration>,
+    /// `None` loops forever
+    repetitions: Option<u32>,
+}
+
+impl AnimationEncoder {
+    /// Start here
+    #[must_use]
+    pub fn new(encoder: Encoder) -> Self {
+        Self {
+            encoder,
+            state: None,
+            end_times: Vec::new(),
+            repetitions: None,
+        }
+    }
+
+    /// How many times the animation is played again after the first time.
+    ///
+    /// `None` (the default) loops forever. `Some(0)` plays the animation once.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_repetitions(mut self, repetitions: Option<u32>) -> Self {
+        self.repetitions = repetitions;
+        self
+    }
+
+    /// Adds the next frame (non-premultiplied RGBA), to be shown for `duration`, and encodes it.
+    ///
+    /// All frames must have the same size, at least 16x16.
+    pub fn add_frame(&mut self, frame: Img<&[RGBA8]>, duration: Duration) -> Result<(), Error> {
+        let state = start(&self.encoder, &mut self.state, frame.width(), frame.height())?;
+        self.encoder.add_animation_frame(state, frame)?;
+        self.push_duration(duration);
+        Ok(())
+    }
+
+    /// Like [`Self::add_frame`], but for 16-bit pixels
+    pub fn add_frame16(&mut self, frame: Img<&[RGBA16]>, duration: Duration) -> Result<(), Error> {
+        let state = start(&self.encoder, &mut self.state, frame.width(), frame.height())?;
+        self.encoder.add_animation_frame16(state, frame)?;
+        self.push_duration(duration);
+        Ok(())
+    }
+
+    fn push_duration(&mut self, duration: Duration) {
+        let start = self.end_times.last().copied().unwrap_or_default();
+        self.end_times.push(start + duration);
+    }
+
+    /// Number of frames added so far
+    #[must_use]
+    pub fn frame_count(&self) -> usize {
+        self.end_times.len()
+    }
+
+    /// Encodes the last frames, and makes the file.
+    ///
+    /// If all frames are opaque, the alpha track will be left out automatically.
+    /// The first frame is also stored as a still image for decoders that don't support animation.
+    pub fn finish(self) -> Result<EncodedImage, Error> {
+        let state = self.state.ok_or(Error::Unsupported("animation without frames"))?;
+        // Rounding end times instead of durations prevents drift when the durations aren't whole ticks.
+        // Every frame has to last at least one tick.
+        let mut prev_end = 0;
+        let durations: Vec<u32> = self.end_times.iter().map(|end| {
+            let end = ((end.as_secs_f64() * f64::from(TIMESCALE)).round() as u64).max(prev_end + 1);
+            let duration = u32::try_from(end - prev_end).unwrap_or(u32::MAX);
+            prev_end = end;
+            duration
+        }).collect();
+        self.encoder.finish_animation(state, &durations, TIMESCALE, self.repetitions)
+    }
+}
+
+/// The animation is started by the first frame, since it needs to know the size
+fn start<'a>(encoder: &Encoder, state: &'a mut Option<AnimationState>, width: usize, height: usize) -> Result<&'a mut AnimationState, Error> {
+    let started = match state.take() {
+        Some(started) => started,
+        None => encoder.start_animation(width, height)?,
+    };
+    Ok(state.insert(started))
+}
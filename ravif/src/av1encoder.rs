@@ -1,12 +1,21 @@
 #![allow(deprecated)]
 use crate::cancel::CancellationToken;
-use crate::dirtyalpha::blurred_dirty_alpha;
-use crate::error::Error;
+use crate::dirtyalpha::{blurred_dirty_alpha, blurred_dirty_alpha16};
+use crate::error::{EncodingErrorDetail, Error, Rav1eError, Stage};
+use crate::mux;
+use crate::progress::{EncodingPhase, Progress, ProgressCallback};
+use crate::reconstruction::{frame_to_rgba, paste, Reconstruction};
+use crate::rows::RowSource;
+use crate::ssim::{frame_ssim, plane_sse, plane_ssim};
 #[cfg(not(feature = "threading"))]
 use crate::rayoff as rayon;
 use imgref::{Img, ImgVec};
 use rav1e::prelude::*;
-use rgb::{RGB8, RGBA8};
+use rgb::{GrayA, RGB16, RGB8, RGBA16, RGBA8};
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Helper to check cancellation with minimal overhead
 /// Returns Error::Cancelled if cancellation is requested
@@ -32,7 +41,7 @@ fn check_cancellation(
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ColorModel {
     /// Standard color model for photographic content. Usually the best choice.
-    /// This library always uses full-resolution color (4:4:4).
+    /// This library uses full-resolution color (4:4:4), unless [`Encoder::with_chroma_subsampling`] is set.
     /// This library will automatically choose between BT.601 or BT.709.
     YCbCr,
     /// RGB channels are encoded without color space transformation.
@@ -41,6 +50,30 @@ pub enum ColorModel {
     RGB,
 }
 
+/// Resolution of color channels. For [`Encoder::with_chroma_subsampling`]
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Full-resolution color. Usually the best choice, since AV1 compresses it very efficiently.
+    #[default]
+    Cs444,
+    /// Color at half horizontal resolution. Requires AV1 Professional profile.
+    Cs422,
+    /// Color at half horizontal and half vertical resolution.
+    /// Blurs and discolors sharp edges, but it's the only mode supported by some hardware decoders.
+    Cs420,
+}
+
+impl ChromaSubsampling {
+    #[inline]
+    fn chroma_sampling(self) -> ChromaSampling {
+        match self {
+            Self::Cs444 => ChromaSampling::Cs444,
+            Self::Cs422 => ChromaSampling::Cs422,
+            Self::Cs420 => ChromaSampling::Cs420,
+        }
+    }
+}
+
 /// Handling of color channels in transparent images. For [`Encoder::with_alpha_color_mode`]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum AlphaColorMode {
@@ -59,15 +92,93 @@ pub enum AlphaColorMode {
     Premultiplied,
 }
 
+/// Flip applied when the image is displayed. For [`Encoder::with_mirror`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mirror {
+    /// Exchanges the top and bottom (upside down)
+    TopBottom = 0,
+    /// Exchanges the left and right sides
+    LeftRight = 1,
+}
+
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
 pub enum BitDepth {
     Eight,
     Ten,
+    /// The most precision for 16-bit and HDR sources that would still band at 10 bits, at the cost of larger files.
+    /// Meant for archival masters rather than delivery, since many decoders, especially hardware ones, can't decode it.
+    Twelve,
     /// Same as `Ten`
     #[default]
     Auto,
 }
 
+impl BitDepth {
+    #[inline]
+    fn to_bits(self) -> u8 {
+        match self {
+            Self::Eight => 8,
+            Self::Ten | Self::Auto => 10,
+            Self::Twelve => 12,
+        }
+    }
+}
+
+/// Single-channel image stored along with the main image. For [`Encoder::with_depth_map`] and [`Encoder::with_gain_map`]
+///
+/// It doesn't need to have the same size as the main image.
+#[derive(Debug, Clone)]
+pub struct AuxiliaryImage {
+    /// Values from 0 to `(1 << bit_depth) - 1`. Larger values make encoding fail with [`Error::InvalidConfig`].
+    pub pixels: ImgVec<u16>,
+    /// 8, 10 or 12
+    pub bit_depth: u8,
+    /// `1..=100`, like [`Encoder::with_quality`]
+    pub quality: f32,
+}
+
+/// ISO 21496-1 parameters of a gain map. For [`Encoder::with_gain_map`]
+///
+/// The gain map turns the main (base) image into the alternate rendition, usually from SDR to HDR.
+/// Gains and headrooms are log2, i.e. in stops.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GainMapMetadata {
+    /// Gain for gain map value 0
+    pub gain_map_min: f64,
+    /// Gain for the max gain map value
+    pub gain_map_max: f64,
+    /// Gamma the gain map values have been encoded with
+    pub gamma: f64,
+    /// Added to the base image before applying the gain
+    pub base_offset: f64,
+    /// Subtracted from the result of applying the gain
+    pub alternate_offset: f64,
+    /// HDR headroom of the base image, usually 0 for SDR
+    pub base_hdr_headroom: f64,
+    /// HDR headroom of the alternate rendition. The gain map is fully applied on displays with this much headroom.
+    pub alternate_hdr_headroom: f64,
+    /// Color space of the alternate rendition, stored in the `tmap` item
+    pub alternate_color_primaries: ColorPrimaries,
+    pub alternate_transfer_characteristics: TransferCharacteristics,
+}
+
+impl Default for GainMapMetadata {
+    /// SDR base image with up to one stop of HDR gain, to a BT.2020 PQ rendition
+    fn default() -> Self {
+        Self {
+            gain_map_min: 0.,
+            gain_map_max: 1.,
+            gamma: 1.,
+            base_offset: 1. / 64.,
+            alternate_offset: 1. / 64.,
+            base_hdr_headroom: 0.,
+            alternate_hdr_headroom: 1.,
+            alternate_color_primaries: ColorPrimaries::BT2020,
+            alternate_transfer_characteristics: TransferCharacteristics::SMPTE2084,
+        }
+    }
+}
+
 /// The newly-created image file + extra info FYI
 #[non_exhaustive]
 #[derive(Clone)]
@@ -78,6 +189,48 @@ pub struct EncodedImage {
     pub color_byte_size: usize,
     /// FYI: number of bytes of AV1 payload used for the alpha channel
     pub alpha_byte_size: usize,
+    /// SSIM of the encoded color (0..=1, higher is better), measured on this file, if [`Encoder::with_target_ssim`] has been set.
+    /// With [`Encoder::with_film_grain`], it's measured against the denoised image.
+    pub ssim: Option<f64>,
+    /// FYI: number of bytes of AV1 payload used for the thumbnail (color and alpha), if [`Encoder::with_thumbnail`] has been set
+    pub thumbnail_byte_size: usize,
+    /// Details of the encoding, if [`Encoder::with_stats`] has been set
+    pub stats: Option<EncodingStats>,
+    /// Decoded pixels, if [`Encoder::with_reconstruction`] has been set
+    pub reconstruction: Option<Reconstruction>,
+}
+
+/// Details of the encoding, from [`Encoder::with_stats`]
+///
+/// The quality of planes is measured against the pixels given to the encoder, which are denoised first if [`Encoder::with_film_grain`] has been set.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default)]
+pub struct EncodingStats {
+    /// Time spent encoding the color. For huge images, it's the sum of all tiles, which are encoded in parallel.
+    pub color_time: Duration,
+    /// Time spent encoding the alpha channel, at the same time as the color
+    pub alpha_time: Duration,
+    /// Number of AV1 tiles of the color, summed over all frames. rav1e splits large frames into tiles to encode them in parallel.
+    pub tiles: usize,
+    /// Number of AV1 frames the image has been split into (1 unless the image is larger than 4096 pixels and split into a grid)
+    pub grid_cells: usize,
+    /// AV1 quantizer (0-255, lower is better) of the color, which may be different from [`Encoder::with_quality`] if the quality has been searched for
+    pub color_quantizer: u8,
+    /// AV1 quantizer of the alpha channel, if the image has one
+    pub alpha_quantizer: Option<u8>,
+    /// Quality of the Y, Cb and Cr planes (or G, B and R), or only Y for grayscale images, as decoded by the encoder
+    pub color_planes: Vec<PlaneQuality>,
+    /// Quality of the alpha channel, if the image has one
+    pub alpha_plane: Option<PlaneQuality>,
+}
+
+/// Difference between the source and the decoded plane, from [`EncodingStats`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlaneQuality {
+    /// Peak signal-to-noise ratio in dB (higher is better), infinite if there's no difference
+    pub psnr: f64,
+    /// Structural similarity (0..=1, higher is better)
+    pub ssim: f64,
 }
 
 /// Encoder config builder
@@ -97,12 +250,51 @@ pub struct Encoder {
     threads: Option<usize>,
     /// [`AlphaColorMode`]
     alpha_color_mode: AlphaColorMode,
-    /// 8 or 10
+    /// 8, 10 or 12
     output_depth: BitDepth,
+    /// 4:4:4 by default
+    chroma_subsampling: ChromaSubsampling,
+    /// BT.709 (sRGB) by default
+    color_primaries: ColorPrimaries,
+    /// sRGB by default
+    transfer_characteristics: TransferCharacteristics,
+    /// HDR static metadata
+    mastering_display: Option<MasteringDisplay>,
+    /// HDR static metadata
+    content_light: Option<ContentLight>,
+    /// Written in addition to the CICP color description
+    icc_profile: Option<Vec<u8>>,
+    /// TIFF-format Exif data
+    exif: Option<Vec<u8>>,
+    /// XMP packet
+    xmp: Option<Vec<u8>>,
     /// Optional cancellation token for interrupting encoding
     cancellation_token: Option<CancellationToken>,
     /// Optional timeout duration for encoding
     timeout: Option<std::time::Duration>,
+    /// Max file size in bytes, searched for with trial encodes
+    target_size: Option<usize>,
+    /// Min SSIM of the color, searched for with trial encodes
+    target_ssim: Option<f64>,
+    /// Max width and height of the thumbnail item
+    thumbnail_size: Option<usize>,
+    /// Auxiliary image with the depth URN
+    depth_map: Option<AuxiliaryImage>,
+    /// Gain map item referenced by a `tmap` item
+    gain_map: Option<(AuxiliaryImage, GainMapMetadata)>,
+    /// Anti-clockwise, in units of 90°
+    rotation: u8,
+    /// Applied after rotation
+    mirror: Option<Mirror>,
+    /// left, top, width, height, applied before rotation
+    crop: Option<(u32, u32, u32, u32)>,
+    /// Relative width and height of a pixel
+    pixel_aspect_ratio: Option<(u32, u32)>,
+    /// Photon noise level, 0 for none
+    film_grain: u8,
+    progress: Option<ProgressCallback>,
+    stats: bool,
+    reconstruction: bool,
 }
 
 impl Default for Encoder {
@@ -121,16 +313,40 @@ impl Encoder {
             alpha_quantizer: quality_to_quantizer(80.),
             speed: 5,
             output_depth: BitDepth::default(),
+            chroma_subsampling: ChromaSubsampling::Cs444,
+            color_primaries: ColorPrimaries::BT709, // sRGB-compatible
+            transfer_characteristics: TransferCharacteristics::SRGB,
+            mastering_display: None,
+            content_light: None,
+            icc_profile: None,
+            exif: None,
+            xmp: None,
             premultiplied_alpha: false,
             color_model: ColorModel::YCbCr,
             threads: None,
             alpha_color_mode: AlphaColorMode::UnassociatedClean,
             cancellation_token: None,
             timeout: None,
+            target_size: None,
+            target_ssim: None,
+            thumbnail_size: None,
+            depth_map: None,
+            gain_map: None,
+            rotation: 0,
+            mirror: None,
+            crop: None,
+            pixel_aspect_ratio: None,
+            film_grain: 0,
+            progress: None,
+            stats: false,
+            reconstruction: false,
         }
     }
 
-    /// Quality `1..=100`. Panics if out of range.
+    /// Quality `1..=100`. Panics if out of range (see [`Self::try_with_quality`]).
+    ///
+    /// Quality 100 is not lossless. rav1e doesn't implement AV1's lossless mode (quantizer index 0),
+    /// so this library can't guarantee bit-exact reconstruction at any setting.
     #[inline(always)]
     #[track_caller]
     #[must_use]
@@ -140,16 +356,30 @@ impl Encoder {
         self
     }
 
+    /// Like [`Self::with_quality`], but returns [`Error::InvalidConfig`] if the quality is out of range, e.g. when it comes from user input
+    #[inline]
+    pub fn try_with_quality(self, quality: f32) -> Result<Self, Error> {
+        if !(1. ..=100.).contains(&quality) {
+            return Err(Error::InvalidConfig("quality must be in 1-100 range"));
+        }
+        Ok(self.with_quality(quality))
+    }
+
     #[doc(hidden)]
     #[deprecated(note = "Renamed to with_bit_depth")]
     #[must_use]
     pub fn with_depth(self, depth: Option<u8>) -> Self {
-        self.with_bit_depth(depth.map(|d| if d >= 10 { BitDepth::Ten } else { BitDepth::Eight }).unwrap_or(BitDepth::Auto))
+        self.with_bit_depth(depth.map(|d| match d {
+            12.. => BitDepth::Twelve,
+            10.. => BitDepth::Ten,
+            _ => BitDepth::Eight,
+        }).unwrap_or(BitDepth::Auto))
     }
 
     /// Internal precision to use in the encoded AV1 data, for both color and alpha. 10-bit depth works best, even for 8-bit inputs/outputs.
     ///
     /// Use 8-bit depth only as a workaround for decoders that need it.
+    /// 12-bit depth is meant for 16-bit sources that need to be preserved as precisely as possible.
     ///
     /// This setting does not affect pixel inputs for this library.
     #[inline(always)]
@@ -159,7 +389,7 @@ impl Encoder {
         self
     }
 
-    /// Quality for the alpha channel only. `1..=100`. Panics if out of range.
+    /// Quality for the alpha channel only. `1..=100`. Panics if out of range (see [`Self::try_with_alpha_quality`]).
     #[inline(always)]
     #[track_caller]
     #[must_use]
@@ -169,10 +399,19 @@ impl Encoder {
         self
     }
 
+    /// Like [`Self::with_alpha_quality`], but returns [`Error::InvalidConfig`] if the quality is out of range
+    #[inline]
+    pub fn try_with_alpha_quality(self, quality: f32) -> Result<Self, Error> {
+        if !(1. ..=100.).contains(&quality) {
+            return Err(Error::InvalidConfig("alpha quality must be in 1-100 range"));
+        }
+        Ok(self.with_alpha_quality(quality))
+    }
+
     /// * 1 = very very slow, but max compression.
     /// * 10 = quick, but larger file sizes and lower quality.
     ///
-    /// Panics if outside `1..=10`.
+    /// Panics if outside `1..=10` (see [`Self::try_with_speed`]).
     #[inline(always)]
     #[track_caller]
     #[must_use]
@@ -182,6 +421,15 @@ impl Encoder {
         self
     }
 
+    /// Like [`Self::with_speed`], but returns [`Error::InvalidConfig`] if the speed is out of range
+    #[inline]
+    pub fn try_with_speed(self, speed: u8) -> Result<Self, Error> {
+        if !(1..=10).contains(&speed) {
+            return Err(Error::InvalidConfig("speed must be in 1-10 range"));
+        }
+        Ok(self.with_speed(speed))
+    }
+
     /// Changes how color channels are stored in the image. The default is YCbCr.
     ///
     /// Note that this is only internal detail for the AVIF file, and doesn't
@@ -193,6 +441,98 @@ impl Encoder {
         self
     }
 
+    /// Reduces resolution of the color channels (luma and alpha always stay at full resolution).
+    /// The default is full-resolution 4:4:4.
+    ///
+    /// Chroma subsampling rarely helps compression of still images in AV1. Use it only for
+    /// decoders that can't handle 4:4:4, such as some hardware decoders and TVs.
+    ///
+    /// Pixels given to `encode_*` functions are always full-resolution, and are downsampled by averaging.
+    /// Subsampling is not supported with [`ColorModel::RGB`].
+    #[inline(always)]
+    #[must_use]
+    pub fn with_chroma_subsampling(mut self, subsampling: ChromaSubsampling) -> Self {
+        self.chroma_subsampling = subsampling;
+        self
+    }
+
+    /// Color gamut of the pixels. The default is `BT709`, which is the same as sRGB.
+    ///
+    /// Use `BT2020` for HDR, or `SMPTE432` for Display P3. The pixels are not converted, only labelled.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_color_primaries(mut self, color_primaries: ColorPrimaries) -> Self {
+        self.color_primaries = color_primaries;
+        self
+    }
+
+    /// Gamma/transfer function of the pixels. The default is `SRGB`.
+    ///
+    /// Use `SMPTE2084` (PQ) or `HLG` for HDR. The pixels must already be encoded with this function,
+    /// and they should be encoded at 10 or 12 bits to avoid banding.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_transfer_characteristics(mut self, transfer_characteristics: TransferCharacteristics) -> Self {
+        self.transfer_characteristics = transfer_characteristics;
+        self
+    }
+
+    /// HDR metadata describing the display the image has been mastered on (SMPTE ST 2086).
+    ///
+    /// It's written to the AV1 payload and to the `mdcv` box of the AVIF file.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_mastering_display(mut self, mastering_display: MasteringDisplay) -> Self {
+        self.mastering_display = Some(mastering_display);
+        self
+    }
+
+    /// HDR metadata with max brightness of the image (MaxCLL and MaxFALL in cd/m²).
+    ///
+    /// It's written to the AV1 payload and to the `clli` box of the AVIF file.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_content_light(mut self, content_light: ContentLight) -> Self {
+        self.content_light = Some(content_light);
+        self
+    }
+
+    /// Embeds an ICC color profile in the AVIF file (as a `colr` box of type `prof`).
+    ///
+    /// The pixels must already be in the color space of the profile. They're not converted.
+    /// [`Self::with_color_primaries`] and [`Self::with_transfer_characteristics`] are ignored, and the AV1 payload and `nclx` box
+    /// mark them as unspecified, so that decoders use the profile. The `nclx` box only describes the YCbCr matrix and range.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_icc_profile(mut self, icc_profile: Vec<u8>) -> Self {
+        self.icc_profile = Some(icc_profile);
+        self
+    }
+
+    /// Adds Exif metadata to the AVIF file (as an `Exif` item describing the image).
+    ///
+    /// The data is in TIFF format, starting with `II` or `MM`, like in JPEG's `APP1` segment or PNG's `eXIf` chunk.
+    /// JPEG's `Exif\0\0` header is removed if present.
+    ///
+    /// AVIF doesn't use Exif for orientation of the image. Set Exif's orientation to 1 (normal),
+    /// otherwise some software may rotate the image twice.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_exif(mut self, exif: Vec<u8>) -> Self {
+        self.exif = Some(exif);
+        self
+    }
+
+    /// Adds XMP metadata to the AVIF file (as a `mime` item of type `application/rdf+xml` describing the image).
+    ///
+    /// The data is an XMP packet (XML), like in JPEG's `APP1` segment after the `http://ns.adobe.com/xap/1.0/\0` header.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_xmp(mut self, xmp: Vec<u8>) -> Self {
+        self.xmp = Some(xmp);
+        self
+    }
+
     #[doc(hidden)]
     #[deprecated = "Renamed to `with_internal_color_model()`"]
     #[must_use]
@@ -202,6 +542,8 @@ impl Encoder {
 
     /// Configures `rayon` thread pool size.
     /// The default `None` is to use all threads in the default `rayon` thread pool.
+    ///
+    /// Panics on `Some(0)` (see [`Self::try_with_num_threads`]).
     #[inline(always)]
     #[track_caller]
     #[must_use]
@@ -211,6 +553,15 @@ impl Encoder {
         self
     }
 
+    /// Like [`Self::with_num_threads`], but returns [`Error::InvalidConfig`] for `Some(0)`
+    #[inline]
+    pub fn try_with_num_threads(self, num_threads: Option<usize>) -> Result<Self, Error> {
+        if num_threads == Some(0) {
+            return Err(Error::InvalidConfig("number of threads must be at least 1"));
+        }
+        Ok(self.with_num_threads(num_threads))
+    }
+
     /// Configure handling of color channels in transparent images
     ///
     /// Note that this doesn't affect input format for this library,
@@ -269,6 +620,283 @@ impl Encoder {
         self.timeout = Some(timeout);
         self
     }
+
+    /// Encode at the best quality that fits the whole AVIF file in `bytes`.
+    ///
+    /// The quality set with [`Self::with_quality`] becomes the maximum quality (set it to 100 to get the best quality that fits).
+    /// Alpha quality is lowered together with color quality, so both share the same budget.
+    ///
+    /// The quantizer is searched for with a series of fast trial encodes, so encoding takes several times longer.
+    /// The pixels are buffered in memory during the search. [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    ///
+    /// Returns `Error::TargetSizeTooSmall` if the image doesn't fit even at the lowest quality.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_target_size(mut self, bytes: usize) -> Self {
+        self.target_size = Some(bytes);
+        self
+    }
+
+    /// Encode at the lowest quality that still looks as good as `ssim` (`0..=1`, e.g. `0.98`).
+    /// Panics if out of range or NaN (see [`Self::try_with_target_ssim`]).
+    ///
+    /// Instead of using the same quantizer for every image, this compares rav1e's reconstruction of the image with the source,
+    /// and picks the highest quantizer that keeps the SSIM of the color channels at or above the target.
+    /// Simple images get smaller files, and complex ones get a higher quality. The achieved SSIM is in [`EncodedImage::ssim`].
+    ///
+    /// The quality set with [`Self::with_quality`] becomes the maximum quality. Alpha quality is lowered together with color quality.
+    /// If [`Self::with_target_size`] is also set, the size limit takes priority.
+    ///
+    /// The quantizer is searched for with a series of fast trial encodes, so encoding takes several times longer.
+    /// [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    #[inline(always)]
+    #[must_use]
+    #[track_caller]
+    pub fn with_target_ssim(mut self, ssim: f64) -> Self {
+        assert!((0. ..=1.).contains(&ssim));
+        self.target_ssim = Some(ssim);
+        self
+    }
+
+    /// Like [`Self::with_target_ssim`], but returns [`Error::InvalidConfig`] if the SSIM is out of range or NaN
+    #[inline]
+    pub fn try_with_target_ssim(self, ssim: f64) -> Result<Self, Error> {
+        if !(0. ..=1.).contains(&ssim) {
+            return Err(Error::InvalidConfig("SSIM must be in 0-1 range"));
+        }
+        Ok(self.with_target_ssim(ssim))
+    }
+
+    /// Add a small preview image, which is at most `max_dim` pixels wide and tall. Panics if `max_dim` is 0 (see [`Self::try_with_thumbnail`]).
+    ///
+    /// The thumbnail is a downscaled copy of the image, encoded with the same settings, and linked to the main image with a `thmb` reference.
+    /// Apps can show it without decoding the full image. It's left out if the image isn't larger than `max_dim`. [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    #[inline(always)]
+    #[must_use]
+    #[track_caller]
+    pub fn with_thumbnail(mut self, max_dim: usize) -> Self {
+        assert!(max_dim > 0);
+        self.thumbnail_size = Some(max_dim);
+        self
+    }
+
+    /// Like [`Self::with_thumbnail`], but returns [`Error::InvalidConfig`] if `max_dim` is 0
+    #[inline]
+    pub fn try_with_thumbnail(self, max_dim: usize) -> Result<Self, Error> {
+        if max_dim == 0 {
+            return Err(Error::InvalidConfig("thumbnail size must be at least 1"));
+        }
+        Ok(self.with_thumbnail(max_dim))
+    }
+
+    /// Attach a depth map as an auxiliary image of the main image. Panics if the bit depth or quality is out of range (see [`Self::try_with_depth_map`]).
+    ///
+    /// [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    #[inline(always)]
+    #[must_use]
+    #[track_caller]
+    pub fn with_depth_map(mut self, depth_map: AuxiliaryImage) -> Self {
+        assert_auxiliary_image(&depth_map);
+        self.depth_map = Some(depth_map);
+        self
+    }
+
+    /// Like [`Self::with_depth_map`], but returns [`Error::InvalidConfig`] if the bit depth or quality is out of range
+    #[inline]
+    pub fn try_with_depth_map(self, depth_map: AuxiliaryImage) -> Result<Self, Error> {
+        check_auxiliary_image(&depth_map)?;
+        Ok(self.with_depth_map(depth_map))
+    }
+
+    /// Attach an ISO 21496-1 gain map, which is stored in a `tmap` derived image item.
+    /// Panics if the bit depth or quality is out of range (see [`Self::try_with_gain_map`]).
+    ///
+    /// The main image remains the primary image, and decoders that support gain maps can display the `tmap` item as its HDR alternative.
+    /// [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    #[inline(always)]
+    #[must_use]
+    #[track_caller]
+    pub fn with_gain_map(mut self, gain_map: AuxiliaryImage, metadata: GainMapMetadata) -> Self {
+        assert_auxiliary_image(&gain_map);
+        self.gain_map = Some((gain_map, metadata));
+        self
+    }
+
+    /// Like [`Self::with_gain_map`], but returns [`Error::InvalidConfig`] if the bit depth or quality is out of range
+    #[inline]
+    pub fn try_with_gain_map(self, gain_map: AuxiliaryImage, metadata: GainMapMetadata) -> Result<Self, Error> {
+        check_auxiliary_image(&gain_map)?;
+        Ok(self.with_gain_map(gain_map, metadata))
+    }
+
+    /// Rotate the image when it's displayed, anti-clockwise by `quarter_turns` × 90°. The pixels are encoded as they are.
+    ///
+    /// Rotation is applied after [`Self::with_crop`] and before [`Self::with_mirror`], like in HEIF.
+    /// Animation tracks are not rotated, only their still image.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_rotation(mut self, quarter_turns: u8) -> Self {
+        self.rotation = quarter_turns % 4;
+        self
+    }
+
+    /// Flip the image when it's displayed. The pixels are encoded as they are.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_mirror(mut self, mirror: Mirror) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    /// Display only the `width` × `height` rectangle at `left`, `top` (in pixels of the image given to the encoder).
+    ///
+    /// Encoding fails if the rectangle is not within the image.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_crop(mut self, left: u32, top: u32, width: u32, height: u32) -> Self {
+        self.crop = Some((left, top, width, height));
+        self
+    }
+
+    /// Display pixels stretched to `h_spacing`:`v_spacing` aspect ratio, e.g. `(2, 1)` for pixels twice as wide as tall.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_pixel_aspect_ratio(mut self, h_spacing: u32, v_spacing: u32) -> Self {
+        self.pixel_aspect_ratio = Some((h_spacing, v_spacing));
+        self
+    }
+
+    /// Replace noise with AV1 film grain synthesis. `strength` is `0..=64` (0 disables it), approximately ISO/100 of camera noise. Panics if out of range (see [`Self::try_with_film_grain`]).
+    ///
+    /// The image is denoised before encoding, and decoders add back synthetic noise of similar strength.
+    /// Noise is very expensive to encode, so this can make files of noisy photos much smaller, but the noise won't be the same as in the original.
+    ///
+    /// [`Self::with_target_ssim`] and [`Self::with_stats`] compare the encoded image with the denoised one, not the original,
+    /// since the noise removed on purpose isn't an encoding error.
+    /// [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    #[inline(always)]
+    #[must_use]
+    #[track_caller]
+    pub fn with_film_grain(mut self, strength: u8) -> Self {
+        assert!(strength <= 64);
+        self.film_grain = strength;
+        self
+    }
+
+    /// Like [`Self::with_film_grain`], but returns [`Error::InvalidConfig`] if the strength is out of range
+    #[inline]
+    pub fn try_with_film_grain(self, strength: u8) -> Result<Self, Error> {
+        if strength > 64 {
+            return Err(Error::InvalidConfig("grain must be in 0-64 range"));
+        }
+        Ok(self.with_film_grain(strength))
+    }
+
+    /// Calls `callback` as the encoding goes through its phases, e.g. to show a progress bar.
+    ///
+    /// Phases are reported in order, except alpha, which is encoded at the same time as the color, so the callback can be called from multiple threads.
+    /// Phases that don't apply to the image are skipped.
+    ///
+    /// ```rust
+    /// use ravif::*;
+    /// let enc = Encoder::new().with_progress(|p: Progress| eprintln!("{:?} {:.0}%", p.phase, p.fraction * 100.));
+    /// ```
+    #[inline(always)]
+    #[must_use]
+    pub fn with_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(ProgressCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Add [`EncodingStats`] to the [`EncodedImage`]: time taken, quantizers used, and PSNR and SSIM of every plane.
+    ///
+    /// The quality is measured on the frames decoded by the encoder itself, so it doesn't need a separate decoder,
+    /// but comparing all the pixels makes encoding a bit slower. [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_stats(mut self, enable: bool) -> Self {
+        self.stats = enable;
+        self
+    }
+
+    /// Add the [`Reconstruction`] to the [`EncodedImage`]: pixels of the image as decoders will see them, for previews and comparisons.
+    ///
+    /// The pixels come from the encoder itself, without decoding the file. They're RGBA, regardless of the input and internal color model,
+    /// with 8 or 16 bits per channel, depending on the bit depth of the file. Images with unusual matrix coefficients don't have it, and [`AnimationEncoder`](crate::AnimationEncoder) returns an error if it's set.
+    ///
+    /// With [`Self::with_film_grain`], it's the denoised image, without the grain that decoders synthesize.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_reconstruction(mut self, enable: bool) -> Self {
+        self.reconstruction = enable;
+        self
+    }
+
+    /// Applies all the settings from the [`EncoderConfig`](crate::EncoderConfig), e.g. one loaded from a file or a preset.
+    ///
+    /// Returns [`Error::InvalidConfig`] instead of panicking on out-of-range values.
+    pub fn try_with_config(self, config: &crate::EncoderConfig) -> Result<Self, Error> {
+        let mut enc = self.try_with_quality(config.quality)?
+            .try_with_alpha_quality(config.effective_alpha_quality())?
+            .try_with_speed(config.speed)?
+            .try_with_num_threads(config.threads)?
+            .try_with_film_grain(config.film_grain)?
+            .with_bit_depth(config.bit_depth)
+            .with_internal_color_model(config.color_model)
+            .with_chroma_subsampling(config.chroma_subsampling)
+            .with_alpha_color_mode(config.alpha_color_mode);
+        enc.thumbnail_size = None;
+        if let Some(max_dim) = config.thumbnail {
+            enc = enc.try_with_thumbnail(max_dim)?;
+        }
+        Ok(enc)
+    }
+
+    /// Checks whether an image of this size can be encoded with this configuration, without encoding it.
+    ///
+    /// Returns [`Error::InvalidConfig`] for empty images, images too large for AVIF, crops that don't fit in the image,
+    /// and chroma subsampling of [`ColorModel::RGB`] (which is only possible for grayscale images).
+    /// The `encode_*` functions check the size too, but only after converting the pixels.
+    pub fn validate(&self, width: usize, height: usize) -> Result<(), Error> {
+        check_dimensions(width, height)?;
+        if let Some((left, top, crop_width, crop_height)) = self.crop {
+            let fits = |start: u32, len: u32, size: usize| len > 0 && (start as usize).saturating_add(len as usize) <= size;
+            if !fits(left, crop_width, width) || !fits(top, crop_height, height) {
+                return Err(Error::InvalidConfig("crop outside of the image"));
+            }
+        }
+        if self.color_model == ColorModel::RGB && self.chroma_subsampling != ChromaSubsampling::Cs444 {
+            return Err(Error::InvalidConfig("chroma subsampling of RGB"));
+        }
+        Ok(())
+    }
+}
+
+/// Empty images, and images too large even for a grid of frames
+fn check_dimensions(width: usize, height: usize) -> Result<(), Error> {
+    if width == 0 || height == 0 {
+        return Err(Error::InvalidConfig("image has no pixels"));
+    }
+    if width > MAX_GRID_TILES * GRID_TILE_SIZE || height > MAX_GRID_TILES * GRID_TILE_SIZE {
+        return Err(Error::InvalidConfig("image too large"));
+    }
+    Ok(())
+}
+
+#[track_caller]
+fn assert_auxiliary_image(aux: &AuxiliaryImage) {
+    assert!(matches!(aux.bit_depth, 8 | 10 | 12));
+    assert!((1. ..=100.).contains(&aux.quality));
+}
+
+fn check_auxiliary_image(aux: &AuxiliaryImage) -> Result<(), Error> {
+    if !matches!(aux.bit_depth, 8 | 10 | 12) {
+        return Err(Error::InvalidConfig("auxiliary image depth must be 8, 10 or 12"));
+    }
+    if !(1. ..=100.).contains(&aux.quality) {
+        return Err(Error::InvalidConfig("auxiliary image quality must be in 1-100 range"));
+    }
+    Ok(())
 }
 
 /// Once done with config, call one of the `encode_*` functions
@@ -289,14 +917,21 @@ impl Encoder {
     /// ```
     ///
     /// If all pixels are opaque, the alpha channel will be left out automatically.
+    /// If all pixels are gray (`r == g == b`), the image will be encoded as monochrome, like [`Self::encode_gray_alpha`].
     ///
     /// This function takes 8-bit inputs, but will generate an AVIF file using 10-bit depth.
     ///
     /// returns AVIF file with info about sizes about AV1 payload.
     pub fn encode_rgba(&self, in_buffer: Img<&[rgb::RGBA<u8>]>) -> Result<EncodedImage, Error> {
+        self.progress(EncodingPhase::Preprocessing, 0.);
         let new_alpha = self.convert_alpha_8bit(in_buffer);
         let buffer = new_alpha.as_ref().map(|b| b.as_ref()).unwrap_or(in_buffer);
         let use_alpha = buffer.pixels().any(|px| px.a != 255);
+        let gray = buffer.pixels().all(|px| px.r == px.g && px.g == px.b);
+        self.progress(EncodingPhase::Preprocessing, 1.);
+        if gray {
+            return self.encode_gray_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.r), use_alpha.then(|| buffer.pixels().map(|px| px.a)));
+        }
         if !use_alpha {
             return self.encode_rgb_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.rgb()));
         }
@@ -319,241 +954,1788 @@ impl Encoder {
                 let alpha = buffer.pixels().map(|px| px.a);
                 self.encode_raw_planes_8_bit(width, height, planes, Some(alpha), PixelRange::Full, matrix_coefficients)
             },
-            BitDepth::Ten | BitDepth::Auto => {
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
                 let planes = buffer.pixels().map(|px| {
                     let (y, u, v) = match self.color_model {
-                        ColorModel::YCbCr => rgb_to_10_bit_ycbcr(px.rgb(), BT601),
-                        ColorModel::RGB => rgb_to_10_bit_gbr(px.rgb()),
+                        ColorModel::YCbCr => rgb_to_hbd_ycbcr(px.rgb(), depth, BT601),
+                        ColorModel::RGB => rgb_to_hbd_gbr(px.rgb(), depth),
                     };
                     [y, u, v]
                 });
-                let alpha = buffer.pixels().map(|px| to_ten(px.a));
-                self.encode_raw_planes_10_bit(width, height, planes, Some(alpha), PixelRange::Full, matrix_coefficients)
+                let alpha = buffer.pixels().map(|px| to_hbd(px.a, depth));
+                self.encode_raw_planes_internal(width, height, planes, Some(alpha), PixelRange::Full, matrix_coefficients, depth)
+            },
+        }
+    }
+
+    fn convert_alpha_8bit(&self, in_buffer: Img<&[RGBA8]>) -> Option<ImgVec<RGBA8>> {
+        match self.alpha_color_mode {
+            AlphaColorMode::UnassociatedDirty => None,
+            AlphaColorMode::UnassociatedClean => blurred_dirty_alpha(in_buffer),
+            AlphaColorMode::Premultiplied => {
+                let prem = in_buffer.pixels().map(premultiply_8bit).collect();
+                Some(ImgVec::new(prem, in_buffer.width(), in_buffer.height()))
             },
         }
     }
 
-    fn convert_alpha_8bit(&self, in_buffer: Img<&[RGBA8]>) -> Option<ImgVec<RGBA8>> {
-        match self.alpha_color_mode {
-            AlphaColorMode::UnassociatedDirty => None,
-            AlphaColorMode::UnassociatedClean => blurred_dirty_alpha(in_buffer),
-            AlphaColorMode::Premultiplied => {
-                let prem = in_buffer.pixels()
-                    .map(|px| {
-                        if px.a == 0 || px.a == 255 {
-                            RGBA8::default()
-                        } else {
-                            RGBA8::new(
-                                (u16::from(px.r) * 255 / u16::from(px.a)) as u8,
-                                (u16::from(px.g) * 255 / u16::from(px.a)) as u8,
-                                (u16::from(px.b) * 255 / u16::from(px.a)) as u8,
-                                px.a,
-                            )
+    /// Like [`Self::encode_rgba`], but returns a future, and the encoding runs on a thread pool for blocking tasks.
+    ///
+    /// It works with any async runtime. Dropping the future before it completes cancels the encoding.
+    /// A token set with [`Self::with_cancellation_token`] can still cancel it too.
+    ///
+    /// Requires the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn encode_rgba_async(&self, in_buffer: ImgVec<RGBA8>) -> impl std::future::Future<Output = Result<EncodedImage, Error>> + Send + 'static {
+        let token = self.cancellation_token.as_ref().map_or_else(CancellationToken::new, CancellationToken::child_token);
+        let enc = self.clone().with_cancellation_token(token.clone());
+        crate::cancel::CancelOnDrop {
+            task: blocking::unblock(move || enc.encode_rgba(in_buffer.as_ref())),
+            token,
+        }
+    }
+
+    /// Make a new AVIF image from 16-bit RGBA pixels (non-premultiplied, alpha last)
+    ///
+    /// This is the same as [`Self::encode_rgba`], but the pixels are converted directly from 16-bit
+    /// to the output bit depth, without rounding to 8 bits first. Use it for deep PNGs and HDR renders
+    /// to avoid banding.
+    ///
+    /// If all pixels are opaque, the alpha channel will be left out automatically.
+    /// If all pixels are gray, the image will be encoded as monochrome.
+    pub fn encode_rgba16(&self, in_buffer: Img<&[RGBA16]>) -> Result<EncodedImage, Error> {
+        self.progress(EncodingPhase::Preprocessing, 0.);
+        let new_alpha = self.convert_alpha_16bit(in_buffer);
+        let buffer = new_alpha.as_ref().map(|b| b.as_ref()).unwrap_or(in_buffer);
+        let use_alpha = buffer.pixels().any(|px| px.a != 0xFFFF);
+        let gray = buffer.pixels().all(|px| px.r == px.g && px.g == px.b);
+        self.progress(EncodingPhase::Preprocessing, 1.);
+        if gray {
+            return self.encode_gray_internal_from_16bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.r), use_alpha.then(|| buffer.pixels().map(|px| px.a)));
+        }
+        if !use_alpha {
+            return self.encode_rgb_internal_from_16bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.rgb()));
+        }
+
+        let width = buffer.width();
+        let height = buffer.height();
+        let matrix_coefficients = match self.color_model {
+            ColorModel::YCbCr => MatrixCoefficients::BT601,
+            ColorModel::RGB => MatrixCoefficients::Identity,
+        };
+        match self.output_depth {
+            BitDepth::Eight => {
+                let planes = buffer.pixels().map(|px| {
+                    let (y, u, v) = match self.color_model {
+                        ColorModel::YCbCr => rgb16_to_8_bit_ycbcr(px.rgb(), BT601),
+                        ColorModel::RGB => rgb16_to_8_bit_gbr(px.rgb()),
+                    };
+                    [y, u, v]
+                });
+                let alpha = buffer.pixels().map(|px| sixteen_to_eight(px.a));
+                self.encode_raw_planes_8_bit(width, height, planes, Some(alpha), PixelRange::Full, matrix_coefficients)
+            },
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                let planes = buffer.pixels().map(|px| {
+                    let (y, u, v) = match self.color_model {
+                        ColorModel::YCbCr => rgb16_to_hbd_ycbcr(px.rgb(), depth, BT601),
+                        ColorModel::RGB => rgb16_to_hbd_gbr(px.rgb(), depth),
+                    };
+                    [y, u, v]
+                });
+                let alpha = buffer.pixels().map(|px| sixteen_to_hbd(px.a, depth));
+                self.encode_raw_planes_internal(width, height, planes, Some(alpha), PixelRange::Full, matrix_coefficients, depth)
+            },
+        }
+    }
+
+    fn convert_alpha_16bit(&self, in_buffer: Img<&[RGBA16]>) -> Option<ImgVec<RGBA16>> {
+        match self.alpha_color_mode {
+            AlphaColorMode::UnassociatedDirty => None,
+            AlphaColorMode::UnassociatedClean => blurred_dirty_alpha16(in_buffer),
+            AlphaColorMode::Premultiplied => {
+                let prem = in_buffer.pixels().map(premultiply_16bit).collect();
+                Some(ImgVec::new(prem, in_buffer.width(), in_buffer.height()))
+            },
+        }
+    }
+
+    /// Make a new AVIF image from RGBA pixels (non-premultiplied, alpha last) that are read incrementally from a [`RowSource`]
+    ///
+    /// This is for images too large to keep in memory. Rows are converted straight into the encoder's frame,
+    /// and huge images that need a grid are read and encoded one row of tiles at a time.
+    ///
+    /// Because the whole image is never available at once, gray images aren't detected, and [`AlphaColorMode::UnassociatedClean`]
+    /// fills transparent pixels with the nearest visible color in the row instead of blurring them.
+    /// [`Self::with_target_size`], [`Self::with_target_ssim`], [`Self::with_thumbnail`] and [`Self::with_film_grain`] are not supported.
+    pub fn encode_rgba_rows(&self, source: impl RowSource<RGBA8> + Send) -> Result<EncodedImage, Error> {
+        match self.output_depth {
+            BitDepth::Eight => self.encode_rows_internal(source, 8, rgba8_to_8_bit(self.color_model)),
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                self.encode_rows_internal(source, depth, rgba8_to_hbd(self.color_model, depth))
+            },
+        }
+    }
+
+    /// Like [`Self::encode_rgba_rows`], but for 16-bit pixels, which are converted without rounding to 8 bits first
+    pub fn encode_rgba16_rows(&self, source: impl RowSource<RGBA16> + Send) -> Result<EncodedImage, Error> {
+        match self.output_depth {
+            BitDepth::Eight => self.encode_rows_internal(source, 8, rgba16_to_8_bit(self.color_model)),
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                self.encode_rows_internal(source, depth, rgba16_to_hbd(self.color_model, depth))
+            },
+        }
+    }
+
+    fn encode_rows_internal<T: RowPixel, P: rav1e::Pixel + Default>(
+        &self, mut source: impl RowSource<T> + Send, bit_depth: u8,
+        convert: impl Fn(T) -> ([P; 3], P) + Sync,
+    ) -> Result<EncodedImage, Error> {
+        if self.target_size.is_some() || self.target_ssim.is_some() || self.thumbnail_size.is_some() || self.film_grain > 0 {
+            return Err(Error::Unsupported("options that need the whole image can't be used with a row source"));
+        }
+        let (width, height) = (source.width(), source.height());
+        check_dimensions(width, height)?;
+        let matrix_coefficients = match self.color_model {
+            ColorModel::YCbCr => MatrixCoefficients::BT601,
+            ColorModel::RGB => MatrixCoefficients::Identity,
+        };
+        let chroma_sampling = self.chroma_subsampling.chroma_sampling();
+        if chroma_sampling != ChromaSampling::Cs444 && matrix_coefficients == MatrixCoefficients::Identity {
+            return Err(Error::InvalidConfig("chroma subsampling of RGB"));
+        }
+        let image = self.image_params(width, height, bit_depth, PixelRange::Full, matrix_coefficients, chroma_sampling);
+        let extras = ExtraItems {
+            thumbnail: None,
+            depth_map: self.depth_map.as_ref().map(|aux| self.encode_auxiliary_image(&image, aux, Stage::DepthMap)).transpose()?,
+            gain_map: self.gain_map.as_ref().map(|(aux, _)| self.encode_auxiliary_image(&image, aux, Stage::GainMap)).transpose()?,
+        };
+        if let Some(grid) = Grid::for_image(width, height)? {
+            return self.encode_grid_rows(&image, &grid, &mut source, &convert, &extras);
+        }
+
+        // color and alpha go straight into the encoders' frames, and alpha is encoded only if it turns out to be needed
+        let cancel_token = self.cancellation_token.as_ref();
+        let alpha_config = auxiliary_config(&image, self.alpha_quantizer, self.speed, Stage::Alpha);
+        let (alpha_ctx, mut alpha_frame) = new_av1_frame::<P>(&alpha_config, cancel_token, image.deadline)?;
+        let alpha_stride = alpha_frame.planes[0].cfg.stride;
+        let mut alpha_rows = alpha_frame.planes[0].data_origin_mut().chunks_mut(alpha_stride);
+        let mut opaque = true;
+        let mut read_error = None;
+        let mut strip = Vec::new();
+        let planes = (0..height).step_by(ROWS_PER_READ).flat_map(|top| {
+            strip.resize(width * ROWS_PER_READ.min(height - top), T::default());
+            if let Err(err) = read_rows(&mut source, &mut strip, width, self.alpha_color_mode) {
+                // the frame will be short of pixels, and the encoding will stop
+                read_error = Some(err);
+                strip.clear();
+            }
+            let mut colors = Vec::with_capacity(strip.len());
+            for (row, alpha_row) in strip.chunks_exact(width).zip(alpha_rows.by_ref()) {
+                for (&px, alpha) in row.iter().zip(alpha_row) {
+                    let (color, a) = convert(px);
+                    opaque &= px.is_opaque();
+                    *alpha = a;
+                    colors.push(color);
+                }
+            }
+            colors
+        });
+        let color = self.encode_color_and_alpha(&image, self.quantizer, self.alpha_quantizer, self.speed, planes, None::<[P; 0]>);
+        if let Some(err) = read_error {
+            return Err(err);
+        }
+        let (color, _) = color?;
+        let alpha = if opaque { None } else {
+            self.image_progress(&image, EncodingPhase::AlphaEncode, 0.);
+            let alpha = encode_av1_frame(&alpha_config, alpha_ctx, alpha_frame, cancel_token, image.deadline, Instant::now())?;
+            self.image_progress(&image, EncodingPhase::AlphaEncode, 1.);
+            Some(alpha)
+        };
+        let stats = self.frame_stats(&image, &color, alpha.as_ref());
+        let reconstruction = self.reconstruct(&image, &color, alpha.as_ref());
+        let mut res = self.make_avif(&image, color.data, alpha.map(|a| a.data), &extras)?;
+        res.stats = stats.map(|s| s.finish(image.bit_depth, self.quantizer, self.alpha_quantizer));
+        res.reconstruction = reconstruction.map(|px| Reconstruction::new(px, image.bit_depth));
+        Ok(res)
+    }
+
+    /// Reads one row of tiles at a time, and encodes its tiles in parallel
+    fn encode_grid_rows<T: RowPixel, P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams, grid: &Grid, source: &mut impl RowSource<T>,
+        convert: &(impl Fn(T) -> ([P; 3], P) + Sync), extras: &ExtraItems,
+    ) -> Result<EncodedImage, Error> {
+        let tile_image = ImageParams { width: grid.tile_width, height: grid.tile_height, progress: false, ..*image };
+        let lefts: Vec<_> = (0..grid.columns).map(|col| col * grid.tile_width).collect();
+        let mut color_tiles = Vec::with_capacity(grid.rows * grid.columns);
+        // `None` for opaque tiles
+        let mut alpha_tiles = Vec::with_capacity(grid.rows * grid.columns);
+        // visible parts of the opaque tiles, for stats of their alpha
+        let mut opaque_areas = Vec::new();
+        let mut strip = Vec::new();
+        let mut stats = FrameStats::default();
+        let mut reconstruction = self.reconstruction.then(|| ImgVec::new(vec![RGBA16::default(); image.width * image.height], image.width, image.height));
+        self.image_progress(image, EncodingPhase::ColorEncode, 0.);
+        for row in 0..grid.rows {
+            let strip_height = grid.tile_height.min(image.height - row * grid.tile_height);
+            strip.resize(image.width * strip_height, T::default());
+            read_rows(source, &mut strip, image.width, self.alpha_color_mode)?;
+
+            let strip = &strip[..];
+            let tiles = par_map(&lefts, &|&left| {
+                // tiles on the right and bottom edges extend past the image, and repeat its last pixels
+                let indices = (0..grid.tile_height).flat_map(move |y| (0..grid.tile_width).map(move |x| {
+                    (left + x).min(image.width - 1) + y.min(strip_height - 1) * image.width
+                }));
+                let opaque = indices.clone().all(|i| strip[i].is_opaque());
+                let (color, alpha) = self.encode_color_and_alpha(&tile_image, self.quantizer, self.alpha_quantizer, self.speed,
+                    indices.clone().map(|i| convert(strip[i]).0), (!opaque).then(|| indices.map(|i| convert(strip[i]).1)))?;
+                // the padding isn't shown, so it's left out of the measurements
+                let visible = grid.visible(image, left, row * grid.tile_height);
+                let stats = self.frame_stats(&visible, &color, alpha.as_ref());
+                // opaque tiles are reconstructed as opaque, without waiting for the shared alpha tile
+                let reconstruction = self.reconstruct(&visible, &color, alpha.as_ref());
+                Ok::<_, Error>((color.data, alpha.map(|a| a.data), stats, reconstruction))
+            });
+            for (tile, &left) in tiles.into_iter().zip(&lefts) {
+                let (color, alpha, tile_stats, tile_reconstruction) = tile?;
+                color_tiles.push(color);
+                if alpha.is_none() {
+                    opaque_areas.push(grid.visible(image, left, row * grid.tile_height));
+                }
+                alpha_tiles.push(alpha);
+                stats.add(tile_stats.unwrap_or_default());
+                reconstruction = reconstruction.zip(tile_reconstruction).map(|(mut px, tile)| { paste(&mut px, tile.as_ref(), left, row * grid.tile_height); px });
+            }
+            self.image_progress(image, EncodingPhase::ColorEncode, (row + 1) as f32 / grid.rows as f32);
+        }
+
+        // if any tile has alpha, all of them need it, and the opaque ones are all the same
+        let alpha_tiles = if alpha_tiles.iter().any(Option::is_some) {
+            let opaque = if !opaque_areas.is_empty() {
+                let max = P::cast_from((1u32 << image.bit_depth) - 1);
+                let opaque = self.encode_auxiliary(&tile_image, self.alpha_quantizer, self.speed, Stage::Alpha, std::iter::repeat_n(max, tile_image.width * tile_image.height))?;
+                if self.stats {
+                    for visible in &opaque_areas {
+                        let error = plane_errors(visible, &opaque, ChromaSampling::Cs400).first().copied();
+                        stats.add(FrameStats { alpha: error, ..FrameStats::default() });
+                    }
+                    stats.alpha_time += opaque.time;
+                }
+                opaque.data
+            } else {
+                Vec::new()
+            };
+            Some(alpha_tiles.into_iter().map(|tile| tile.unwrap_or_else(|| opaque.clone())).collect())
+        } else {
+            None
+        };
+        let mut res = self.make_grid_avif(image, grid, color_tiles, alpha_tiles, extras)?;
+        res.stats = self.stats.then(|| stats.finish(image.bit_depth, self.quantizer, self.alpha_quantizer));
+        res.reconstruction = reconstruction.map(|px| Reconstruction::new(px, image.bit_depth));
+        Ok(res)
+    }
+
+    /// Make a new AVIF image from RGB pixels
+    ///
+    /// Make the `Img` for the `buffer` like this:
+    ///
+    /// ```rust,ignore
+    /// Img::new(&pixels_rgb[..], width, height)
+    /// ```
+    ///
+    /// If you have pixels as `u8` slice, then first do:
+    ///
+    /// ```rust,ignore
+    /// use rgb::ComponentSlice;
+    /// let pixels_rgb = pixels_u8.as_rgb();
+    /// ```
+    ///
+    /// If all pixels are gray (`r == g == b`), the image will be encoded as monochrome, like [`Self::encode_gray`].
+    ///
+    /// returns AVIF file, size of color metadata
+    #[inline]
+    pub fn encode_rgb(&self, buffer: Img<&[RGB8]>) -> Result<EncodedImage, Error> {
+        if buffer.pixels().all(|px| px.r == px.g && px.g == px.b) {
+            return self.encode_gray_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.r), None::<std::iter::Empty<_>>);
+        }
+        self.encode_rgb_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels())
+    }
+
+    fn encode_rgb_internal_from_8bit(&self, width: usize, height: usize, pixels: impl Iterator<Item = RGB8> + Send + Sync) -> Result<EncodedImage, Error> {
+        let matrix_coefficients = match self.color_model {
+            ColorModel::YCbCr => MatrixCoefficients::BT601,
+            ColorModel::RGB => MatrixCoefficients::Identity,
+        };
+
+        match self.output_depth {
+            BitDepth::Eight => {
+                let planes = pixels.map(|px| {
+                    let (y, u, v) = match self.color_model {
+                        ColorModel::YCbCr => rgb_to_8_bit_ycbcr(px, BT601),
+                        ColorModel::RGB => rgb_to_8_bit_gbr(px),
+                    };
+                    [y, u, v]
+                });
+                self.encode_raw_planes_8_bit(width, height, planes, None::<[_; 0]>, PixelRange::Full, matrix_coefficients)
+            },
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                let planes = pixels.map(|px| {
+                    let (y, u, v) = match self.color_model {
+                        ColorModel::YCbCr => rgb_to_hbd_ycbcr(px, depth, BT601),
+                        ColorModel::RGB => rgb_to_hbd_gbr(px, depth),
+                    };
+                    [y, u, v]
+                });
+                self.encode_raw_planes_internal(width, height, planes, None::<[_; 0]>, PixelRange::Full, matrix_coefficients, depth)
+            },
+        }
+    }
+
+    /// Make a new AVIF image from 16-bit RGB pixels
+    ///
+    /// Like [`Self::encode_rgb`], but keeps the extra precision of 16-bit inputs.
+    #[inline]
+    pub fn encode_rgb16(&self, buffer: Img<&[RGB16]>) -> Result<EncodedImage, Error> {
+        if buffer.pixels().all(|px| px.r == px.g && px.g == px.b) {
+            return self.encode_gray_internal_from_16bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.r), None::<std::iter::Empty<_>>);
+        }
+        self.encode_rgb_internal_from_16bit(buffer.width(), buffer.height(), buffer.pixels())
+    }
+
+    /// Make a new monochrome AVIF image from grayscale pixels
+    ///
+    /// The image has only a luma plane, which makes it smaller and faster to encode than RGB.
+    /// [`Self::with_chroma_subsampling`] has no effect on it.
+    ///
+    /// returns AVIF file with info about sizes about AV1 payload.
+    #[inline]
+    pub fn encode_gray(&self, buffer: Img<&[u8]>) -> Result<EncodedImage, Error> {
+        self.encode_gray_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels(), None::<std::iter::Empty<_>>)
+    }
+
+    /// Make a new monochrome AVIF image from grayscale pixels with alpha (non-premultiplied)
+    ///
+    /// Alpha is handled the same way as in [`Self::encode_rgba`], and left out if all pixels are opaque.
+    pub fn encode_gray_alpha(&self, in_buffer: Img<&[GrayA<u8>]>) -> Result<EncodedImage, Error> {
+        self.progress(EncodingPhase::Preprocessing, 0.);
+        let rgba = in_buffer.map_buf(|buf| buf.iter().map(|px| RGBA8::new(px.v, px.v, px.v, px.a)).collect::<Vec<_>>());
+        let new_alpha = self.convert_alpha_8bit(rgba.as_ref());
+        let buffer = new_alpha.as_ref().map(|b| b.as_ref()).unwrap_or(rgba.as_ref());
+        let use_alpha = buffer.pixels().any(|px| px.a != 255);
+        self.progress(EncodingPhase::Preprocessing, 1.);
+        self.encode_gray_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels().map(|px| px.r), use_alpha.then(|| buffer.pixels().map(|px| px.a)))
+    }
+
+    fn encode_gray_internal_from_8bit(
+        &self, width: usize, height: usize,
+        luma: impl Iterator<Item = u8> + Send,
+        alpha: Option<impl Iterator<Item = u8> + Send>,
+    ) -> Result<EncodedImage, Error> {
+        match self.output_depth {
+            BitDepth::Eight => {
+                self.encode_gray_planes_internal(width, height, luma.map(|y| [y, 0, 0]), alpha, 8)
+            },
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                let planes = luma.map(move |y| [to_hbd(y, depth), 0, 0]);
+                let alpha = alpha.map(|a| a.map(move |a| to_hbd(a, depth)));
+                self.encode_gray_planes_internal(width, height, planes, alpha, depth)
+            },
+        }
+    }
+
+    fn encode_gray_internal_from_16bit(
+        &self, width: usize, height: usize,
+        luma: impl Iterator<Item = u16> + Send,
+        alpha: Option<impl Iterator<Item = u16> + Send>,
+    ) -> Result<EncodedImage, Error> {
+        match self.output_depth {
+            BitDepth::Eight => {
+                let planes = luma.map(|y| [sixteen_to_eight(y), 0, 0]);
+                let alpha = alpha.map(|a| a.map(sixteen_to_eight));
+                self.encode_gray_planes_internal(width, height, planes, alpha, 8)
+            },
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                let planes = luma.map(move |y| [sixteen_to_hbd(y, depth), 0, 0]);
+                let alpha = alpha.map(|a| a.map(move |a| sixteen_to_hbd(a, depth)));
+                self.encode_gray_planes_internal(width, height, planes, alpha, depth)
+            },
+        }
+    }
+
+    fn encode_rgb_internal_from_16bit(&self, width: usize, height: usize, pixels: impl Iterator<Item = RGB16> + Send + Sync) -> Result<EncodedImage, Error> {
+        let matrix_coefficients = match self.color_model {
+            ColorModel::YCbCr => MatrixCoefficients::BT601,
+            ColorModel::RGB => MatrixCoefficients::Identity,
+        };
+
+        match self.output_depth {
+            BitDepth::Eight => {
+                let planes = pixels.map(|px| {
+                    let (y, u, v) = match self.color_model {
+                        ColorModel::YCbCr => rgb16_to_8_bit_ycbcr(px, BT601),
+                        ColorModel::RGB => rgb16_to_8_bit_gbr(px),
+                    };
+                    [y, u, v]
+                });
+                self.encode_raw_planes_8_bit(width, height, planes, None::<[_; 0]>, PixelRange::Full, matrix_coefficients)
+            },
+            BitDepth::Ten | BitDepth::Twelve | BitDepth::Auto => {
+                let depth = self.output_depth.to_bits();
+                let planes = pixels.map(|px| {
+                    let (y, u, v) = match self.color_model {
+                        ColorModel::YCbCr => rgb16_to_hbd_ycbcr(px, depth, BT601),
+                        ColorModel::RGB => rgb16_to_hbd_gbr(px, depth),
+                    };
+                    [y, u, v]
+                });
+                self.encode_raw_planes_internal(width, height, planes, None::<[_; 0]>, PixelRange::Full, matrix_coefficients, depth)
+            },
+        }
+    }
+
+    /// Encodes AVIF from 3 planar channels that are in the color space described by `matrix_coefficients`,
+    /// with transfer characteristics and color primaries set with [`Self::with_transfer_characteristics`] and [`Self::with_color_primaries`] (sRGB by default).
+    ///
+    /// Alpha always uses full range. Planes must be full-resolution, even if [`Self::with_chroma_subsampling`] has been set.
+    /// If there's no alpha, use `None::<[_; 0]>`.
+    ///
+    /// `color_pixel_range` should be `PixelRange::Full` to avoid worsening already small 8-bit dynamic range.
+    /// Support for limited range may be removed in the future.
+    ///
+    /// If `AlphaColorMode::Premultiplied` has been set, the alpha pixels must be premultiplied.
+    /// `AlphaColorMode::UnassociatedClean` has no effect in this function, and is equivalent to `AlphaColorMode::UnassociatedDirty`.
+    ///
+    /// returns AVIF file, size of color metadata, size of alpha metadata overhead
+    #[inline]
+    pub fn encode_raw_planes_8_bit(
+        &self, width: usize, height: usize,
+        planes: impl IntoIterator<Item = [u8; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = u8> + Send>,
+        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
+    ) -> Result<EncodedImage, Error> {
+        self.encode_raw_planes_internal(width, height, planes, alpha, color_pixel_range, matrix_coefficients, 8)
+    }
+
+    /// Encodes AVIF from 3 planar channels that are in the color space described by `matrix_coefficients`,
+    /// with transfer characteristics and color primaries set with [`Self::with_transfer_characteristics`] and [`Self::with_color_primaries`] (sRGB by default).
+    ///
+    /// The pixels are 10-bit (values `0.=1023`).
+    ///
+    /// Alpha always uses full range. Planes must be full-resolution, even if [`Self::with_chroma_subsampling`] has been set.
+    /// If there's no alpha, use `None::<[_; 0]>`.
+    ///
+    /// `color_pixel_range` should be `PixelRange::Full`. Support for limited range may be removed in the future.
+    ///
+    /// If `AlphaColorMode::Premultiplied` has been set, the alpha pixels must be premultiplied.
+    /// `AlphaColorMode::UnassociatedClean` has no effect in this function, and is equivalent to `AlphaColorMode::UnassociatedDirty`.
+    ///
+    /// returns AVIF file, size of color metadata, size of alpha metadata overhead
+    #[inline]
+    pub fn encode_raw_planes_10_bit(
+        &self, width: usize, height: usize,
+        planes: impl IntoIterator<Item = [u16; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = u16> + Send>,
+        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
+    ) -> Result<EncodedImage, Error> {
+        self.encode_raw_planes_internal(width, height, planes, alpha, color_pixel_range, matrix_coefficients, 10)
+    }
+
+    /// Encodes AVIF from 3 planar channels that are in the color space described by `matrix_coefficients`,
+    /// with transfer characteristics and color primaries set with [`Self::with_transfer_characteristics`] and [`Self::with_color_primaries`] (sRGB by default).
+    ///
+    /// The pixels are 12-bit (values `0.=4095`).
+    ///
+    /// Alpha always uses full range. Planes must be full-resolution, even if [`Self::with_chroma_subsampling`] has been set.
+    /// If there's no alpha, use `None::<[_; 0]>`.
+    ///
+    /// `color_pixel_range` should be `PixelRange::Full`. Support for limited range may be removed in the future.
+    ///
+    /// If `AlphaColorMode::Premultiplied` has been set, the alpha pixels must be premultiplied.
+    /// `AlphaColorMode::UnassociatedClean` has no effect in this function, and is equivalent to `AlphaColorMode::UnassociatedDirty`.
+    ///
+    /// returns AVIF file, size of color metadata, size of alpha metadata overhead
+    #[inline]
+    pub fn encode_raw_planes_12_bit(
+        &self, width: usize, height: usize,
+        planes: impl IntoIterator<Item = [u16; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = u16> + Send>,
+        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
+    ) -> Result<EncodedImage, Error> {
+        self.encode_raw_planes_internal(width, height, planes, alpha, color_pixel_range, matrix_coefficients, 12)
+    }
+
+    #[inline(never)]
+    #[allow(clippy::too_many_arguments)]
+    fn encode_raw_planes_internal<P: rav1e::Pixel + Default>(
+        &self, width: usize, height: usize,
+        planes: impl IntoIterator<Item = [P; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = P> + Send>,
+        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
+        input_pixels_bit_depth: u8,
+    ) -> Result<EncodedImage, Error> {
+        let chroma_sampling = self.chroma_subsampling.chroma_sampling();
+        if chroma_sampling != ChromaSampling::Cs444 && matrix_coefficients == MatrixCoefficients::Identity {
+            return Err(Error::InvalidConfig("chroma subsampling of RGB"));
+        }
+
+        let image = self.image_params(width, height, input_pixels_bit_depth, color_pixel_range, matrix_coefficients, chroma_sampling);
+        self.encode_image(&image, planes, alpha)
+    }
+
+    /// Only the first component of `planes` is used (as luma)
+    fn encode_gray_planes_internal<P: rav1e::Pixel + Default>(
+        &self, width: usize, height: usize,
+        planes: impl IntoIterator<Item = [P; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = P> + Send>,
+        input_pixels_bit_depth: u8,
+    ) -> Result<EncodedImage, Error> {
+        // RGB (identity matrix) is not allowed without 4:4:4 chroma, and the matrix is irrelevant without chroma anyway
+        let image = self.image_params(width, height, input_pixels_bit_depth, PixelRange::Full, MatrixCoefficients::BT601, ChromaSampling::Cs400);
+        self.encode_image(&image, planes, alpha)
+    }
+
+    fn image_params(
+        &self, width: usize, height: usize, bit_depth: u8,
+        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients, chroma_sampling: ChromaSampling,
+    ) -> ImageParams {
+        ImageParams {
+            width,
+            height,
+            bit_depth,
+            color_pixel_range,
+            matrix_coefficients,
+            chroma_sampling,
+            threads: self.threads.map(|threads| {
+                if threads > 0 { threads } else { rayon::current_num_threads() }
+            }),
+            // Calculate deadline from timeout if set
+            deadline: self.timeout.map(|timeout| std::time::Instant::now() + timeout),
+            progress: true,
+        }
+    }
+
+    fn progress(&self, phase: EncodingPhase, fraction: f32) {
+        if let Some(callback) = &self.progress {
+            (callback.0)(Progress { phase, fraction });
+        }
+    }
+
+    fn frame_stats<P: rav1e::Pixel>(&self, image: &ImageParams, color: &Av1Output<P>, alpha: Option<&Av1Output<P>>) -> Option<FrameStats> {
+        self.stats.then(|| FrameStats {
+            color_time: color.time,
+            alpha_time: alpha.map_or(Duration::ZERO, |a| a.time),
+            tiles: color.tiles,
+            grid_cells: 1,
+            color: plane_errors(image, color, image.chroma_sampling),
+            alpha: alpha.and_then(|a| plane_errors(image, a, ChromaSampling::Cs400).first().copied()),
+        })
+    }
+
+    /// Pixels in the range of the bit depth, to be assembled from tiles before [`Reconstruction::new`]
+    fn reconstruct<P: rav1e::Pixel>(&self, image: &ImageParams, color: &Av1Output<P>, alpha: Option<&Av1Output<P>>) -> Option<ImgVec<RGBA16>> {
+        if !self.reconstruction {
+            return None;
+        }
+        let alpha = match alpha {
+            Some(alpha) => Some(alpha.rec.as_deref()?),
+            None => None,
+        };
+        frame_to_rgba(color.rec.as_deref()?, alpha, image.width, image.height, image.bit_depth,
+            image.color_pixel_range, image.matrix_coefficients, image.chroma_sampling, alpha.is_some() && self.premultiplied_alpha)
+    }
+
+    /// Tiles, thumbnails and trial encodes aren't reported individually
+    fn image_progress(&self, image: &ImageParams, phase: EncodingPhase, fraction: f32) {
+        if image.progress {
+            self.progress(phase, fraction);
+        }
+    }
+
+    fn encode_image<P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams,
+        planes: impl IntoIterator<Item = [P; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = P> + Send>,
+    ) -> Result<EncodedImage, Error> {
+        check_dimensions(image.width, image.height)?;
+        let grid = Grid::for_image(image.width, image.height)?;
+        let search = self.target_size.is_some() || self.target_ssim.is_some();
+        let thumbnail = self.thumbnail_size.filter(|&max_dim| image.width.max(image.height) > max_dim);
+        let mut extras = ExtraItems {
+            thumbnail: None,
+            depth_map: self.depth_map.as_ref().map(|aux| self.encode_auxiliary_image(image, aux, Stage::DepthMap)).transpose()?,
+            gain_map: self.gain_map.as_ref().map(|(aux, _)| self.encode_auxiliary_image(image, aux, Stage::GainMap)).transpose()?,
+        };
+        if !search && grid.is_none() && thumbnail.is_none() && self.film_grain == 0 {
+            let (color, alpha) = self.encode_color_and_alpha(image, self.quantizer, self.alpha_quantizer, self.speed, planes, alpha)?;
+            let stats = self.frame_stats(image, &color, alpha.as_ref());
+            let reconstruction = self.reconstruct(image, &color, alpha.as_ref());
+            let mut res = self.make_avif(image, color.data, alpha.map(|a| a.data), &extras)?;
+            res.stats = stats.map(|s| s.finish(image.bit_depth, self.quantizer, self.alpha_quantizer));
+            res.reconstruction = reconstruction.map(|px| Reconstruction::new(px, image.bit_depth));
+            return Ok(res);
+        }
+
+        // the search needs to encode the same pixels many times, and tiles and thumbnails are cut out of the whole image
+        let mut planes: Vec<_> = planes.into_iter().collect();
+        if self.film_grain > 0 {
+            // the noise is going to be synthesized by the decoder, so the original noise would be doubled
+            let threshold = (u32::from(self.film_grain) * 3 / 4 + 1) << (image.bit_depth - 8);
+            denoise(&mut planes, image.width, image.height, threshold);
+        }
+        let alpha: Option<Vec<_>> = alpha.map(|a| a.into_iter().collect());
+        extras.thumbnail = thumbnail.map(|max_dim| self.encode_thumbnail(image, max_dim, &planes, alpha.as_deref())).transpose()?;
+        if search {
+            self.encode_searching_quantizer(image, grid.as_ref(), &planes, alpha.as_deref(), &extras)
+        } else {
+            self.encode_with_quantizer(image, grid.as_ref(), &planes, alpha.as_deref(), &extras, self.quantizer, self.speed, true)
+        }
+    }
+
+    /// Depth map or gain map, encoded at its own quality and bit depth
+    fn encode_auxiliary_image(&self, image: &ImageParams, aux: &AuxiliaryImage, stage: Stage) -> Result<AuxiliaryItem, Error> {
+        let max = (1u32 << aux.bit_depth) - 1;
+        if aux.pixels.pixels().any(|px| u32::from(px) > max) {
+            return Err(Error::InvalidConfig("auxiliary image has values above the max of its bit depth"));
+        }
+        let aux_image = ImageParams {
+            width: aux.pixels.width(),
+            height: aux.pixels.height(),
+            bit_depth: aux.bit_depth,
+            color_pixel_range: PixelRange::Full,
+            chroma_sampling: ChromaSampling::Cs400,
+            progress: false,
+            ..*image
+        };
+        let quantizer = quality_to_quantizer(aux.quality);
+        let pixels = aux.pixels.as_ref().pixels();
+        let data = if aux.bit_depth == 8 {
+            self.encode_auxiliary(&aux_image, quantizer, self.speed, stage, pixels.map(|px| px as u8))?.data
+        } else {
+            self.encode_auxiliary(&aux_image, quantizer, self.speed, stage, pixels)?.data
+        };
+        Ok(AuxiliaryItem { image: aux_image, data })
+    }
+
+    /// Sets SSIM of the result if [`Self::with_target_ssim`] is used. Stats and reconstruction are only made for the `is_final` encode.
+    #[allow(clippy::too_many_arguments)]
+    fn encode_with_quantizer<P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams, grid: Option<&Grid>, planes: &[[P; 3]], alpha: Option<&[P]>, extras: &ExtraItems, quantizer: u8, speed: u8, is_final: bool,
+    ) -> Result<EncodedImage, Error> {
+        // the configured quality is the upper limit, and alpha quality follows color quality
+        let alpha_quantizer = self.alpha_quantizer.saturating_add(quantizer - self.quantizer);
+        if let Some(grid) = grid {
+            return self.encode_grid(image, grid, planes, alpha, extras, quantizer, alpha_quantizer, speed, is_final);
+        }
+        let (color, alpha) = self.encode_color_and_alpha(image, quantizer, alpha_quantizer, speed,
+            planes.iter().copied(), alpha.map(|a| a.iter().copied()))?;
+        let ssim = self.target_ssim.and(color.source.as_ref().zip(color.rec.as_ref()))
+            .map(|(source, rec)| frame_ssim(source, rec, image.width, image.height, image.bit_depth, image.chroma_sampling));
+        let stats = if is_final { self.frame_stats(image, &color, alpha.as_ref()) } else { None };
+        let reconstruction = if is_final { self.reconstruct(image, &color, alpha.as_ref()) } else { None };
+        let mut res = self.make_avif(image, color.data, alpha.map(|a| a.data), extras)?;
+        res.ssim = ssim;
+        res.stats = stats.map(|s| s.finish(image.bit_depth, quantizer, alpha_quantizer));
+        res.reconstruction = reconstruction.map(|px| Reconstruction::new(px, image.bit_depth));
+        Ok(res)
+    }
+
+    /// Downscaled copy of the image, encoded at the configured quality
+    fn encode_thumbnail<P: rav1e::Pixel + Default>(&self, image: &ImageParams, max_dim: usize, planes: &[[P; 3]], alpha: Option<&[P]>) -> Result<Thumbnail, Error> {
+        let long_side = image.width.max(image.height);
+        let scaled = |size: usize| ((size * max_dim + long_side / 2) / long_side).max(1);
+        let thumb = ImageParams { width: scaled(image.width), height: scaled(image.height), progress: false, ..*image };
+        let thumb_planes = downscale(image, &thumb, |i| planes[i]);
+        let thumb_alpha = alpha.map(|alpha| downscale(image, &thumb, |i| [alpha[i]]));
+        let (color, alpha) = self.encode_color_and_alpha(&thumb, self.quantizer, self.alpha_quantizer, self.speed,
+            thumb_planes, thumb_alpha.map(|a| a.into_iter().map(|[a]| a)))?;
+        Ok(Thumbnail { image: thumb, color: color.data, alpha: alpha.map(|a| a.data) })
+    }
+
+    /// Encodes tiles of the grid in parallel
+    #[allow(clippy::too_many_arguments)]
+    fn encode_grid<P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams, grid: &Grid, planes: &[[P; 3]], alpha: Option<&[P]>, extras: &ExtraItems, quantizer: u8, alpha_quantizer: u8, speed: u8, is_final: bool,
+    ) -> Result<EncodedImage, Error> {
+        let tile_image = ImageParams { width: grid.tile_width, height: grid.tile_height, progress: false, ..*image };
+        let origins: Vec<_> = (0..grid.rows)
+            .flat_map(|row| (0..grid.columns).map(move |col| (col * grid.tile_width, row * grid.tile_height)))
+            .collect();
+        let tiles_done = AtomicUsize::new(0);
+        self.image_progress(image, EncodingPhase::ColorEncode, 0.);
+        let tiles = par_map(&origins, &|&(left, top)| {
+            // tiles on the right and bottom edges extend past the image, and repeat its last pixels
+            let indices = (0..grid.tile_height).flat_map(move |y| (0..grid.tile_width).map(move |x| {
+                (left + x).min(image.width - 1) + (top + y).min(image.height - 1) * image.width
+            }));
+            let (color, alpha) = self.encode_color_and_alpha(&tile_image, quantizer, alpha_quantizer, speed,
+                indices.clone().map(|i| planes[i]), alpha.map(|a| indices.map(|i| a[i])))?;
+            // the padding isn't shown, so it's left out of the measurements
+            let visible = grid.visible(image, left, top);
+            let ssim = self.target_ssim.and(color.source.as_ref().zip(color.rec.as_ref()))
+                .map(|(source, rec)| frame_ssim(source, rec, visible.width, visible.height, image.bit_depth, image.chroma_sampling) * (visible.width * visible.height) as f64);
+            // tiles have both color and alpha
+            let done = (tiles_done.fetch_add(1, Ordering::Relaxed) + 1) as f32 / origins.len() as f32;
+            self.image_progress(image, EncodingPhase::ColorEncode, done);
+            if alpha.is_some() {
+                self.image_progress(image, EncodingPhase::AlphaEncode, done);
+            }
+            let stats = if is_final { self.frame_stats(&visible, &color, alpha.as_ref()) } else { None };
+            let reconstruction = if is_final { self.reconstruct(&visible, &color, alpha.as_ref()) } else { None };
+            Ok::<_, Error>((color.data, alpha.map(|a| a.data), ssim, stats, reconstruction))
+        });
+
+        let mut color_tiles = Vec::with_capacity(tiles.len());
+        let mut alpha_tiles = alpha.map(|_| Vec::with_capacity(tiles.len()));
+        let mut ssim_sum = 0.;
+        let mut stats = FrameStats::default();
+        let mut reconstruction = (is_final && self.reconstruction).then(|| ImgVec::new(vec![RGBA16::default(); image.width * image.height], image.width, image.height));
+        for (tile, &(left, top)) in tiles.into_iter().zip(&origins) {
+            let (color, alpha, ssim, tile_stats, tile_reconstruction) = tile?;
+            color_tiles.push(color);
+            alpha_tiles.iter_mut().zip(alpha).for_each(|(tiles, alpha)| tiles.push(alpha));
+            ssim_sum += ssim.unwrap_or(0.);
+            stats.add(tile_stats.unwrap_or_default());
+            reconstruction = reconstruction.zip(tile_reconstruction).map(|(mut px, tile)| { paste(&mut px, tile.as_ref(), left, top); px });
+        }
+        let mut res = self.make_grid_avif(image, grid, color_tiles, alpha_tiles, extras)?;
+        // weighted by the visible area of tiles
+        res.ssim = self.target_ssim.map(|_| ssim_sum / (image.width * image.height) as f64);
+        res.stats = (is_final && self.stats).then(|| stats.finish(image.bit_depth, quantizer, alpha_quantizer));
+        res.reconstruction = reconstruction.map(|px| Reconstruction::new(px, image.bit_depth));
+        Ok(res)
+    }
+
+    /// Finds the quantizer for `target_ssim` and `target_size`, using fast trial encodes
+    fn encode_searching_quantizer<P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams, grid: Option<&Grid>, planes: &[[P; 3]], alpha: Option<&[P]>, extras: &ExtraItems,
+    ) -> Result<EncodedImage, Error> {
+        let trial_speed = self.speed.max(TRIAL_SPEED);
+        // the number of trials isn't known in advance, so they're reported as one
+        let trial_image = ImageParams { progress: false, ..*image };
+        let encode = |quantizer: u8, speed: u8, is_final: bool| self.encode_with_quantizer(&trial_image, grid, planes, alpha, extras, quantizer, speed, is_final);
+        self.image_progress(image, EncodingPhase::ColorEncode, 0.);
+        self.search_quantizer(trial_speed, encode)
+            .inspect(|_| self.image_progress(image, EncodingPhase::ColorEncode, 1.))
+    }
+
+    /// `encode` takes a quantizer, speed, and whether it's the final encode that needs stats and reconstruction
+    fn search_quantizer(&self, trial_speed: u8, encode: impl Fn(u8, u8, bool) -> Result<EncodedImage, Error>) -> Result<EncodedImage, Error> {
+        let mut trials = HashMap::new();
+        // returns file size and SSIM
+        let mut trial = |quantizer: u8| -> Result<(usize, f64), Error> {
+            let t = match trials.entry(quantizer) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(encode(quantizer, trial_speed, false)?),
+            };
+            Ok((t.avif_file.len(), t.ssim.unwrap_or(0.)))
+        };
+
+        let mut quantizer = self.quantizer;
+        if let Some(target_ssim) = self.target_ssim {
+            // higher quantizers can only get worse, so the last one before the first miss is the best
+            quantizer = match lowest_quantizer_where(self.quantizer, |q| Ok(trial(q)?.1 < target_ssim))? {
+                Some(q) => q.saturating_sub(1).max(self.quantizer),
+                None => 255,
+            };
+        }
+        if let Some(target_size) = self.target_size {
+            quantizer = match lowest_quantizer_where(quantizer, |q| Ok(trial(q)?.0 <= target_size))? {
+                Some(q) => q,
+                None => {
+                    let smallest = trials.values().map(|t| t.avif_file.len()).min().unwrap_or(0);
+                    return Err(Error::TargetSizeTooSmall(smallest));
+                },
+            };
+        }
+        let trial = match trials.remove(&quantizer) {
+            Some(trial) => trial,
+            None => encode(quantizer, trial_speed, false)?,
+        };
+        // the trial has no stats and reconstruction, so it's encoded again to get them
+        let final_trial = |trial| if self.stats || self.reconstruction { encode(quantizer, trial_speed, true) } else { Ok(trial) };
+        if trial_speed == self.speed {
+            return final_trial(trial);
+        }
+
+        // slower speeds compress better, so the final encode is very likely to fit too.
+        // Its own SSIM is reported, and if it misses a target that the trial met, the trial is used instead.
+        let res = encode(quantizer, self.speed, true)?;
+        let too_large = self.target_size.is_some_and(|target_size| res.avif_file.len() > target_size);
+        let too_different = self.target_ssim.is_some_and(|target_ssim| res.ssim < Some(target_ssim) && trial.ssim >= Some(target_ssim));
+        if too_large || too_different { final_trial(trial) } else { Ok(res) }
+    }
+
+    fn encode_color_and_alpha<P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams, quantizer: u8, alpha_quantizer: u8, speed: u8,
+        planes: impl IntoIterator<Item = [P; 3]> + Send,
+        alpha: Option<impl IntoIterator<Item = P> + Send>,
+    ) -> Result<(Av1Output<P>, Option<Av1Output<P>>), Error> {
+        let &ImageParams { width, height, chroma_sampling, threads, deadline, .. } = image;
+        let color_description = Some(self.color_description(image.matrix_coefficients));
+        let (mastering_display, content_light, film_grain) = (self.mastering_display, self.content_light, self.film_grain);
+
+        let cancel_token = self.cancellation_token.as_ref();
+        let progress = move |phase, fraction| self.image_progress(image, phase, fraction);
+
+        let encode_color = move || {
+            encode_to_av1::<P>(
+                &Av1EncodeConfig {
+                    width,
+                    height,
+                    bit_depth: image.bit_depth.into(),
+                    quantizer: quantizer.into(),
+                    speed: SpeedTweaks::from_my_preset(speed, quantizer),
+                    threads,
+                    pixel_range: image.color_pixel_range,
+                    chroma_sampling,
+                    color_description,
+                    mastering_display,
+                    content_light,
+                    animated: false,
+                    film_grain,
+                    stage: Stage::Color,
+                },
+                cancel_token,
+                deadline,
+                move |frame| {
+                    let pixel_count = (width * height) as f32;
+                    let planes = planes.into_iter().enumerate().map(|(i, px)| {
+                        if i % PROGRESS_INTERVAL == 0 {
+                            progress(EncodingPhase::FrameFill, i as f32 / pixel_count);
+                        }
+                        px
+                    });
+                    if chroma_sampling == ChromaSampling::Cs400 {
+                        init_frame_1(width, height, planes.map(|[y, ..]| y), frame, cancel_token, deadline)?;
+                    } else {
+                        init_frame_3(width, height, planes, chroma_sampling, frame, cancel_token, deadline)?;
+                    }
+                    progress(EncodingPhase::FrameFill, 1.);
+                    progress(EncodingPhase::ColorEncode, 0.);
+                    Ok(())
+                },
+            ).inspect(|_| progress(EncodingPhase::ColorEncode, 1.))
+        };
+        let encode_alpha = move || {
+            alpha.map(|alpha| {
+                progress(EncodingPhase::AlphaEncode, 0.);
+                self.encode_auxiliary(image, alpha_quantizer, speed, Stage::Alpha, alpha)
+                    .inspect(|_| progress(EncodingPhase::AlphaEncode, 1.))
+            })
+        };
+        #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+        let (color, alpha) = (encode_color(), encode_alpha());
+        #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+        let (color, alpha) = rayon::join(encode_color, encode_alpha);
+        Ok((color?, alpha.transpose()?))
+    }
+
+    /// Single-channel image in full range, e.g. alpha or a depth map
+    fn encode_auxiliary<P: rav1e::Pixel + Default>(
+        &self, image: &ImageParams, quantizer: u8, speed: u8, stage: Stage,
+        pixels: impl IntoIterator<Item = P> + Send,
+    ) -> Result<Av1Output<P>, Error> {
+        let &ImageParams { width, height, deadline, .. } = image;
+        let cancel_token = self.cancellation_token.as_ref();
+        encode_to_av1::<P>(&auxiliary_config(image, quantizer, speed, stage), cancel_token, deadline,
+            |frame| init_frame_1(width, height, pixels, frame, cancel_token, deadline))
+    }
+
+    /// Checks the settings, and starts encoding of an animation with frames of this size
+    pub(crate) fn start_animation(&self, width: usize, height: usize) -> Result<AnimationState, Error> {
+        let unsupported = [
+            (self.target_size.is_some(), "target size of animations"),
+            (self.target_ssim.is_some(), "target SSIM of animations"),
+            (self.thumbnail_size.is_some(), "thumbnails of animations"),
+            (self.film_grain > 0, "film grain in animations"),
+            (self.stats, "stats of animations"),
+            (self.reconstruction, "reconstruction of animations"),
+            (self.depth_map.is_some() || self.gain_map.is_some(), "auxiliary images of animations"),
+        ];
+        if let Some(&(_, msg)) = unsupported.iter().find(|(set, _)| *set) {
+            return Err(Error::Unsupported(msg));
+        }
+        // rav1e's limit for non-still pictures
+        if width < 16 || height < 16 {
+            return Err(Error::Unsupported("animation smaller than 16x16"));
+        }
+        check_dimensions(width, height)?;
+        let matrix_coefficients = match self.color_model {
+            ColorModel::YCbCr => MatrixCoefficients::BT601,
+            ColorModel::RGB => MatrixCoefficients::Identity,
+        };
+        let chroma_sampling = self.chroma_subsampling.chroma_sampling();
+        if chroma_sampling != ChromaSampling::Cs444 && matrix_coefficients == MatrixCoefficients::Identity {
+            return Err(Error::InvalidConfig("chroma subsampling of RGB"));
+        }
+        let bit_depth = self.output_depth.to_bits();
+        let image = self.image_params(width, height, bit_depth, PixelRange::Full, matrix_coefficients, chroma_sampling);
+        self.image_progress(&image, EncodingPhase::ColorEncode, 0.);
+        Ok(if bit_depth == 8 {
+            AnimationState::Eight(self.start_animation_tracks(image)?)
+        } else {
+            AnimationState::High(self.start_animation_tracks(image)?)
+        })
+    }
+
+    fn start_animation_tracks<P: rav1e::Pixel>(&self, image: ImageParams) -> Result<AnimationTracks<P>, Error> {
+        let color = SequenceEncoder::new(Av1EncodeConfig {
+            width: image.width,
+            height: image.height,
+            bit_depth: image.bit_depth.into(),
+            quantizer: self.quantizer.into(),
+            speed: SpeedTweaks::from_my_preset(self.speed, self.quantizer),
+            threads: image.threads,
+            pixel_range: image.color_pixel_range,
+            chroma_sampling: image.chroma_sampling,
+            color_description: Some(self.color_description(image.matrix_coefficients)),
+            mastering_display: self.mastering_display,
+            content_light: self.content_light,
+            animated: true,
+            film_grain: 0,
+            stage: Stage::Color,
+        })?;
+        Ok(AnimationTracks { image, color, alpha: None, frames: 0 })
+    }
+
+    /// Adds a frame to an animation started with [`Self::start_animation`]
+    pub(crate) fn add_animation_frame(&self, state: &mut AnimationState, frame: Img<&[RGBA8]>) -> Result<(), Error> {
+        let new_alpha = self.convert_alpha_8bit(frame);
+        let frame = new_alpha.as_ref().map(|b| b.as_ref()).unwrap_or(frame);
+        match state {
+            AnimationState::Eight(tracks) => self.add_frame_to_tracks(tracks, frame, rgba8_to_8_bit(self.color_model)),
+            AnimationState::High(tracks) => {
+                let depth = tracks.image.bit_depth;
+                self.add_frame_to_tracks(tracks, frame, rgba8_to_hbd(self.color_model, depth))
+            },
+        }
+    }
+
+    /// Like [`Self::add_animation_frame`], but for 16-bit pixels
+    pub(crate) fn add_animation_frame16(&self, state: &mut AnimationState, frame: Img<&[RGBA16]>) -> Result<(), Error> {
+        let new_alpha = self.convert_alpha_16bit(frame);
+        let frame = new_alpha.as_ref().map(|b| b.as_ref()).unwrap_or(frame);
+        match state {
+            AnimationState::Eight(tracks) => self.add_frame_to_tracks(tracks, frame, rgba16_to_8_bit(self.color_model)),
+            AnimationState::High(tracks) => {
+                let depth = tracks.image.bit_depth;
+                self.add_frame_to_tracks(tracks, frame, rgba16_to_hbd(self.color_model, depth))
+            },
+        }
+    }
+
+    /// Sends the frame to the color and alpha encoders, which may keep a few frames for lookahead
+    fn add_frame_to_tracks<T: RowPixel, P: rav1e::Pixel + Default>(
+        &self, tracks: &mut AnimationTracks<P>, frame: Img<&[T]>,
+        convert: impl Fn(T) -> ([P; 3], P) + Sync,
+    ) -> Result<(), Error> {
+        let AnimationTracks { image, color, alpha, frames } = tracks;
+        let &mut ImageParams { width, height, chroma_sampling, deadline, .. } = image;
+        if frame.width() != width || frame.height() != height {
+            return Err(Error::Unsupported("frames of different sizes"));
+        }
+        let cancel_token = self.cancellation_token.as_ref();
+        // the alpha track is started by the first frame that needs it, with opaque frames in place of the previous ones
+        if alpha.is_none() && !frame.pixels().all(T::is_opaque) {
+            self.image_progress(image, EncodingPhase::AlphaEncode, 0.);
+            let mut track = SequenceEncoder::new(Av1EncodeConfig { animated: true, ..auxiliary_config(image, self.alpha_quantizer, self.speed, Stage::Alpha) })?;
+            let max = P::cast_from((1u32 << image.bit_depth) - 1);
+            for _ in 0..*frames {
+                track.send_frame(cancel_token, deadline, |f| init_frame_1(width, height, std::iter::repeat_n(max, width * height), f, cancel_token, deadline))?;
+            }
+            *alpha = Some(track);
+        }
+
+        let convert = &convert;
+        let encode_color = || color.send_frame(cancel_token, deadline, |f| {
+            init_frame_3(width, height, frame.pixels().map(|px| convert(px).0), chroma_sampling, f, cancel_token, deadline)
+        });
+        let encode_alpha = || alpha.as_mut().map(|alpha| alpha.send_frame(cancel_token, deadline, |f| {
+            init_frame_1(width, height, frame.pixels().map(|px| convert(px).1), f, cancel_token, deadline)
+        }));
+        #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+        let (color, alpha) = (encode_color(), encode_alpha());
+        #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+        let (color, alpha) = rayon::join(encode_color, encode_alpha);
+        color?;
+        alpha.transpose()?;
+        *frames += 1;
+        Ok(())
+    }
+
+    /// Encodes the remaining frames, and makes the file. There must be one duration per frame, in units of `timescale`.
+    pub(crate) fn finish_animation(&self, state: AnimationState, durations: &[u32], timescale: u32, repetitions: Option<u32>) -> Result<EncodedImage, Error> {
+        match state {
+            AnimationState::Eight(tracks) => self.finish_animation_tracks(tracks, durations, timescale, repetitions),
+            AnimationState::High(tracks) => self.finish_animation_tracks(tracks, durations, timescale, repetitions),
+        }
+    }
+
+    fn finish_animation_tracks<P: rav1e::Pixel>(&self, tracks: AnimationTracks<P>, durations: &[u32], timescale: u32, repetitions: Option<u32>) -> Result<EncodedImage, Error> {
+        let AnimationTracks { image, color, alpha, .. } = tracks;
+        let cancel_token = self.cancellation_token.as_ref();
+        let finish_color = || color.finish(cancel_token, image.deadline);
+        let finish_alpha = || alpha.map(|alpha| alpha.finish(cancel_token, image.deadline));
+        #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+        let (color, alpha) = (finish_color(), finish_alpha());
+        #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+        let (color, alpha) = rayon::join(finish_color, finish_alpha);
+        let (color, alpha) = (color?, alpha.transpose()?);
+        self.image_progress(&image, EncodingPhase::ColorEncode, 1.);
+        if alpha.is_some() {
+            self.image_progress(&image, EncodingPhase::AlphaEncode, 1.);
+        }
+        self.make_avis(&image, color, alpha, durations, timescale, repetitions)
+    }
+
+    fn make_avif(&self, image: &ImageParams, color: Vec<u8>, alpha: Option<Vec<u8>>, extras: &ExtraItems) -> Result<EncodedImage, Error> {
+        self.image_progress(image, EncodingPhase::Muxing, 0.);
+        let color_byte_size = color.len();
+        let alpha_byte_size = alpha.as_ref().map_or(0, |a| a.len());
+
+        let mut file = mux::HeifFile::new();
+        let color_id = file.add_item(*b"av01", color);
+        // This is redundant, but Chrome wants it, and checks that it matches
+        file.add_property(color_id, color_av1c(image), true);
+        self.add_color_properties(&mut file, color_id, image)?;
+        self.add_transform_properties(&mut file, color_id, image, image)?;
+        if let Some(alpha) = alpha {
+            let alpha_id = file.add_item(*b"av01", alpha);
+            file.add_property(alpha_id, alpha_av1c(image), true);
+            self.add_alpha_properties(&mut file, alpha_id, color_id, image)?;
+            self.add_transform_properties(&mut file, alpha_id, image, image)?;
+        }
+        let thumbnail_byte_size = self.add_extra_items(&mut file, color_id, image, extras)?;
+        self.add_metadata_items(&mut file, color_id);
+
+        let avif_file = file.to_vec();
+        self.image_progress(image, EncodingPhase::Muxing, 1.);
+        Ok(EncodedImage {
+            avif_file, color_byte_size, alpha_byte_size, ssim: None, thumbnail_byte_size, stats: None, reconstruction: None,
+        })
+    }
+
+    /// Image sequence with the first frame also as the primary image, for decoders that don't support animation
+    fn make_avis(&self, image: &ImageParams, color: Vec<Av1Packet>, alpha: Option<Vec<Av1Packet>>, durations: &[u32], timescale: u32, repetitions: Option<u32>) -> Result<EncodedImage, Error> {
+        self.image_progress(image, EncodingPhase::Muxing, 0.);
+        let color_byte_size = color.iter().map(|p| p.data.len()).sum();
+        let alpha_byte_size = alpha.iter().flatten().map(|p| p.data.len()).sum();
+        let (width, height) = image_size(image)?;
+        let samples = |packets: Vec<Av1Packet>| packets.into_iter().zip(durations).map(|(p, &duration)| mux::Sample {
+            data: p.data, duration, sync: p.key,
+        }).collect();
+
+        let mut file = mux::HeifFile::new();
+        file.set_timing(timescale, repetitions);
+        let mut sample_entry = vec![color_av1c(image), self.colr_nclx(image)];
+        sample_entry.extend(self.icc_profile.as_deref().map(mux::colr_icc));
+        let color_track = file.add_track(mux::Track {
+            width, height, sample_entry, alpha_of: None, samples: samples(color),
+        });
+        let alpha_track = alpha.map(|alpha| file.add_track(mux::Track {
+            width, height, sample_entry: vec![alpha_av1c(image)], alpha_of: Some(color_track), samples: samples(alpha),
+        }));
+
+        let color_id = file.add_sample_item(*b"av01", color_track, 0);
+        file.add_property(color_id, color_av1c(image), true);
+        self.add_color_properties(&mut file, color_id, image)?;
+        self.add_transform_properties(&mut file, color_id, image, image)?;
+        if let Some(alpha_track) = alpha_track {
+            let alpha_id = file.add_sample_item(*b"av01", alpha_track, 0);
+            file.add_property(alpha_id, alpha_av1c(image), true);
+            self.add_alpha_properties(&mut file, alpha_id, color_id, image)?;
+            self.add_transform_properties(&mut file, alpha_id, image, image)?;
+        }
+        self.add_metadata_items(&mut file, color_id);
+
+        let avif_file = file.to_vec();
+        self.image_progress(image, EncodingPhase::Muxing, 1.);
+        Ok(EncodedImage {
+            avif_file, color_byte_size, alpha_byte_size, ssim: None, thumbnail_byte_size: 0, stats: None, reconstruction: None,
+        })
+    }
+
+    /// Grid of tiles, which are hidden `av01` items
+    fn make_grid_avif(&self, image: &ImageParams, grid: &Grid, color: Vec<Vec<u8>>, alpha: Option<Vec<Vec<u8>>>, extras: &ExtraItems) -> Result<EncodedImage, Error> {
+        self.image_progress(image, EncodingPhase::Muxing, 0.);
+        let color_byte_size = color.iter().map(Vec::len).sum();
+        let alpha_byte_size = alpha.iter().flatten().map(Vec::len).sum();
+        let (width, height) = image_size(image)?;
+        let (tile_width, tile_height) = image_size(&ImageParams { width: grid.tile_width, height: grid.tile_height, ..*image })?;
+        let grid_data = mux::image_grid(grid.rows as u16, grid.columns as u16, width, height);
+
+        let mut file = mux::HeifFile::new();
+        let color_id = file.add_item(*b"grid", grid_data.clone());
+        self.add_color_properties(&mut file, color_id, image)?;
+        self.add_transform_properties(&mut file, color_id, image, image)?;
+        for tile in color {
+            let tile_id = file.add_item(*b"av01", tile);
+            file.set_hidden(tile_id);
+            file.add_property(tile_id, color_av1c(image), true);
+            file.add_property(tile_id, mux::ispe(tile_width, tile_height), false);
+            file.add_ref(*b"dimg", color_id, tile_id);
+        }
+        if let Some(alpha) = alpha {
+            let alpha_id = file.add_item(*b"grid", grid_data);
+            self.add_alpha_properties(&mut file, alpha_id, color_id, image)?;
+            self.add_transform_properties(&mut file, alpha_id, image, image)?;
+            for tile in alpha {
+                let tile_id = file.add_item(*b"av01", tile);
+                file.set_hidden(tile_id);
+                file.add_property(tile_id, alpha_av1c(image), true);
+                file.add_property(tile_id, mux::ispe(tile_width, tile_height), false);
+                file.add_ref(*b"dimg", alpha_id, tile_id);
+            }
+        }
+        let thumbnail_byte_size = self.add_extra_items(&mut file, color_id, image, extras)?;
+        self.add_metadata_items(&mut file, color_id);
+
+        let avif_file = file.to_vec();
+        self.image_progress(image, EncodingPhase::Muxing, 1.);
+        Ok(EncodedImage {
+            avif_file, color_byte_size, alpha_byte_size, ssim: None, thumbnail_byte_size, stats: None, reconstruction: None,
+        })
+    }
+
+    /// Thumbnail, depth map and gain map. Returns number of bytes of AV1 data of the thumbnail.
+    fn add_extra_items(&self, file: &mut mux::HeifFile, color_id: u16, image: &ImageParams, extras: &ExtraItems) -> Result<usize, Error> {
+        let mut thumbnail_byte_size = 0;
+        if let Some(thumb) = &extras.thumbnail {
+            let thumb_id = file.add_item(*b"av01", thumb.color.clone());
+            file.add_ref(*b"thmb", thumb_id, color_id);
+            file.add_property(thumb_id, color_av1c(&thumb.image), true);
+            self.add_color_properties(file, thumb_id, &thumb.image)?;
+            self.add_transform_properties(file, thumb_id, image, &thumb.image)?;
+            if let Some(alpha) = &thumb.alpha {
+                let alpha_id = file.add_item(*b"av01", alpha.clone());
+                file.add_property(alpha_id, alpha_av1c(&thumb.image), true);
+                self.add_alpha_properties(file, alpha_id, thumb_id, &thumb.image)?;
+                self.add_transform_properties(file, alpha_id, image, &thumb.image)?;
+            }
+            thumbnail_byte_size = thumb.color.len() + thumb.alpha.as_ref().map_or(0, Vec::len);
+        }
+        if let Some(depth) = &extras.depth_map {
+            let depth_id = add_auxiliary_item(file, depth)?;
+            file.add_ref(*b"auxl", depth_id, color_id);
+            file.add_property(depth_id, mux::auxc("urn:mpeg:mpegB:cicp:systems:auxiliary:depth"), false);
+            self.add_transform_properties(file, depth_id, image, &depth.image)?;
+        }
+        if let (Some(gain_map), Some((_, metadata))) = (&extras.gain_map, &self.gain_map) {
+            let gain_map_id = add_auxiliary_item(file, gain_map)?;
+            file.set_hidden(gain_map_id);
+            let signed = |x: f64| ((x * 1e6).round().clamp(i32::MIN.into(), i32::MAX.into()) as i32, 1_000_000);
+            let unsigned = |x: f64| ((x * 1e6).round().clamp(0., u32::MAX.into()) as u32, 1_000_000);
+            let tmap_id = file.add_item(*b"tmap", mux::tone_map(unsigned(metadata.base_hdr_headroom), unsigned(metadata.alternate_hdr_headroom),
+                signed(metadata.gain_map_min), signed(metadata.gain_map_max), unsigned(metadata.gamma),
+                signed(metadata.base_offset), signed(metadata.alternate_offset)));
+            // the base image, then the gain map
+            file.add_ref(*b"dimg", tmap_id, color_id);
+            file.add_ref(*b"dimg", tmap_id, gain_map_id);
+            let (width, height) = image_size(image)?;
+            file.add_property(tmap_id, mux::ispe(width, height), false);
+            // the tone-mapped image is in the color space of the alternate rendition
+            file.add_property(tmap_id, mux::colr_nclx(metadata.alternate_color_primaries as u16, metadata.alternate_transfer_characteristics as u16,
+                image.matrix_coefficients as u16, image.color_pixel_range == PixelRange::Full), false);
+            self.add_transform_properties(file, tmap_id, image, image)?;
+            // the tone-mapped image is preferred by decoders that support it
+            file.add_group(*b"altr", vec![tmap_id, color_id]);
+            file.add_brand(*b"tmap");
+        }
+        Ok(thumbnail_byte_size)
+    }
+
+    /// Properties of the whole image, except the codec configuration
+    fn add_color_properties(&self, file: &mut mux::HeifFile, color_id: u16, image: &ImageParams) -> Result<(), Error> {
+        let (width, height) = image_size(image)?;
+        file.add_property(color_id, mux::ispe(width, height), false);
+        let channels = if image.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
+        file.add_property(color_id, mux::pixi(channels, image.bit_depth), false);
+        // Redundant info, already in AV1, but Safari needs it
+        file.add_property(color_id, self.colr_nclx(image), false);
+        if let Some(icc) = &self.icc_profile {
+            file.add_property(color_id, mux::colr_icc(icc), false);
+        }
+        if let Some(cll) = self.content_light {
+            file.add_property(color_id, mux::clli(cll.max_content_light_level, cll.max_frame_average_light_level), false);
+        }
+        if let Some(md) = self.mastering_display {
+            // AV1 uses RGB order and 0.16 fixed point, but mdcv uses GBR order and units of 0.00002
+            let xy = |p: ChromaticityPoint| (chromaticity_to_mdcv(p.x), chromaticity_to_mdcv(p.y));
+            let [r, g, b] = md.primaries;
+            file.add_property(color_id, mux::mdcv([xy(g), xy(b), xy(r)], xy(md.white_point),
+                fixed_point_to_mdcv(md.max_luminance, 8), fixed_point_to_mdcv(md.min_luminance, 14)), false);
+        }
+        Ok(())
+    }
+
+    fn add_alpha_properties(&self, file: &mut mux::HeifFile, alpha_id: u16, color_id: u16, image: &ImageParams) -> Result<(), Error> {
+        let (width, height) = image_size(image)?;
+        file.add_ref(*b"auxl", alpha_id, color_id);
+        if self.premultiplied_alpha {
+            file.add_ref(*b"prem", color_id, alpha_id);
+        }
+        file.add_property(alpha_id, mux::ispe(width, height), false);
+        file.add_property(alpha_id, mux::auxc("urn:mpeg:mpegB:cicp:systems:auxiliary:alpha"), false);
+        file.add_property(alpha_id, mux::pixi(1, image.bit_depth), false);
+        Ok(())
+    }
+
+    /// Pixel aspect ratio, and then crop, rotation and mirroring in this order, after all other properties.
+    /// `item_image` can be smaller than the whole `image` (e.g. a thumbnail), and the crop is scaled to whole pixels of it.
+    fn add_transform_properties(&self, file: &mut mux::HeifFile, id: u16, image: &ImageParams, item_image: &ImageParams) -> Result<(), Error> {
+        if let Some((h_spacing, v_spacing)) = self.pixel_aspect_ratio {
+            file.add_property(id, mux::pasp(h_spacing, v_spacing), false);
+        }
+        if let Some((left, top, width, height)) = self.crop {
+            let (image_width, image_height) = image_size(image)?;
+            let (item_width, item_height) = image_size(item_image)?;
+            if width == 0 || height == 0 || u64::from(left) + u64::from(width) > image_width.into() || u64::from(top) + u64::from(height) > image_height.into() {
+                return Err(Error::InvalidConfig("crop outside of the image"));
+            }
+            // the crop is scaled to whole pixels of the item, rounded outwards, and clap is relative to its center
+            let scaled = |start: u32, len: u32, image_len: u32, item_len: u32| {
+                let scale = |x: u32| u64::from(x) * u64::from(item_len);
+                let start_px = scale(start) / u64::from(image_len);
+                let end_px = scale(start + len).div_ceil(u64::from(image_len)).min(item_len.into()).max(start_px + 1);
+                let len = end_px - start_px;
+                Ok::<_, Error>((len as u32, fraction(2 * start_px as i64 + len as i64 - i64::from(item_len), 2)?))
+            };
+            let (clap_width, offset_x) = scaled(left, width, image_width, item_width)?;
+            let (clap_height, offset_y) = scaled(top, height, image_height, item_height)?;
+            file.add_property(id, mux::clap((clap_width, 1), (clap_height, 1), offset_x, offset_y), true);
+        }
+        if self.rotation != 0 {
+            file.add_property(id, mux::irot(self.rotation), true);
+        }
+        if let Some(mirror) = self.mirror {
+            file.add_property(id, mux::imir(mirror as u8), true);
+        }
+        Ok(())
+    }
+
+    fn add_metadata_items(&self, file: &mut mux::HeifFile, color_id: u16) {
+        if let Some(exif) = &self.exif {
+            let tiff = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+            // offset to the TIFF header, which immediately follows
+            let mut data = Vec::with_capacity(4 + tiff.len());
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend_from_slice(tiff);
+            let exif_id = file.add_item(*b"Exif", data);
+            file.add_ref(*b"cdsc", exif_id, color_id);
+        }
+        if let Some(xmp) = &self.xmp {
+            let xmp_id = file.add_mime_item("application/rdf+xml", xmp.clone());
+            file.add_ref(*b"cdsc", xmp_id, color_id);
+        }
+    }
+
+    fn colr_nclx(&self, image: &ImageParams) -> Vec<u8> {
+        let cd = self.color_description(image.matrix_coefficients);
+        mux::colr_nclx(cd.color_primaries as u16, cd.transfer_characteristics as u16, cd.matrix_coefficients as u16,
+            image.color_pixel_range == PixelRange::Full)
+    }
+
+    /// With an ICC profile, the primaries and transfer characteristics are unspecified, so that they don't contradict the profile.
+    /// The matrix is still needed to convert YCbCr to RGB.
+    fn color_description(&self, matrix_coefficients: MatrixCoefficients) -> ColorDescription {
+        let (color_primaries, transfer_characteristics) = if self.icc_profile.is_some() {
+            (ColorPrimaries::Unspecified, TransferCharacteristics::Unspecified)
+        } else {
+            (self.color_primaries, self.transfer_characteristics)
+        };
+        ColorDescription { transfer_characteristics, color_primaries, matrix_coefficients }
+    }
+}
+
+/// Single-channel `av01` item with its properties
+fn add_auxiliary_item(file: &mut mux::HeifFile, aux: &AuxiliaryItem) -> Result<u16, Error> {
+    let (width, height) = image_size(&aux.image)?;
+    let id = file.add_item(*b"av01", aux.data.clone());
+    file.add_property(id, alpha_av1c(&aux.image), true);
+    file.add_property(id, mux::ispe(width, height), false);
+    file.add_property(id, mux::pixi(1, aux.image.bit_depth), false);
+    Ok(id)
+}
+
+/// `n / d` in lowest terms
+fn fraction(n: i64, d: u64) -> Result<(i32, u32), Error> {
+    let (mut a, mut b) = (n.unsigned_abs(), d);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let gcd = a.max(1);
+    let n = i32::try_from(n / gcd as i64).map_err(|_| Error::InvalidConfig("image too large"))?;
+    let d = u32::try_from(d / gcd).map_err(|_| Error::InvalidConfig("image too large"))?;
+    Ok((n, d))
+}
+
+fn image_size(image: &ImageParams) -> Result<(u32, u32), Error> {
+    let width = u32::try_from(image.width).map_err(|_| Error::InvalidConfig("image too large"))?;
+    let height = u32::try_from(image.height).map_err(|_| Error::InvalidConfig("image too large"))?;
+    Ok((width, height))
+}
+
+fn color_av1c(image: &ImageParams) -> Vec<u8> {
+    mux::av1c(&mux::Av1C {
+        seq_profile: seq_profile(image.chroma_sampling, image.bit_depth),
+        high_bitdepth: image.bit_depth >= 10,
+        twelve_bit: image.bit_depth >= 12,
+        monochrome: image.chroma_sampling == ChromaSampling::Cs400,
+        chroma_subsampling: match image.chroma_sampling {
+            ChromaSampling::Cs420 | ChromaSampling::Cs400 => (true, true),
+            ChromaSampling::Cs422 => (true, false),
+            ChromaSampling::Cs444 => (false, false),
+        },
+    })
+}
+
+fn alpha_av1c(image: &ImageParams) -> Vec<u8> {
+    mux::av1c(&mux::Av1C {
+        seq_profile: seq_profile(ChromaSampling::Cs400, image.bit_depth),
+        high_bitdepth: image.bit_depth >= 10,
+        twelve_bit: image.bit_depth >= 12,
+        monochrome: true,
+        chroma_subsampling: (true, true),
+    })
+}
+
+/// Speed of trial encodes for [`Encoder::with_target_size`] and [`Encoder::with_target_ssim`]
+const TRIAL_SPEED: u8 = 8;
+
+/// Binary search for the lowest quantizer in `lo..=255` for which `pred` is true, assuming it stays true for higher quantizers
+fn lowest_quantizer_where(mut lo: u8, mut pred: impl FnMut(u8) -> Result<bool, Error>) -> Result<Option<u8>, Error> {
+    let mut hi = 255;
+    let mut found = None;
+    while lo <= hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(mid)? {
+            found = Some(mid);
+            if mid == lo {
+                break;
+            }
+            hi = mid - 1;
+        } else if mid == 255 {
+            break;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Ok(found)
+}
+
+/// Properties of the image being encoded, shared by color and alpha
+#[derive(Clone, Copy)]
+struct ImageParams {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_pixel_range: PixelRange,
+    matrix_coefficients: MatrixCoefficients,
+    chroma_sampling: ChromaSampling,
+    /// Resolved number of threads, or None for the rayon pool
+    threads: Option<usize>,
+    deadline: Option<std::time::Instant>,
+    /// Whether encoding of this image is reported to [`Encoder::with_progress`]
+    progress: bool,
+}
+
+/// Animation being encoded by [`AnimationEncoder`](crate::AnimationEncoder), at the output bit depth
+pub(crate) enum AnimationState {
+    Eight(AnimationTracks<u8>),
+    High(AnimationTracks<u16>),
+}
+
+pub(crate) struct AnimationTracks<P: rav1e::Pixel> {
+    image: ImageParams,
+    color: SequenceEncoder<P>,
+    /// Started by the first frame that isn't opaque
+    alpha: Option<SequenceEncoder<P>>,
+    /// Number of frames sent so far
+    frames: usize,
+}
+
+/// Encoded once, and added to every trial encode
+struct ExtraItems {
+    thumbnail: Option<Thumbnail>,
+    depth_map: Option<AuxiliaryItem>,
+    gain_map: Option<AuxiliaryItem>,
+}
+
+struct AuxiliaryItem {
+    image: ImageParams,
+    data: Vec<u8>,
+}
+
+struct Thumbnail {
+    image: ImageParams,
+    color: Vec<u8>,
+    alpha: Option<Vec<u8>>,
+}
+
+/// Stats of a frame, or a sum of tiles
+#[derive(Clone, Default)]
+struct FrameStats {
+    color_time: Duration,
+    alpha_time: Duration,
+    tiles: usize,
+    grid_cells: usize,
+    color: Vec<PlaneError>,
+    alpha: Option<PlaneError>,
+}
+
+impl FrameStats {
+    fn add(&mut self, other: Self) {
+        self.color_time += other.color_time;
+        self.alpha_time += other.alpha_time;
+        self.tiles += other.tiles;
+        self.grid_cells += other.grid_cells;
+        if self.color.is_empty() {
+            self.color = other.color;
+        } else {
+            self.color.iter_mut().zip(other.color).for_each(|(a, b)| a.add(b));
+        }
+        match (&mut self.alpha, other.alpha) {
+            (Some(a), Some(b)) => a.add(b),
+            (a, b) => *a = a.or(b),
+        }
+    }
+
+    fn finish(self, bit_depth: u8, quantizer: u8, alpha_quantizer: u8) -> EncodingStats {
+        EncodingStats {
+            color_time: self.color_time,
+            alpha_time: self.alpha_time,
+            tiles: self.tiles,
+            grid_cells: self.grid_cells,
+            color_quantizer: quantizer,
+            alpha_quantizer: self.alpha.map(|_| alpha_quantizer),
+            color_planes: self.color.into_iter().map(|p| p.quality(bit_depth)).collect(),
+            alpha_plane: self.alpha.map(|p| p.quality(bit_depth)),
+        }
+    }
+}
+
+/// Differences in one plane, which can be added up over tiles
+#[derive(Clone, Copy, Default)]
+struct PlaneError {
+    /// Sum of squared errors
+    sse: u64,
+    /// SSIM weighed by the number of pixels
+    ssim_sum: f64,
+    pixels: usize,
+}
+
+impl PlaneError {
+    fn add(&mut self, other: Self) {
+        self.sse += other.sse;
+        self.ssim_sum += other.ssim_sum;
+        self.pixels += other.pixels;
+    }
+
+    fn quality(self, bit_depth: u8) -> PlaneQuality {
+        let max = f64::from((1u32 << bit_depth) - 1);
+        let mse = self.sse as f64 / self.pixels as f64;
+        PlaneQuality {
+            psnr: 10. * (max * max / mse).log10(),
+            ssim: self.ssim_sum / self.pixels as f64,
+        }
+    }
+}
+
+/// Compares the planes of the source with the encoder's reconstruction
+fn plane_errors<P: rav1e::Pixel>(image: &ImageParams, out: &Av1Output<P>, chroma_sampling: ChromaSampling) -> Vec<PlaneError> {
+    let (Some(source), Some(rec)) = (&out.source, &out.rec) else {
+        return Vec::new();
+    };
+    let (width, height) = (image.width, image.height);
+    let mut sizes = vec![(width, height)];
+    if let Some((xdec, ydec)) = chroma_sampling.get_decimation() {
+        let chroma = ((width + xdec) >> xdec, (height + ydec) >> ydec);
+        sizes.extend([chroma, chroma]);
+    }
+    sizes.into_iter().zip(source.planes.iter().zip(&rec.planes)).map(|((width, height), (source, rec))| PlaneError {
+        sse: plane_sse(source, rec, width, height),
+        ssim_sum: plane_ssim(source, rec, width, height, image.bit_depth) * (width * height) as f64,
+        pixels: width * height,
+    }).collect()
+}
+
+/// Box filter (averages all pixels covered by each output pixel). `pixel` gets index in the source image.
+fn downscale<P: rav1e::Pixel, const N: usize>(src: &ImageParams, dst: &ImageParams, pixel: impl Fn(usize) -> [P; N]) -> Vec<[P; N]> {
+    let mut out = Vec::with_capacity(dst.width * dst.height);
+    for y in 0..dst.height {
+        let rows = y * src.height / dst.height..((y + 1) * src.height).div_ceil(dst.height);
+        for x in 0..dst.width {
+            let cols = x * src.width / dst.width..((x + 1) * src.width).div_ceil(dst.width);
+            // pixels of a 12-bit thumbnail of a huge image can each cover more than 2^20 source pixels
+            let mut sum = [0u64; N];
+            for row in rows.clone() {
+                for col in cols.clone() {
+                    sum.iter_mut().zip(pixel(col + row * src.width)).for_each(|(s, px)| *s += u64::from(u32::cast_from(px)));
+                }
+            }
+            let n = (rows.len() * cols.len()) as u64;
+            out.push(sum.map(|s| P::cast_from(((s + n / 2) / n) as u32)));
+        }
+    }
+    out
+}
+
+/// Sigma filter: averages each pixel with its 3×3 neighbors that differ from it by at most `threshold`.
+/// It removes noise, but keeps edges.
+fn denoise<P: rav1e::Pixel>(planes: &mut [[P; 3]], width: usize, height: usize, threshold: u32) {
+    let src = planes.to_vec();
+    for y in 0..height {
+        for x in 0..width {
+            let center = src[x + y * width].map(u32::cast_from);
+            let mut sum = [0u32; 3];
+            let mut count = [0u32; 3];
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let px = src[nx + ny * width].map(u32::cast_from);
+                    for c in 0..3 {
+                        if px[c].abs_diff(center[c]) <= threshold {
+                            sum[c] += px[c];
+                            count[c] += 1;
                         }
-                    })
-                    .collect();
-                Some(ImgVec::new(prem, in_buffer.width(), in_buffer.height()))
-            },
+                    }
+                }
+            }
+            // the center is always counted
+            planes[x + y * width] = std::array::from_fn(|c| P::cast_from((sum[c] + count[c] / 2) / count[c]));
         }
     }
+}
 
-    /// Make a new AVIF image from RGB pixels
-    ///
-    /// Make the `Img` for the `buffer` like this:
-    ///
-    /// ```rust,ignore
-    /// Img::new(&pixels_rgb[..], width, height)
-    /// ```
-    ///
-    /// If you have pixels as `u8` slice, then first do:
-    ///
-    /// ```rust,ignore
-    /// use rgb::ComponentSlice;
-    /// let pixels_rgb = pixels_u8.as_rgb();
-    /// ```
-    ///
-    /// returns AVIF file, size of color metadata
-    #[inline]
-    pub fn encode_rgb(&self, buffer: Img<&[RGB8]>) -> Result<EncodedImage, Error> {
-        self.encode_rgb_internal_from_8bit(buffer.width(), buffer.height(), buffer.pixels())
+/// How many pixels are converted between reports of [`EncodingPhase::FrameFill`]
+const PROGRESS_INTERVAL: usize = 1 << 20;
+
+/// How many rows are read from a [`RowSource`] at a time when the image is converted directly into a frame
+const ROWS_PER_READ: usize = 16;
+
+/// Pixels that can be read from a [`RowSource`]
+trait RowPixel: Copy + Default + Send + Sync {
+    fn is_opaque(self) -> bool;
+    fn is_transparent(self) -> bool;
+    /// The same color with alpha = 0
+    fn transparent(self) -> Self;
+    fn premultiplied(self) -> Self;
+}
+
+impl RowPixel for RGBA8 {
+    fn is_opaque(self) -> bool { self.a == 255 }
+    fn is_transparent(self) -> bool { self.a == 0 }
+    fn transparent(self) -> Self { self.rgb().with_alpha(0) }
+    fn premultiplied(self) -> Self { premultiply_8bit(self) }
+}
+
+impl RowPixel for RGBA16 {
+    fn is_opaque(self) -> bool { self.a == 0xFFFF }
+    fn is_transparent(self) -> bool { self.a == 0 }
+    fn transparent(self) -> Self { self.rgb().with_alpha(0) }
+    fn premultiplied(self) -> Self { premultiply_16bit(self) }
+}
+
+/// Converts pixels to 8-bit planes and alpha, for row sources and animation frames
+fn rgba8_to_8_bit(color_model: ColorModel) -> impl Fn(RGBA8) -> ([u8; 3], u8) + Copy + Sync {
+    move |px| {
+        let (y, u, v) = match color_model {
+            ColorModel::YCbCr => rgb_to_8_bit_ycbcr(px.rgb(), BT601),
+            ColorModel::RGB => rgb_to_8_bit_gbr(px.rgb()),
+        };
+        ([y, u, v], px.a)
     }
+}
 
-    fn encode_rgb_internal_from_8bit(&self, width: usize, height: usize, pixels: impl Iterator<Item = RGB8> + Send + Sync) -> Result<EncodedImage, Error> {
-        let matrix_coefficients = match self.color_model {
-            ColorModel::YCbCr => MatrixCoefficients::BT601,
-            ColorModel::RGB => MatrixCoefficients::Identity,
+fn rgba8_to_hbd(color_model: ColorModel, depth: u8) -> impl Fn(RGBA8) -> ([u16; 3], u16) + Copy + Sync {
+    move |px| {
+        let (y, u, v) = match color_model {
+            ColorModel::YCbCr => rgb_to_hbd_ycbcr(px.rgb(), depth, BT601),
+            ColorModel::RGB => rgb_to_hbd_gbr(px.rgb(), depth),
         };
+        ([y, u, v], to_hbd(px.a, depth))
+    }
+}
 
-        match self.output_depth {
-            BitDepth::Eight => {
-                let planes = pixels.map(|px| {
-                    let (y, u, v) = match self.color_model {
-                        ColorModel::YCbCr => rgb_to_8_bit_ycbcr(px, BT601),
-                        ColorModel::RGB => rgb_to_8_bit_gbr(px),
-                    };
-                    [y, u, v]
-                });
-                self.encode_raw_planes_8_bit(width, height, planes, None::<[_; 0]>, PixelRange::Full, matrix_coefficients)
-            },
-            BitDepth::Ten | BitDepth::Auto => {
-                let planes = pixels.map(|px| {
-                    let (y, u, v) = match self.color_model {
-                        ColorModel::YCbCr => rgb_to_10_bit_ycbcr(px, BT601),
-                        ColorModel::RGB => rgb_to_10_bit_gbr(px),
-                    };
-                    [y, u, v]
-                });
-                self.encode_raw_planes_10_bit(width, height, planes, None::<[_; 0]>, PixelRange::Full, matrix_coefficients)
-            },
-        }
+fn rgba16_to_8_bit(color_model: ColorModel) -> impl Fn(RGBA16) -> ([u8; 3], u8) + Copy + Sync {
+    move |px| {
+        let (y, u, v) = match color_model {
+            ColorModel::YCbCr => rgb16_to_8_bit_ycbcr(px.rgb(), BT601),
+            ColorModel::RGB => rgb16_to_8_bit_gbr(px.rgb()),
+        };
+        ([y, u, v], sixteen_to_eight(px.a))
     }
+}
 
-    /// Encodes AVIF from 3 planar channels that are in the color space described by `matrix_coefficients`,
-    /// with sRGB transfer characteristics and color primaries.
-    ///
-    /// Alpha always uses full range. Chroma subsampling is not supported, and it's a bad idea for AVIF anyway.
-    /// If there's no alpha, use `None::<[_; 0]>`.
-    ///
-    /// `color_pixel_range` should be `PixelRange::Full` to avoid worsening already small 8-bit dynamic range.
-    /// Support for limited range may be removed in the future.
-    ///
-    /// If `AlphaColorMode::Premultiplied` has been set, the alpha pixels must be premultiplied.
-    /// `AlphaColorMode::UnassociatedClean` has no effect in this function, and is equivalent to `AlphaColorMode::UnassociatedDirty`.
-    ///
-    /// returns AVIF file, size of color metadata, size of alpha metadata overhead
-    #[inline]
-    pub fn encode_raw_planes_8_bit(
-        &self, width: usize, height: usize,
-        planes: impl IntoIterator<Item = [u8; 3]> + Send,
-        alpha: Option<impl IntoIterator<Item = u8> + Send>,
-        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
-    ) -> Result<EncodedImage, Error> {
-        self.encode_raw_planes_internal(width, height, planes, alpha, color_pixel_range, matrix_coefficients, 8)
+fn rgba16_to_hbd(color_model: ColorModel, depth: u8) -> impl Fn(RGBA16) -> ([u16; 3], u16) + Copy + Sync {
+    move |px| {
+        let (y, u, v) = match color_model {
+            ColorModel::YCbCr => rgb16_to_hbd_ycbcr(px.rgb(), depth, BT601),
+            ColorModel::RGB => rgb16_to_hbd_gbr(px.rgb(), depth),
+        };
+        ([y, u, v], sixteen_to_hbd(px.a, depth))
     }
+}
 
-    /// Encodes AVIF from 3 planar channels that are in the color space described by `matrix_coefficients`,
-    /// with sRGB transfer characteristics and color primaries.
-    ///
-    /// The pixels are 10-bit (values `0.=1023`).
-    ///
-    /// Alpha always uses full range. Chroma subsampling is not supported, and it's a bad idea for AVIF anyway.
-    /// If there's no alpha, use `None::<[_; 0]>`.
-    ///
-    /// `color_pixel_range` should be `PixelRange::Full`. Support for limited range may be removed in the future.
-    ///
-    /// If `AlphaColorMode::Premultiplied` has been set, the alpha pixels must be premultiplied.
-    /// `AlphaColorMode::UnassociatedClean` has no effect in this function, and is equivalent to `AlphaColorMode::UnassociatedDirty`.
-    ///
-    /// returns AVIF file, size of color metadata, size of alpha metadata overhead
-    #[inline]
-    pub fn encode_raw_planes_10_bit(
-        &self, width: usize, height: usize,
-        planes: impl IntoIterator<Item = [u16; 3]> + Send,
-        alpha: Option<impl IntoIterator<Item = u16> + Send>,
-        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
-    ) -> Result<EncodedImage, Error> {
-        self.encode_raw_planes_internal(width, height, planes, alpha, color_pixel_range, matrix_coefficients, 10)
+/// Reads the next `rows.len() / width` rows, and applies the alpha color mode to them
+fn read_rows<T: RowPixel>(source: &mut impl RowSource<T>, rows: &mut [T], width: usize, alpha_color_mode: AlphaColorMode) -> Result<(), Error> {
+    source.read_rows(rows)?;
+    match alpha_color_mode {
+        AlphaColorMode::UnassociatedDirty => {},
+        AlphaColorMode::UnassociatedClean => for row in rows.chunks_exact_mut(width) {
+            // repeating the color on the left is cheap to predict, and doesn't need the neighboring rows
+            let mut color = row.iter().copied().find(|px| !px.is_transparent()).unwrap_or_default();
+            for px in row {
+                if px.is_transparent() {
+                    *px = color.transparent();
+                } else {
+                    color = *px;
+                }
+            }
+        },
+        AlphaColorMode::Premultiplied => rows.iter_mut().for_each(|px| *px = px.premultiplied()),
     }
+    Ok(())
+}
 
-    #[inline(never)]
-    #[allow(clippy::too_many_arguments)]
-    fn encode_raw_planes_internal<P: rav1e::Pixel + Default>(
-        &self, width: usize, height: usize,
-        planes: impl IntoIterator<Item = [P; 3]> + Send,
-        alpha: Option<impl IntoIterator<Item = P> + Send>,
-        color_pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients,
-        input_pixels_bit_depth: u8,
-    ) -> Result<EncodedImage, Error> {
-        let color_description = Some(ColorDescription {
-            transfer_characteristics: TransferCharacteristics::SRGB,
-            color_primaries: ColorPrimaries::BT709, // sRGB-compatible
-            matrix_coefficients,
-        });
+fn premultiply_8bit(px: RGBA8) -> RGBA8 {
+    if px.a == 0 {
+        RGBA8::default()
+    } else {
+        let a = u16::from(px.a);
+        RGBA8::new(
+            ((u16::from(px.r) * a + 127) / 255) as u8,
+            ((u16::from(px.g) * a + 127) / 255) as u8,
+            ((u16::from(px.b) * a + 127) / 255) as u8,
+            px.a,
+        )
+    }
+}
 
-        let threads = self.threads.map(|threads| {
-            if threads > 0 { threads } else { rayon::current_num_threads() }
-        });
+fn premultiply_16bit(px: RGBA16) -> RGBA16 {
+    if px.a == 0 {
+        RGBA16::default()
+    } else {
+        let a = u32::from(px.a);
+        RGBA16::new(
+            ((u32::from(px.r) * a + 0x7FFF) / 0xFFFF) as u16,
+            ((u32::from(px.g) * a + 0x7FFF) / 0xFFFF) as u16,
+            ((u32::from(px.b) * a + 0x7FFF) / 0xFFFF) as u16,
+            px.a,
+        )
+    }
+}
 
-        let cancel_token = self.cancellation_token.as_ref();
-        let cancel_token_alpha = self.cancellation_token.as_ref();
+/// Max width and height of a single frame. Larger images are split into a grid of tiles this large at most,
+/// which keeps rav1e's memory use reasonable and is well within what hardware decoders support.
+const GRID_TILE_SIZE: usize = 4096;
+/// MIAF doesn't allow smaller tiles
+const MIN_GRID_TILE_SIZE: usize = 64;
+/// Tiles are indexed with a byte
+const MAX_GRID_TILES: usize = 256;
+
+/// Layout of tiles of an image that is too large for a single frame
+#[derive(Debug, Clone, Copy)]
+struct Grid {
+    columns: usize,
+    rows: usize,
+    tile_width: usize,
+    tile_height: usize,
+}
 
-        // Calculate deadline from timeout if set
-        let deadline = self.timeout.map(|timeout| std::time::Instant::now() + timeout);
+impl Grid {
+    /// `None` if the image fits in one frame
+    fn for_image(width: usize, height: usize) -> Result<Option<Self>, Error> {
+        if width <= GRID_TILE_SIZE && height <= GRID_TILE_SIZE {
+            return Ok(None);
+        }
+        let columns = width.div_ceil(GRID_TILE_SIZE);
+        let rows = height.div_ceil(GRID_TILE_SIZE);
+        if columns > MAX_GRID_TILES || rows > MAX_GRID_TILES {
+            return Err(Error::InvalidConfig("image too large"));
+        }
+        // tiles need even sizes for chroma subsampling
+        Ok(Some(Self {
+            columns,
+            rows,
+            tile_width: width.div_ceil(columns).next_multiple_of(2).max(MIN_GRID_TILE_SIZE),
+            tile_height: height.div_ceil(rows).next_multiple_of(2).max(MIN_GRID_TILE_SIZE),
+        }))
+    }
 
-        let encode_color = move || {
-            encode_to_av1::<P>(
-                &Av1EncodeConfig {
-                    width,
-                    height,
-                    bit_depth: input_pixels_bit_depth.into(),
-                    quantizer: self.quantizer.into(),
-                    speed: SpeedTweaks::from_my_preset(self.speed, self.quantizer),
-                    threads,
-                    pixel_range: color_pixel_range,
-                    chroma_sampling: ChromaSampling::Cs444,
-                    color_description,
-                },
-                cancel_token,
-                deadline,
-                move |frame| init_frame_3(width, height, planes, frame, cancel_token, deadline),
-            )
-        };
-        let encode_alpha = move || {
-            alpha.map(|alpha| {
-                encode_to_av1::<P>(
-                    &Av1EncodeConfig {
-                        width,
-                        height,
-                        bit_depth: input_pixels_bit_depth.into(),
-                        quantizer: self.alpha_quantizer.into(),
-                        speed: SpeedTweaks::from_my_preset(self.speed, self.alpha_quantizer),
-                        threads,
-                        pixel_range: PixelRange::Full,
-                        chroma_sampling: ChromaSampling::Cs400,
-                        color_description: None,
-                    },
-                    cancel_token_alpha,
-                    deadline,
-                    |frame| init_frame_1(width, height, alpha, frame, cancel_token_alpha, deadline),
-                )
-            })
-        };
-        #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
-        let (color, alpha) = (encode_color(), encode_alpha());
-        #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
-        let (color, alpha) = rayon::join(encode_color, encode_alpha);
-        let (color, alpha) = (color?, alpha.transpose()?);
+    /// Part of the tile at `left`, `top` that is inside the image. Tiles on the right and bottom edges are padded.
+    fn visible(&self, image: &ImageParams, left: usize, top: usize) -> ImageParams {
+        ImageParams {
+            width: self.tile_width.min(image.width - left),
+            height: self.tile_height.min(image.height - top),
+            progress: false,
+            ..*image
+        }
+    }
+}
 
-        let avif_file = avif_serialize::Aviffy::new()
-            .matrix_coefficients(match matrix_coefficients {
-                MatrixCoefficients::Identity => avif_serialize::constants::MatrixCoefficients::Rgb,
-                MatrixCoefficients::BT709 => avif_serialize::constants::MatrixCoefficients::Bt709,
-                MatrixCoefficients::Unspecified => avif_serialize::constants::MatrixCoefficients::Unspecified,
-                MatrixCoefficients::BT601 => avif_serialize::constants::MatrixCoefficients::Bt601,
-                MatrixCoefficients::YCgCo => avif_serialize::constants::MatrixCoefficients::Ycgco,
-                MatrixCoefficients::BT2020NCL => avif_serialize::constants::MatrixCoefficients::Bt2020Ncl,
-                MatrixCoefficients::BT2020CL => avif_serialize::constants::MatrixCoefficients::Bt2020Cl,
-                _ => return Err(Error::Unsupported("matrix coefficients")),
-            })
-            .premultiplied_alpha(self.premultiplied_alpha)
-            .to_vec(&color, alpha.as_deref(), width as u32, height as u32, input_pixels_bit_depth);
-        let color_byte_size = color.len();
-        let alpha_byte_size = alpha.as_ref().map_or(0, |a| a.len());
+/// Like rayon's `par_iter().map().collect()`, but works without the `threading` feature too
+fn par_map<T: Sync, R: Send>(items: &[T], f: &(impl Fn(&T) -> R + Sync)) -> Vec<R> {
+    if items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let (a, b) = items.split_at(items.len() / 2);
+    #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+    let (mut a, b) = (par_map(a, f), par_map(b, f));
+    #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+    let (mut a, b) = rayon::join(|| par_map(a, f), || par_map(b, f));
+    a.extend(b);
+    a
+}
 
-        Ok(EncodedImage {
-            avif_file, color_byte_size, alpha_byte_size,
-        })
+/// 0.16 fixed point to CIE 1931 xy in units of 0.00002
+fn chromaticity_to_mdcv(v: u16) -> u16 {
+    ((u32::from(v) * 50000 + (1 << 15)) >> 16) as u16
+}
+
+/// Fixed point with `frac_bits` to cd/m² in units of 0.0001
+fn fixed_point_to_mdcv(v: u32, frac_bits: u8) -> u32 {
+    ((u64::from(v) * 10000 + (1 << (frac_bits - 1))) >> frac_bits).min(u32::MAX.into()) as u32
+}
+
+/// Same as rav1e's choice of AV1 profile
+fn seq_profile(chroma_sampling: ChromaSampling, bit_depth: u8) -> u8 {
+    match chroma_sampling {
+        _ if bit_depth >= 12 => 2,
+        ChromaSampling::Cs422 => 2,
+        ChromaSampling::Cs444 => 1,
+        ChromaSampling::Cs420 | ChromaSampling::Cs400 => 0,
     }
 }
 
+/// Expands 8-bit value to 10 or 12 bits
 #[inline(always)]
-fn to_ten(x: u8) -> u16 {
-    (u16::from(x) << 2) | (u16::from(x) >> 6)
+fn to_hbd(x: u8, depth: u8) -> u16 {
+    (u16::from(x) << (depth - 8)) | (u16::from(x) >> (16 - depth))
 }
 
+/// Rounds 16-bit value to 10 or 12 bits
 #[inline(always)]
-fn rgb_to_10_bit_gbr(px: rgb::RGB<u8>) -> (u16, u16, u16) {
-    (to_ten(px.g), to_ten(px.b), to_ten(px.r))
+fn sixteen_to_hbd(x: u16, depth: u8) -> u16 {
+    ((u32::from(x) * ((1 << depth) - 1) + 0x7FFF) / 0xFFFF) as u16
+}
+
+/// Rounds 16-bit value to 8 bits
+#[inline(always)]
+fn sixteen_to_eight(x: u16) -> u8 {
+    ((u32::from(x) * 255 + 0x7FFF) / 0xFFFF) as u8
+}
+
+#[inline(always)]
+fn rgb_to_hbd_gbr(px: rgb::RGB<u8>, depth: u8) -> (u16, u16, u16) {
+    (to_hbd(px.g, depth), to_hbd(px.b, depth), to_hbd(px.r, depth))
 }
 
 #[inline(always)]
@@ -561,29 +2743,53 @@ fn rgb_to_8_bit_gbr(px: rgb::RGB<u8>) -> (u8, u8, u8) {
     (px.g, px.b, px.r)
 }
 
+#[inline(always)]
+fn rgb16_to_hbd_gbr(px: RGB16, depth: u8) -> (u16, u16, u16) {
+    (sixteen_to_hbd(px.g, depth), sixteen_to_hbd(px.b, depth), sixteen_to_hbd(px.r, depth))
+}
+
+#[inline(always)]
+fn rgb16_to_8_bit_gbr(px: RGB16) -> (u8, u8, u8) {
+    (sixteen_to_eight(px.g), sixteen_to_eight(px.b), sixteen_to_eight(px.r))
+}
+
 // const REC709: [f32; 3] = [0.2126, 0.7152, 0.0722];
 const BT601: [f32; 3] = [0.2990, 0.5870, 0.1140];
 
+/// `input_max` is 255 for 8-bit and 65535 for 16-bit pixels
 #[inline(always)]
-fn rgb_to_ycbcr(px: rgb::RGB<u8>, depth: u8, matrix: [f32; 3]) -> (f32, f32, f32) {
+fn rgb_to_ycbcr<T: Copy + Into<f32>>(px: rgb::RGB<T>, input_max: f32, depth: u8, matrix: [f32; 3]) -> (f32, f32, f32) {
+    let (r, g, b) = (px.r.into(), px.g.into(), px.b.into());
     let max_value = ((1 << depth) - 1) as f32;
-    let scale = max_value / 255.;
+    let scale = max_value / input_max;
     let shift = (max_value * 0.5).round();
-    let y = (scale * matrix[2]).mul_add(f32::from(px.b), (scale * matrix[0]).mul_add(f32::from(px.r), scale * matrix[1] * f32::from(px.g)));
-    let cb = f32::from(px.b).mul_add(scale, -y).mul_add(0.5 / (1. - matrix[2]), shift);
-    let cr = f32::from(px.r).mul_add(scale, -y).mul_add(0.5 / (1. - matrix[0]), shift);
+    let y = (scale * matrix[2]).mul_add(b, (scale * matrix[0]).mul_add(r, scale * matrix[1] * g));
+    let cb = b.mul_add(scale, -y).mul_add(0.5 / (1. - matrix[2]), shift);
+    let cr = r.mul_add(scale, -y).mul_add(0.5 / (1. - matrix[0]), shift);
     (y.round(), cb.round(), cr.round())
 }
 
 #[inline(always)]
-fn rgb_to_10_bit_ycbcr(px: rgb::RGB<u8>, matrix: [f32; 3]) -> (u16, u16, u16) {
-    let (y, u, v) = rgb_to_ycbcr(px, 10, matrix);
+fn rgb_to_hbd_ycbcr(px: rgb::RGB<u8>, depth: u8, matrix: [f32; 3]) -> (u16, u16, u16) {
+    let (y, u, v) = rgb_to_ycbcr(px, 255., depth, matrix);
     (y as u16, u as u16, v as u16)
 }
 
 #[inline(always)]
 fn rgb_to_8_bit_ycbcr(px: rgb::RGB<u8>, matrix: [f32; 3]) -> (u8, u8, u8) {
-    let (y, u, v) = rgb_to_ycbcr(px, 8, matrix);
+    let (y, u, v) = rgb_to_ycbcr(px, 255., 8, matrix);
+    (y as u8, u as u8, v as u8)
+}
+
+#[inline(always)]
+fn rgb16_to_hbd_ycbcr(px: RGB16, depth: u8, matrix: [f32; 3]) -> (u16, u16, u16) {
+    let (y, u, v) = rgb_to_ycbcr(px, 65535., depth, matrix);
+    (y as u16, u as u16, v as u16)
+}
+
+#[inline(always)]
+fn rgb16_to_8_bit_ycbcr(px: RGB16, matrix: [f32; 3]) -> (u8, u8, u8) {
+    let (y, u, v) = rgb_to_ycbcr(px, 65535., 8, matrix);
     (y as u8, u as u8, v as u8)
 }
 
@@ -721,6 +2927,27 @@ struct Av1EncodeConfig {
     pub pixel_range: PixelRange,
     pub chroma_sampling: ChromaSampling,
     pub color_description: Option<ColorDescription>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light: Option<ContentLight>,
+    /// Image sequence with inter frames
+    pub animated: bool,
+    /// Photon noise level (approx. ISO/100), 0 for none
+    pub film_grain: u8,
+    /// For errors
+    pub stage: Stage,
+}
+
+impl Av1EncodeConfig {
+    fn error(&self, cause: Rav1eError) -> Error {
+        Error::EncodingError(EncodingErrorDetail {
+            cause,
+            stage: self.stage,
+            width: self.width,
+            height: self.height,
+            bit_depth: self.bit_depth as u8,
+            chroma_sampling: self.chroma_sampling,
+        })
+    }
 }
 
 fn rav1e_config(p: &Av1EncodeConfig) -> Config {
@@ -730,7 +2957,15 @@ fn rav1e_config(p: &Av1EncodeConfig) -> Config {
         let threads = p.threads.unwrap_or_else(rayon::current_num_threads);
         threads.min((p.width * p.height) / (p.speed.min_tile_size as usize).pow(2))
     };
-    let speed_settings = p.speed.speed_settings();
+    let mut speed_settings = p.speed.speed_settings();
+    if p.animated {
+        // the still image tweaks disable what makes inter frames efficient
+        let preset = SpeedSettings::from_preset(p.speed.speed_preset);
+        speed_settings.multiref = preset.multiref;
+        speed_settings.rdo_lookahead_frames = preset.rdo_lookahead_frames;
+        speed_settings.scene_detection_mode = preset.scene_detection_mode;
+        speed_settings.motion.include_near_mvs = preset.motion.include_near_mvs;
+    }
     let cfg = Config::new()
         .with_encoder_config(EncoderConfig {
         width: p.width,
@@ -742,14 +2977,14 @@ fn rav1e_config(p: &Av1EncodeConfig) -> Config {
         chroma_sample_position: ChromaSamplePosition::Unknown,
         pixel_range: p.pixel_range,
         color_description: p.color_description,
-        mastering_display: None,
-        content_light: None,
+        mastering_display: p.mastering_display,
+        content_light: p.content_light,
         enable_timing_info: false,
-        still_picture: true,
+        still_picture: !p.animated,
         error_resilient: false,
         switch_frame_interval: 0,
-        min_key_frame_interval: 0,
-        max_key_frame_interval: 0,
+        min_key_frame_interval: if p.animated { 12 } else { 0 },
+        max_key_frame_interval: if p.animated { 240 } else { 0 },
         reservoir_frame_delay: None,
         low_latency: false,
         quantizer: p.quantizer,
@@ -759,7 +2994,17 @@ fn rav1e_config(p: &Av1EncodeConfig) -> Config {
         tile_cols: 0,
         tile_rows: 0,
         tiles,
-        film_grain_params: None,
+        film_grain_params: (p.film_grain > 0).then(|| vec![generate_photon_noise_params(0, u64::MAX, NoiseGenArgs {
+            iso_setting: u32::from(p.film_grain) * 100,
+            width: p.width as u32,
+            height: p.height as u32,
+            transfer_function: match p.color_description {
+                Some(cd) if cd.transfer_characteristics == TransferCharacteristics::SMPTE2084 => TransferFunction::SMPTE2084,
+                _ => TransferFunction::BT1886,
+            },
+            chroma_grain: false,
+            random_seed: None,
+        })]),
         level_idx: None,
         speed_settings,
     });
@@ -774,7 +3019,8 @@ fn rav1e_config(p: &Av1EncodeConfig) -> Config {
 fn init_frame_3<P: rav1e::Pixel + Default>(
     width: usize,
     height: usize,
-    planes: impl IntoIterator<Item = [P; 3]> + Send,
+    planes: impl IntoIterator<Item = [P; 3]>,
+    chroma_sampling: ChromaSampling,
     frame: &mut Frame<P>,
     cancel_token: Option<&CancellationToken>,
     deadline: Option<std::time::Instant>,
@@ -790,21 +3036,60 @@ fn init_frame_3<P: rav1e::Pixel + Default>(
     let mut pixel_count = 0usize;
     const CHECK_INTERVAL: usize = 1_000_000; // Check every ~1MP
 
-    for ((y, u), v) in y.rows_iter_mut().zip(u.rows_iter_mut()).zip(v.rows_iter_mut()).take(height) {
-        let y = &mut y[..width];
-        let u = &mut u[..width];
-        let v = &mut v[..width];
-        for ((y, u), v) in y.iter_mut().zip(u).zip(v) {
+    let (xdec, ydec) = chroma_sampling.get_decimation().unwrap_or((0, 0));
+    if xdec == 0 && ydec == 0 {
+        for ((y, u), v) in y.rows_iter_mut().zip(u.rows_iter_mut()).zip(v.rows_iter_mut()).take(height) {
+            let y = &mut y[..width];
+            let u = &mut u[..width];
+            let v = &mut v[..width];
+            for ((y, u), v) in y.iter_mut().zip(u).zip(v) {
+                let px = planes.next().ok_or(Error::TooFewPixels)?;
+                *y = px[0];
+                *u = px[1];
+                *v = px[2];
+
+                pixel_count += 1;
+                if pixel_count % CHECK_INTERVAL == 0 {
+                    check_cancellation(cancel_token, deadline)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Chroma is averaged over 2x1 or 2x2 blocks (edges of odd-sized images have fewer samples)
+    let chroma_width = (width + xdec) >> xdec;
+    let mut sums = vec![(0u32, 0u32); chroma_width];
+    let mut u_rows = u.rows_iter_mut();
+    let mut v_rows = v.rows_iter_mut();
+    for (row, y) in y.rows_iter_mut().take(height).enumerate() {
+        for (x, y) in y[..width].iter_mut().enumerate() {
             let px = planes.next().ok_or(Error::TooFewPixels)?;
             *y = px[0];
-            *u = px[1];
-            *v = px[2];
+            let sum = &mut sums[x >> xdec];
+            sum.0 += u32::cast_from(px[1]);
+            sum.1 += u32::cast_from(px[2]);
 
             pixel_count += 1;
             if pixel_count % CHECK_INTERVAL == 0 {
                 check_cancellation(cancel_token, deadline)?;
             }
         }
+
+        let last_row = row + 1 == height;
+        if ydec > 0 && row & 1 == 0 && !last_row {
+            continue;
+        }
+        let rows = if ydec > 0 && row & 1 == 1 { 2 } else { 1 };
+        let u = &mut u_rows.next().unwrap()[..chroma_width];
+        let v = &mut v_rows.next().unwrap()[..chroma_width];
+        for (cx, ((u, v), sum)) in u.iter_mut().zip(v).zip(sums.iter_mut()).enumerate() {
+            let cols = if xdec > 0 && (cx << 1) + 1 < width { 2 } else { 1 };
+            let n = cols * rows;
+            *u = P::cast_from((sum.0 + n / 2) / n);
+            *v = P::cast_from((sum.1 + n / 2) / n);
+            *sum = (0, 0);
+        }
     }
     Ok(())
 }
@@ -812,7 +3097,7 @@ fn init_frame_3<P: rav1e::Pixel + Default>(
 fn init_frame_1<P: rav1e::Pixel + Default>(
     width: usize,
     height: usize,
-    planes: impl IntoIterator<Item = P> + Send,
+    planes: impl IntoIterator<Item = P>,
     frame: &mut Frame<P>,
     cancel_token: Option<&CancellationToken>,
     deadline: Option<std::time::Instant>,
@@ -837,33 +3122,68 @@ fn init_frame_1<P: rav1e::Pixel + Default>(
     Ok(())
 }
 
+/// AV1 data of one frame of an image sequence
+struct Av1Packet {
+    data: Vec<u8>,
+    /// Can be decoded without the previous frames
+    key: bool,
+}
+
+/// AV1 data of a single frame
+struct Av1Output<P: rav1e::Pixel> {
+    data: Vec<u8>,
+    /// Source frame as seen by the encoder
+    source: Option<Arc<Frame<P>>>,
+    /// Decoded frame, for measuring quality
+    rec: Option<Arc<Frame<P>>>,
+    /// Including filling of the frame
+    time: Duration,
+    /// Number of AV1 tiles
+    tiles: usize,
+}
+
 #[inline(never)]
 fn encode_to_av1<P: rav1e::Pixel>(
     p: &Av1EncodeConfig,
     cancel_token: Option<&CancellationToken>,
     deadline: Option<std::time::Instant>,
     init: impl FnOnce(&mut Frame<P>) -> Result<(), Error>,
-) -> Result<Vec<u8>, Error> {
-    // Check cancellation/timeout before starting
-    if let Some(token) = cancel_token {
-        if token.is_cancelled() {
-            return Err(Error::Cancelled);
-        }
-    }
-    if let Some(deadline) = deadline {
-        if std::time::Instant::now() >= deadline {
-            return Err(Error::Cancelled);
-        }
-    }
+) -> Result<Av1Output<P>, Error> {
+    let start = Instant::now();
+    let (ctx, mut frame) = new_av1_frame(p, cancel_token, deadline)?;
+    init(&mut frame)?;
+    encode_av1_frame(p, ctx, frame, cancel_token, deadline, start)
+}
 
-    let mut ctx: Context<P> = rav1e_config(p).new_context()?;
-    let mut frame = ctx.new_frame();
+/// Encoder and an empty frame for it, for filling the frame before deciding whether to encode it
+fn new_av1_frame<P: rav1e::Pixel>(
+    p: &Av1EncodeConfig,
+    cancel_token: Option<&CancellationToken>,
+    deadline: Option<std::time::Instant>,
+) -> Result<(Context<P>, Frame<P>), Error> {
+    check_cancellation(cancel_token, deadline)?;
+    let ctx: Context<P> = rav1e_config(p).new_context().map_err(|e| p.error(Rav1eError::InvalidConfig(e)))?;
+    let frame = ctx.new_frame();
+    Ok((ctx, frame))
+}
 
-    init(&mut frame)?;
-    ctx.send_frame(frame)?;
+/// Encodes a frame made by [`new_av1_frame`]. `start` is when the encoding started, for [`Av1Output::time`].
+fn encode_av1_frame<P: rav1e::Pixel>(
+    p: &Av1EncodeConfig,
+    mut ctx: Context<P>,
+    frame: Frame<P>,
+    cancel_token: Option<&CancellationToken>,
+    deadline: Option<std::time::Instant>,
+    start: Instant,
+) -> Result<Av1Output<P>, Error> {
+    let config = rav1e_config(p);
+    let tiles = config.tiling_info().map_or(1, |t| t.cols * t.rows);
+    ctx.send_frame(frame).map_err(|e| p.error(Rav1eError::Status(e)))?;
     ctx.flush();
 
     let mut out = Vec::new();
+    let mut source = None;
+    let mut rec = None;
 
     loop {
         // Check cancellation on every iteration (fast: ~5-15ns for token, ~20-50ns for timeout)
@@ -883,12 +3203,84 @@ fn encode_to_av1<P: rav1e::Pixel>(
             Ok(mut packet) => match packet.frame_type {
                 FrameType::KEY => {
                     out.append(&mut packet.data);
+                    source = packet.source;
+                    rec = packet.rec;
                 },
                 _ => continue,
             },
             Err(EncoderStatus::Encoded | EncoderStatus::LimitReached) => break,
-            Err(err) => Err(err)?,
+            Err(err) => return Err(p.error(Rav1eError::Status(err))),
+        }
+    }
+    Ok(Av1Output { data: out, source, rec, time: start.elapsed(), tiles })
+}
+
+/// Config of a single-channel image in full range, e.g. alpha or a depth map
+fn auxiliary_config(image: &ImageParams, quantizer: u8, speed: u8, stage: Stage) -> Av1EncodeConfig {
+    Av1EncodeConfig {
+        width: image.width,
+        height: image.height,
+        bit_depth: image.bit_depth.into(),
+        quantizer: quantizer.into(),
+        speed: SpeedTweaks::from_my_preset(speed, quantizer),
+        threads: image.threads,
+        pixel_range: PixelRange::Full,
+        chroma_sampling: ChromaSampling::Cs400,
+        color_description: None,
+        mastering_display: None,
+        content_light: None,
+        animated: false,
+        film_grain: 0,
+        stage,
+    }
+}
+
+/// Encodes frames of an image sequence in order, as they're sent. Returns one packet per frame.
+struct SequenceEncoder<P: rav1e::Pixel> {
+    config: Av1EncodeConfig,
+    ctx: Context<P>,
+    packets: Vec<Av1Packet>,
+}
+
+impl<P: rav1e::Pixel> SequenceEncoder<P> {
+    fn new(config: Av1EncodeConfig) -> Result<Self, Error> {
+        let ctx = rav1e_config(&config).new_context().map_err(|e| config.error(Rav1eError::InvalidConfig(e)))?;
+        Ok(Self { config, ctx, packets: Vec::new() })
+    }
+
+    #[inline(never)]
+    fn send_frame(
+        &mut self,
+        cancel_token: Option<&CancellationToken>,
+        deadline: Option<std::time::Instant>,
+        init: impl FnOnce(&mut Frame<P>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        check_cancellation(cancel_token, deadline)?;
+        let mut frame = self.ctx.new_frame();
+        init(&mut frame)?;
+        self.ctx.send_frame(frame).map_err(|e| self.config.error(Rav1eError::Status(e)))?;
+        self.receive(cancel_token, deadline)
+    }
+
+    /// Encodes the frames that rav1e has kept for lookahead
+    fn finish(mut self, cancel_token: Option<&CancellationToken>, deadline: Option<std::time::Instant>) -> Result<Vec<Av1Packet>, Error> {
+        self.ctx.flush();
+        self.receive(cancel_token, deadline)?;
+        Ok(self.packets)
+    }
+
+    fn receive(&mut self, cancel_token: Option<&CancellationToken>, deadline: Option<std::time::Instant>) -> Result<(), Error> {
+        loop {
+            check_cancellation(cancel_token, deadline)?;
+            match self.ctx.receive_packet() {
+                Ok(packet) => self.packets.push(Av1Packet {
+                    data: packet.data,
+                    key: packet.frame_type == FrameType::KEY,
+                }),
+                Err(EncoderStatus::Encoded) => continue,
+                Err(EncoderStatus::NeedMoreData | EncoderStatus::LimitReached) => return Ok(()),
+                Err(err) => return Err(self.config.error(Rav1eError::Status(err))),
+            }
         }
     }
-    Ok(out)
 }
@@ -31,6 +31,7 @@ use std::sync::Arc;
 #[derive(Debug, Clone)]
 pub struct CancellationToken {
     cancelled: Arc<AtomicBool>,
+    parent: Option<Box<CancellationToken>>,
 }
 
 impl CancellationToken {
@@ -39,6 +40,17 @@ impl CancellationToken {
     pub fn new() -> Self {
         Self {
             cancelled: Arc::new(AtomicBool::new(false)),
+            parent: None,
+        }
+    }
+
+    /// Make a new token that is cancelled when this one is, but can also be cancelled on its own
+    /// without affecting this token.
+    #[must_use]
+    pub fn child_token(&self) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            parent: Some(Box::new(self.clone())),
         }
     }
 
@@ -52,10 +64,10 @@ impl CancellationToken {
 
     /// Check if cancellation has been requested
     ///
-    /// Returns `true` if `cancel()` has been called.
+    /// Returns `true` if `cancel()` has been called on this token or its parent.
     #[inline]
     pub fn is_cancelled(&self) -> bool {
-        self.cancelled.load(Ordering::Relaxed)
+        self.cancelled.load(Ordering::Relaxed) || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
     }
 
     /// Reset the cancellation state
@@ -72,6 +84,30 @@ impl Default for CancellationToken {
     }
 }
 
+/// Encoding running on a thread pool for blocking tasks, which is cancelled when the future is dropped
+#[cfg(feature = "async")]
+pub(crate) struct CancelOnDrop<T> {
+    pub task: blocking::Task<T>,
+    pub token: CancellationToken,
+}
+
+#[cfg(feature = "async")]
+impl<T> std::future::Future for CancelOnDrop<T> {
+    type Output = T;
+
+    fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<T> {
+        std::pin::Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> Drop for CancelOnDrop<T> {
+    fn drop(&mut self) {
+        // the thread can't be stopped, but the encoder checks the token often
+        self.token.cancel();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,4 +136,18 @@ mod tests {
         assert!(token.is_cancelled());
         assert!(clone.is_cancelled());
     }
+
+    #[test]
+    fn test_cancellation_token_child() {
+        let token = CancellationToken::new();
+        let child = token.child_token();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!token.is_cancelled());
+
+        let child = token.child_token();
+        token.cancel();
+        assert!(child.is_cancelled());
+    }
 }
@@ -0,0 +1,210 @@
+use crate::av1encoder::{AlphaColorMode, BitDepth, ChromaSubsampling, ColorModel};
+use crate::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Encoder settings that can be stored in config files, for [`Encoder::try_with_config`](crate::Encoder::try_with_config)
+///
+/// It can be written and parsed as text, a comma- or newline-separated list of `key=value` and preset names,
+/// e.g. `q=80,s=4,depth=10,alpha=clean` or `web,q=70`. Keys are:
+///
+/// * `q` or `quality`, `aq` or `alpha-quality`: 1-100
+/// * `s` or `speed`: 1-10
+/// * `depth`: `8`, `10`, `12` or `auto`
+/// * `color`: `ycbcr` or `rgb`
+/// * `chroma`: `444`, `422` or `420`
+/// * `alpha`: `clean`, `dirty` or `premultiplied`
+/// * `threads`, `grain` (0-64), `thumbnail` (max width and height)
+///
+/// Presets must come before other settings, which override them. Text after `#` on a line is ignored.
+///
+/// With the `serde` feature, it's serializable with the long names of the keys, and the same values as the text.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default, rename_all = "kebab-case"))]
+#[non_exhaustive]
+pub struct EncoderConfig {
+    /// 1-100, see [`Encoder::with_quality`](crate::Encoder::with_quality)
+    pub quality: f32,
+    /// 1-100. `None` makes it a bit higher than `quality`, since alpha is cheap to store and its artifacts are very visible.
+    pub alpha_quality: Option<f32>,
+    /// 1-10, see [`Encoder::with_speed`](crate::Encoder::with_speed)
+    pub speed: u8,
+    pub bit_depth: BitDepth,
+    pub color_model: ColorModel,
+    pub chroma_subsampling: ChromaSubsampling,
+    pub alpha_color_mode: AlphaColorMode,
+    /// `None` uses all threads of the rayon thread pool
+    pub threads: Option<usize>,
+    /// 0-64, see [`Encoder::with_film_grain`](crate::Encoder::with_film_grain)
+    pub film_grain: u8,
+    /// Max width and height, see [`Encoder::with_thumbnail`](crate::Encoder::with_thumbnail)
+    pub thumbnail: Option<usize>,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            quality: 80.,
+            alpha_quality: None,
+            speed: 5,
+            bit_depth: BitDepth::Auto,
+            color_model: ColorModel::YCbCr,
+            chroma_subsampling: ChromaSubsampling::Cs444,
+            alpha_color_mode: AlphaColorMode::UnassociatedClean,
+            threads: None,
+            film_grain: 0,
+            thumbnail: None,
+        }
+    }
+}
+
+impl EncoderConfig {
+    /// Names of presets for [`Self::preset`]
+    pub const PRESETS: [&'static str; 4] = ["web", "photo-archive", "screenshot", "thumbnail"];
+
+    /// Settings for a common use:
+    ///
+    /// * `web`: good compression of photos and graphics, at a reasonable speed
+    /// * `photo-archive`: high quality 12-bit images, slow to encode, keeping the color of transparent pixels
+    /// * `screenshot`: high quality, for sharp text and edges of user interfaces
+    /// * `thumbnail`: small and fast to encode and decode, 8-bit with subsampled color
+    pub fn preset(name: &str) -> Result<Self, Error> {
+        let mut config = Self::default();
+        match name {
+            "web" => {
+                config.quality = 75.;
+                config.speed = 6;
+                config.bit_depth = BitDepth::Ten;
+            },
+            "photo-archive" => {
+                config.quality = 95.;
+                config.alpha_quality = Some(100.);
+                config.speed = 2;
+                config.bit_depth = BitDepth::Twelve;
+                config.alpha_color_mode = AlphaColorMode::UnassociatedDirty;
+            },
+            "screenshot" => {
+                config.quality = 92.;
+                config.speed = 5;
+                config.bit_depth = BitDepth::Ten;
+            },
+            "thumbnail" => {
+                config.quality = 60.;
+                config.speed = 8;
+                config.bit_depth = BitDepth::Eight;
+                config.chroma_subsampling = ChromaSubsampling::Cs420;
+            },
+            _ => return Err(Error::InvalidConfig("unknown preset")),
+        }
+        Ok(config)
+    }
+
+    /// The explicit `alpha_quality`, or one derived from `quality`
+    #[must_use]
+    pub fn effective_alpha_quality(&self) -> f32 {
+        self.alpha_quality.unwrap_or_else(|| ((self.quality + 100.) / 2.).min(self.quality + self.quality / 4. + 2.))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        fn number<T: FromStr + PartialOrd>(value: &str, min: T, max: T, msg: &'static str) -> Result<T, Error> {
+            value.parse().ok().filter(|n| (min..=max).contains(n)).ok_or(Error::InvalidConfig(msg))
+        }
+        match key {
+            "q" | "quality" => self.quality = number(value, 1., 100., "quality must be in 1-100 range")?,
+            "aq" | "alpha-quality" => self.alpha_quality = Some(number(value, 1., 100., "alpha quality must be in 1-100 range")?),
+            "s" | "speed" => self.speed = number(value, 1, 10, "speed must be in 1-10 range")?,
+            "depth" => self.bit_depth = value.parse()?,
+            "color" => self.color_model = value.parse()?,
+            "chroma" => self.chroma_subsampling = value.parse()?,
+            "alpha" => self.alpha_color_mode = value.parse()?,
+            "threads" => self.threads = Some(number(value, 1, usize::MAX, "number of threads must be at least 1")?),
+            "grain" => self.film_grain = number(value, 0, 64, "grain must be in 0-64 range")?,
+            "thumbnail" => self.thumbnail = Some(number(value, 1, usize::MAX, "thumbnail size must be at least 1")?),
+            _ => return Err(Error::InvalidConfig("unknown setting")),
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for EncoderConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut config = Self::default();
+        let mut has_settings = false;
+        let items = s.lines().flat_map(|line| line.split('#').next().unwrap_or_default().split(','));
+        for item in items.map(str::trim).filter(|item| !item.is_empty()) {
+            match item.split_once('=') {
+                Some((key, value)) => {
+                    config.set(key.trim(), value.trim())?;
+                    has_settings = true;
+                },
+                None if has_settings => return Err(Error::InvalidConfig("presets must come before other settings")),
+                None => config = Self::preset(item)?,
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for EncoderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "q={}", self.quality)?;
+        if let Some(aq) = self.alpha_quality {
+            write!(f, ",aq={aq}")?;
+        }
+        write!(f, ",s={},depth={},color={},chroma={},alpha={}", self.speed, self.bit_depth, self.color_model, self.chroma_subsampling, self.alpha_color_mode)?;
+        if let Some(threads) = self.threads {
+            write!(f, ",threads={threads}")?;
+        }
+        if self.film_grain > 0 {
+            write!(f, ",grain={}", self.film_grain)?;
+        }
+        if let Some(thumbnail) = self.thumbnail {
+            write!(f, ",thumbnail={thumbnail}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Text names of enum variants, used by [`EncoderConfig`]
+macro_rules! names {
+    ($ty:ty, $msg:literal, $($variant:path => $name:literal),+) => {
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(match self { $($variant => $name),+ })
+            }
+        }
+
+        impl FromStr for $ty {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self, Error> {
+                match s {
+                    $($name => Ok($variant),)+
+                    _ => Err(Error::InvalidConfig($msg)),
+                }
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $ty {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+names!(BitDepth, "depth must be 8, 10, 12 or auto", BitDepth::Eight => "8", BitDepth::Ten => "10", BitDepth::Twelve => "12", BitDepth::Auto => "auto");
+names!(ColorModel, "color must be ycbcr or rgb", ColorModel::YCbCr => "ycbcr", ColorModel::RGB => "rgb");
+names!(ChromaSubsampling, "chroma must be 444, 422 or 420", ChromaSubsampling::Cs444 => "444", ChromaSubsampling::Cs422 => "422", ChromaSubsampling::Cs420 => "420");
+names!(AlphaColorMode, "alpha must be clean, dirty or premultiplied",
+    AlphaColorMode::UnassociatedClean => "clean", AlphaColorMode::UnassociatedDirty => "dirty", AlphaColorMode::Premultiplied => "premultiplied");
@@ -1,5 +1,5 @@
 use imgref::{Img, ImgRef};
-use rgb::{ComponentMap, RGB, RGBA8};
+use rgb::{ComponentMap, RGB, RGBA16, RGBA8};
 
 #[inline]
 fn weighed_pixel(px: RGBA8) -> (u16, RGB<u32>) {
@@ -40,6 +40,23 @@ pub(crate) fn blurred_dirty_alpha(img: ImgRef<RGBA8>) -> Option<Img<Vec<RGBA8>>>
     Some(blur_transparent_pixels(img2.as_ref()))
 }
 
+/// 16-bit version of [`blurred_dirty_alpha`]
+///
+/// The cleaning is computed at 8-bit precision, but only fully-transparent pixels are changed,
+/// so the visible pixels keep all of their precision.
+pub(crate) fn blurred_dirty_alpha16(img: ImgRef<RGBA16>) -> Option<Img<Vec<RGBA16>>> {
+    let img8 = Img::new(img.pixels().map(|px| px.map(|c| (c >> 8) as u8)).collect::<Vec<_>>(), img.width(), img.height());
+    let cleaned = blurred_dirty_alpha(img8.as_ref())?;
+    let out = img.pixels().zip(cleaned.pixels()).map(|(px, clean)| {
+        if px.a == 0 {
+            clean.rgb().map(|c| u16::from(c) * 257).with_alpha(0)
+        } else {
+            px
+        }
+    }).collect();
+    Some(Img::new(out, img.width(), img.height()))
+}
+
 /// copy color from opaque pixels to transparent pixels
 /// (so that when edges get crushed by compression, the distortion will be away from visible edge)
 fn bleed_opaque_color(img: ImgRef<RGBA8>, bg: RGBA8) -> Img<Vec<RGBA8>> {
@@ -1,8 +1,77 @@
 use quick_error::quick_error;
+use rav1e::prelude::ChromaSampling;
+use std::fmt;
 
+/// Which image rav1e was encoding. For [`EncodingErrorDetail`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Stage {
+    /// Color of the image, its tiles, thumbnail or animation frames
+    Color,
+    /// Alpha channel of the image, its tiles, thumbnail or animation frames
+    Alpha,
+    /// From [`Encoder::with_depth_map`](crate::Encoder::with_depth_map)
+    DepthMap,
+    /// From [`Encoder::with_gain_map`](crate::Encoder::with_gain_map)
+    GainMap,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Color => "color",
+            Self::Alpha => "alpha",
+            Self::DepthMap => "depth map",
+            Self::GainMap => "gain map",
+        })
+    }
+}
+
+/// What rav1e reported, and about which frame. For [`Error::EncodingError`]
 #[derive(Debug)]
-#[doc(hidden)]
-pub struct EncodingErrorDetail; // maybe later
+#[non_exhaustive]
+pub struct EncodingErrorDetail {
+    /// The error from rav1e
+    pub cause: Rav1eError,
+    pub stage: Stage,
+    /// Size of the frame, which may be a tile or a thumbnail of the image. 0 if unknown.
+    pub width: usize,
+    pub height: usize,
+    pub bit_depth: u8,
+    pub chroma_sampling: ChromaSampling,
+}
+
+impl EncodingErrorDetail {
+    /// For errors converted from rav1e's types, without context
+    fn new(cause: Rav1eError) -> Self {
+        Self { cause, stage: Stage::Color, width: 0, height: 0, bit_depth: 0, chroma_sampling: ChromaSampling::Cs444 }
+    }
+}
+
+impl fmt::Display for EncodingErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.width == 0 || self.height == 0 {
+            return write!(f, "{}", self.cause);
+        }
+        write!(f, "{} ({} of {}x{}, {}-bit, {:?})", self.cause, self.stage, self.width, self.height, self.bit_depth, self.chroma_sampling)
+    }
+}
+
+quick_error! {
+    /// Error returned by rav1e
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[non_exhaustive]
+    pub enum Rav1eError {
+        InvalidConfig(e: rav1e::InvalidConfig) {
+            display("Invalid configuration: {}", e)
+            source(e)
+        }
+        Status(e: rav1e::EncoderStatus) {
+            display("Encoder status: {}", e)
+            source(e)
+        }
+    }
+}
 
 quick_error! {
     /// Failures enum
@@ -20,10 +89,20 @@ quick_error! {
         Cancelled {
             display("Encoding was cancelled")
         }
+        /// A setting is out of range, or doesn't work with the size of the image. See [`Encoder::validate`](crate::Encoder::validate).
+        InvalidConfig(msg: &'static str) {
+            display("Invalid configuration: {}", msg)
+        }
+        /// The image can't fit in the size set with `with_target_size`. Has the smallest size found.
+        TargetSizeTooSmall(smallest: usize) {
+            display("Can't fit the image in the target size (the smallest file was {} bytes)", smallest)
+        }
+        /// rav1e has failed. The [`EncodingErrorDetail`] says what it was encoding.
         EncodingError(e: EncodingErrorDetail) {
-            display("Encoding error reported by rav1e")
-            from(_e: rav1e::InvalidConfig) -> (EncodingErrorDetail)
-            from(_e: rav1e::EncoderStatus) -> (EncodingErrorDetail)
+            display("Encoding error reported by rav1e: {}", e)
+            source(&e.cause)
+            from(e: rav1e::InvalidConfig) -> (EncodingErrorDetail::new(Rav1eError::InvalidConfig(e)))
+            from(e: rav1e::EncoderStatus) -> (EncodingErrorDetail::new(Rav1eError::Status(e)))
         }
     }
 }
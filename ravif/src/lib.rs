@@ -68,6 +68,7 @@
 //! }
 //! # }
 
+mod animation;
 mod av1encoder;
 
 mod cancel;
@@ -75,22 +76,33 @@ pub use cancel::CancellationToken;
 
 mod error;
 pub use av1encoder::ColorModel;
-pub use error::Error;
+pub use error::{EncodingErrorDetail, Error, Rav1eError, Stage};
 
 #[doc(hidden)]
 #[deprecated = "Renamed to `ColorModel`"]
 pub type ColorSpace = ColorModel;
 
-pub use av1encoder::{AlphaColorMode, BitDepth, EncodedImage, Encoder};
+pub use animation::AnimationEncoder;
+pub use config::EncoderConfig;
+pub use progress::{EncodingPhase, Progress};
+pub use reconstruction::Reconstruction;
+pub use rows::RowSource;
+pub use av1encoder::{AlphaColorMode, AuxiliaryImage, BitDepth, ChromaSubsampling, EncodedImage, Encoder, EncodingStats, GainMapMetadata, Mirror, PlaneQuality};
 #[doc(inline)]
-pub use rav1e::prelude::MatrixCoefficients;
+pub use rav1e::prelude::{ChromaSampling, ChromaticityPoint, ColorPrimaries, ContentLight, MasteringDisplay, MatrixCoefficients, TransferCharacteristics};
 
+mod config;
 mod dirtyalpha;
+mod mux;
+mod progress;
+mod reconstruction;
+mod rows;
+mod ssim;
 
 #[doc(no_inline)]
 pub use imgref::Img;
 #[doc(no_inline)]
-pub use rgb::{RGB8, RGBA8};
+pub use rgb::{GrayA, RGB16, RGB8, RGBA16, RGBA8};
 
 #[cfg(not(feature = "threading"))]
 mod rayoff {
@@ -177,6 +189,731 @@ fn encode8_opaque() {
     assert_eq!(parsed1.primary_item, parsed2.primary_item); // both are the same pixels
 }
 
+#[test]
+fn encode16_keeps_precision() {
+    // all of these pixels are the same in 8-bit
+    let img = imgref::ImgVec::new((0..64).flat_map(|y| (0..64).map(move |x| {
+        RGBA16::new(0x8000 + x * 4, 0x8000 + y * 4, 0x80FF - x * 4, 0xFFFF)
+    })).collect(), 64, 64);
+
+    let enc = Encoder::new()
+        .with_quality(90.0)
+        .with_speed(10)
+        .with_num_threads(Some(1));
+    let EncodedImage { avif_file, alpha_byte_size, .. } = enc.encode_rgba16(img.as_ref()).unwrap();
+    assert_eq!(0, alpha_byte_size);
+    let parsed16 = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
+    assert_eq!(parsed16.primary_item_metadata().unwrap().bit_depth, 10);
+
+    let img8 = imgref::ImgVec::new(img.pixels().map(|px| RGB8::new((px.r >> 8) as u8, (px.g >> 8) as u8, (px.b >> 8) as u8)).collect(), 64, 64);
+    let avif_file = enc.encode_rgb(img8.as_ref()).unwrap().avif_file;
+    let parsed8 = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
+    assert_ne!(parsed8.primary_item, parsed16.primary_item);
+
+    let transparent = img.map_buf(|b| b.into_iter().map(|px| RGBA16 { a: px.r, ..px }).collect::<Vec<_>>());
+    let EncodedImage { avif_file, alpha_byte_size, .. } = enc.encode_rgba16(transparent.as_ref()).unwrap();
+    assert!(alpha_byte_size > 0);
+    let parsed = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
+    assert!(parsed.alpha_item.is_some());
+}
+
+#[test]
+fn encode12() {
+    let img = imgref::ImgVec::new((0..60).flat_map(|y| (0..80).map(move |x| {
+        RGBA16::new(x * 800, y * 1000, 0xFFFF - x * 700, (x + y) * 400)
+    })).collect(), 80, 60);
+
+    let enc = Encoder::new()
+        .with_quality(80.0)
+        .with_speed(10)
+        .with_bit_depth(BitDepth::Twelve)
+        .with_num_threads(Some(1));
+    let EncodedImage { avif_file, alpha_byte_size, .. } = enc.encode_rgba16(img.as_ref()).unwrap();
+    assert!(alpha_byte_size > 0);
+
+    let parsed = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
+    let md = parsed.primary_item_metadata().unwrap();
+    assert_eq!(md.bit_depth, 12);
+    assert_eq!(md.seq_profile, 2);
+    assert_eq!(parsed.alpha_item_metadata().unwrap().unwrap().bit_depth, 12);
+
+    let img8 = imgref::ImgVec::new(img.pixels().map(|px| RGB8::new((px.r >> 8) as u8, (px.g >> 8) as u8, (px.b >> 8) as u8)).collect(), 80, 60);
+    let avif_file = enc.encode_rgb(img8.as_ref()).unwrap().avif_file;
+    let parsed = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
+    assert_eq!(parsed.primary_item_metadata().unwrap().bit_depth, 12);
+}
+
+#[test]
+fn encode_premultiplied_8_and_16_bit() {
+    let img = imgref::ImgVec::new((0..48).flat_map(|y| (0..64).map(move |x| {
+        RGBA8::new((x * 4) as u8, 200 - (y * 3) as u8, 100, 64 + (x + y) as u8)
+    })).collect(), 64, 48);
+    let img16 = imgref::ImgVec::new(img.pixels().map(|px| RGBA16::new(px.r.into(), px.g.into(), px.b.into(), px.a.into()) * 257).collect(), 64, 48);
+
+    let enc = Encoder::new()
+        .with_quality(95.)
+        .with_speed(10)
+        .with_bit_depth(BitDepth::Eight)
+        .with_alpha_color_mode(AlphaColorMode::Premultiplied)
+        .with_reconstruction(true);
+    let rec8 = enc.encode_rgba(img.as_ref()).unwrap().reconstruction.unwrap().to_rgba8();
+    let rec16 = enc.encode_rgba16(img16.as_ref()).unwrap().reconstruction.unwrap().to_rgba8();
+    // compared premultiplied, because unpremultiplying amplifies small differences
+    let premultiplied = |px: RGBA8| [px.r, px.g, px.b].map(|c| u16::from(c) * u16::from(px.a) / 255);
+    for ((a, b), px) in rec8.pixels().zip(rec16.pixels()).zip(img.pixels()) {
+        assert!(a.a.abs_diff(b.a) <= 1 && a.a.abs_diff(px.a) <= 4, "{a:?} {b:?} {px:?}");
+        for ((c8, c16), c) in premultiplied(a).into_iter().zip(premultiplied(b)).zip(premultiplied(px)) {
+            assert!(c8.abs_diff(c16) <= 4, "{a:?} {b:?}");
+            assert!(c8.abs_diff(c) <= 6, "{a:?} {px:?}");
+        }
+    }
+}
+
+#[test]
+fn encode_subsampled() {
+    // odd sizes to test edges
+    let img = imgref::ImgVec::new((0..77).flat_map(|y| (0..51).map(move |x| {
+        RGBA8::new((x * 5) as u8, 255 - (y * 3) as u8, ((x ^ y) * 7) as u8, 255)
+    })).collect(), 51, 77);
+
+    let enc = Encoder::new()
+        .with_quality(80.0)
+        .with_speed(10)
+        .with_num_threads(Some(1));
+
+    for (subsampling, xy, profile) in [(ChromaSubsampling::Cs420, (true, true), 0), (ChromaSubsampling::Cs422, (true, false), 2)] {
+        let avif_file = enc.clone().with_chroma_subsampling(subsampling).encode_rgba(img.as_ref()).unwrap().avif_file;
+        let parsed = avif_parse::read_avif(&mut avif_file.as_slice()).unwrap();
+        let md = parsed.primary_item_metadata().unwrap();
+        assert_eq!(md.chroma_subsampling, xy);
+        assert_eq!(md.seq_profile, profile);
+        assert_eq!(md.max_frame_width.get(), 51);
+        assert_eq!(md.max_frame_height.get(), 77);
+    }
+
+    let res = enc.with_chroma_subsampling(ChromaSubsampling::Cs420)
+        .with_internal_color_model(ColorModel::RGB)
+        .encode_rgba(img.as_ref());
+    assert!(matches!(res, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn encode_target_size() {
+    let img = imgref::ImgVec::new((0..100).flat_map(|y| (0..100).map(move |x| {
+        RGBA8::new((x * y) as u8, (x ^ y) as u8, (x * 3 + y) as u8, if x > 80 { y as u8 } else { 255 })
+    })).collect(), 100, 100);
+
+    let enc = Encoder::new()
+        .with_quality(100.0)
+        .with_speed(10)
+        .with_num_threads(Some(1));
+    let unlimited = enc.encode_rgba(img.as_ref()).unwrap();
+
+    let target = unlimited.avif_file.len() / 3;
+    let res = enc.clone().with_target_size(target).encode_rgba(img.as_ref()).unwrap();
+    assert!(res.avif_file.len() <= target);
+    assert!(res.avif_file.len() > target / 2, "{} vs {target}", res.avif_file.len());
+    assert!(res.alpha_byte_size > 0);
+    avif_parse::read_avif(&mut res.avif_file.as_slice()).unwrap();
+
+    // doesn't get worse than necessary
+    let res = enc.clone().with_target_size(unlimited.avif_file.len()).encode_rgba(img.as_ref()).unwrap();
+    assert_eq!(res.avif_file, unlimited.avif_file);
+
+    let res = enc.with_target_size(100).encode_rgba(img.as_ref());
+    assert!(matches!(res, Err(Error::TargetSizeTooSmall(n)) if n > 100));
+}
+
+#[test]
+fn encode_target_ssim() {
+    let img = imgref::ImgVec::new((0..100).flat_map(|y| (0..100).map(move |x| {
+        RGBA8::new((x * y) as u8, (x ^ y) as u8, (x * 3 + y) as u8, 255)
+    })).collect(), 100, 100);
+
+    let enc = Encoder::new()
+        .with_quality(100.0)
+        .with_speed(10)
+        .with_num_threads(Some(1));
+    assert!(enc.encode_rgba(img.as_ref()).unwrap().ssim.is_none());
+
+    let high = enc.clone().with_target_ssim(0.99).encode_rgba(img.as_ref()).unwrap();
+    let low = enc.clone().with_target_ssim(0.9).encode_rgba(img.as_ref()).unwrap();
+    assert!(high.ssim.unwrap() >= 0.99, "{:?}", high.ssim);
+    assert!(low.ssim.unwrap() >= 0.9, "{:?}", low.ssim);
+    assert!(low.avif_file.len() < high.avif_file.len());
+    avif_parse::read_avif(&mut low.avif_file.as_slice()).unwrap();
+
+    // slower than the trial encodes, so the SSIM is of the final encode
+    let slow = enc.clone().with_speed(6).with_target_ssim(0.95).encode_rgba(img.as_ref()).unwrap();
+    assert!(slow.ssim.unwrap() >= 0.95, "{:?}", slow.ssim);
+    assert!(matches!(enc.clone().try_with_target_ssim(f64::NAN), Err(Error::InvalidConfig(_))));
+    assert!(matches!(enc.clone().try_with_target_ssim(1.5), Err(Error::InvalidConfig(_))));
+
+    // size limit wins
+    let res = enc.with_target_ssim(0.99).with_target_size(low.avif_file.len()).encode_rgba(img.as_ref()).unwrap();
+    assert!(res.avif_file.len() <= low.avif_file.len());
+    assert!(res.ssim.unwrap() < 0.99);
+}
+
+#[test]
+fn encode_hdr() {
+    let img = imgref::ImgVec::new((0..64).flat_map(|y| (0..64).map(move |x| {
+        RGB16::new((x * y * 16) as u16, (x * 1000) as u16, (y * 1000) as u16)
+    })).collect(), 64, 64);
+
+    let d65 = ChromaticityPoint { x: 20493, y: 21561 };
+    let res = Encoder::new()
+        .with_speed(10)
+        .with_num_threads(Some(1))
+        .with_color_primaries(ColorPrimaries::BT2020)
+        .with_transfer_characteristics(TransferCharacteristics::SMPTE2084)
+        .with_mastering_display(MasteringDisplay {
+            primaries: [ChromaticityPoint { x: 46396, y: 19005 }, ChromaticityPoint { x: 11141, y: 52167 }, ChromaticityPoint { x: 8650, y: 3015 }],
+            white_point: d65,
+            max_luminance: 1000 << 8,
+            min_luminance: 1 << 14,
+        })
+        .with_content_light(ContentLight { max_content_light_level: 1000, max_frame_average_light_level: 400 })
+        .encode_rgb16(img.as_ref()).unwrap();
+
+    let file = &res.avif_file;
+    let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle).map(|pos| &file[pos + needle.len()..]);
+    // primaries, transfer, matrix, full range
+    assert_eq!(&find(b"colrnclx").unwrap()[..7], &[0, 9, 0, 16, 0, 6, 0x80]);
+    assert_eq!(&find(b"clli").unwrap()[..4], &[0x03, 0xE8, 0x01, 0x90]);
+    // green primary first, x = 0.17
+    let mdcv = find(b"mdcv").unwrap();
+    assert_eq!(u16::from_be_bytes([mdcv[0], mdcv[1]]), 8500);
+    assert_eq!(u32::from_be_bytes([mdcv[16], mdcv[17], mdcv[18], mdcv[19]]), 1000 * 10000);
+    assert_eq!(u32::from_be_bytes([mdcv[20], mdcv[21], mdcv[22], mdcv[23]]), 10000);
+    avif_parse::read_avif(&mut file.as_slice()).unwrap();
+}
+
+#[test]
+fn encode_icc_profile() {
+    let img = imgref::ImgVec::new(vec![RGB8::new(255, 0, 100); 16 * 8], 16, 8);
+    let icc = b"not really an ICC profile".to_vec();
+    let res = Encoder::new().with_speed(10).with_icc_profile(icc.clone()).encode_rgb(img.as_ref()).unwrap();
+    let file = &res.avif_file;
+    let pos = file.windows(8).position(|w| w == b"colrprof").unwrap();
+    assert_eq!(u32::from_be_bytes(file[pos - 4..pos].try_into().unwrap()) as usize, 4 + 8 + icc.len());
+    assert_eq!(&file[pos + 8..pos + 8 + icc.len()], &icc[..]);
+    // nclx only has the matrix (BT.601) and full range, and leaves the primaries and transfer to the profile
+    assert!(file.windows(15).any(|w| w == b"colrnclx\0\x02\0\x02\0\x06\x80"));
+    avif_parse::read_avif(&mut file.as_slice()).unwrap();
+
+    let file = Encoder::new().with_speed(10).encode_rgb(img.as_ref()).unwrap().avif_file;
+    assert!(file.windows(15).any(|w| w == b"colrnclx\0\x01\0\x0d\0\x06\x80"));
+}
+
+#[test]
+fn encode_exif_xmp() {
+    let img = imgref::ImgVec::new(vec![RGBA8::new(255, 0, 100, 128); 16 * 8], 16, 8);
+    let exif = b"MM\0\x2a\0\0\0\x08\0\0".to_vec();
+    let xmp = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>".to_vec();
+    let res = Encoder::new().with_speed(10)
+        .with_exif([&b"Exif\0\0"[..], &exif].concat())
+        .with_xmp(xmp.clone())
+        .encode_rgba(img.as_ref()).unwrap();
+    let file = &res.avif_file;
+    let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle);
+    assert!(find(b"infe").is_some());
+    assert!(find(b"Exif\0").is_some());
+    assert!(find(b"mime\0application/rdf+xml\0").is_some());
+    assert!(find(b"cdsc").is_some());
+    // zero offset to TIFF header
+    assert!(find(&[&[0, 0, 0, 0][..], &exif].concat()).is_some());
+    assert!(find(&xmp).is_some());
+    avif_parse::read_avif(&mut file.as_slice()).unwrap();
+}
+
+#[test]
+fn encode_gray() {
+    let gray: Vec<u8> = (0..64 * 48).map(|i| ((i % 64) * 3 + (i / 64) * 2) as u8).collect();
+    let enc = Encoder::new().with_quality(70.).with_speed(10);
+
+    let mono = enc.encode_gray(imgref::Img::new(&gray[..], 64, 48)).unwrap();
+    let parsed = avif_parse::read_avif(&mut mono.avif_file.as_slice()).unwrap();
+    assert!(parsed.primary_item_metadata().unwrap().monochrome);
+    assert!(parsed.alpha_item.is_none());
+
+    // detected automatically
+    let rgb: Vec<_> = gray.iter().map(|&g| RGB8::new(g, g, g)).collect();
+    let detected = enc.encode_rgb(imgref::Img::new(&rgb[..], 64, 48)).unwrap();
+    assert_eq!(detected.avif_file, mono.avif_file);
+    let colorful: Vec<_> = gray.iter().map(|&g| RGB8::new(g, g, g ^ 1)).collect();
+    let color = enc.encode_rgb(imgref::Img::new(&colorful[..], 64, 48)).unwrap();
+    assert!(!avif_parse::read_avif(&mut color.avif_file.as_slice()).unwrap().primary_item_metadata().unwrap().monochrome);
+    assert!(color.color_byte_size > mono.color_byte_size);
+
+    let gray_alpha: Vec<_> = gray.iter().map(|&g| GrayA::new(g, g / 2)).collect();
+    let with_alpha = enc.encode_gray_alpha(imgref::Img::new(&gray_alpha[..], 64, 48)).unwrap();
+    let parsed = avif_parse::read_avif(&mut with_alpha.avif_file.as_slice()).unwrap();
+    assert!(parsed.primary_item_metadata().unwrap().monochrome);
+    assert!(parsed.alpha_item.is_some());
+    let rgba: Vec<_> = gray_alpha.iter().map(|px| RGBA8::new(px.v, px.v, px.v, px.a)).collect();
+    assert_eq!(enc.encode_rgba(imgref::Img::new(&rgba[..], 64, 48)).unwrap().avif_file, with_alpha.avif_file);
+}
+
+#[test]
+fn encode_animation() {
+    let frames: Vec<_> = (0..5usize).map(|n| imgref::ImgVec::new((0..48 * 32usize).map(|i| {
+        let (x, y) = (i % 48, i / 48);
+        let inside = x.abs_diff(8 + n * 6) < 6 && y.abs_diff(16) < 6;
+        if inside { RGBA8::new(255, 80, 0, 255) } else { RGBA8::new(0, (x * 5) as u8, (y * 7) as u8, if n == 4 { 128 } else { 255 }) }
+    }).collect(), 48, 32)).collect();
+    let mut anim = AnimationEncoder::new(Encoder::new().with_quality(70.).with_speed(10)).with_repetitions(Some(2));
+    for f in &frames {
+        anim.add_frame(f.as_ref(), std::time::Duration::from_millis(100) / 3).unwrap();
+    }
+    assert_eq!(5, anim.frame_count());
+    let res = anim.finish().unwrap();
+    let file = &res.avif_file;
+    assert_eq!(&file[8..12], b"avis");
+    let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle).unwrap() + needle.len();
+    let u32_at = |pos: usize| u32::from_be_bytes(file[pos..pos + 4].try_into().unwrap());
+
+    // color + alpha tracks, one sample per frame, first is key
+    assert_eq!(2, file.windows(4).filter(|w| w == b"trak").count());
+    let stsz = find(b"stsz");
+    assert_eq!(5, u32_at(stsz + 8));
+    let stss = find(b"stss");
+    assert_eq!(1, u32_at(stss + 4));
+    assert_eq!(1, u32_at(stss + 8));
+    // 33+34+33+33+34 ms
+    let stts = find(b"stts");
+    assert_eq!(5, (0..u32_at(stts + 4) as usize).map(|i| u32_at(stts + 8 + i * 8)).sum::<u32>());
+    assert_eq!(167, (0..u32_at(stts + 4) as usize).map(|i| u32_at(stts + 8 + i * 8) * u32_at(stts + 12 + i * 8)).sum::<u32>());
+    let mvhd = find(b"mvhd");
+    assert_eq!(3 * 167, u64::from_be_bytes(file[mvhd + 24..mvhd + 32].try_into().unwrap()));
+
+    // the first frame is the primary image
+    let first_sample = u32_at(find(b"stco") + 8) as usize..(u32_at(find(b"stco") + 8) + u32_at(stsz + 12)) as usize;
+    let mut still = file.clone();
+    still[8..12].copy_from_slice(b"avif");
+    let parsed = avif_parse::read_avif(&mut still.as_slice()).unwrap();
+    assert_eq!(&file[first_sample], &parsed.primary_item[..]);
+    assert!(parsed.alpha_item.is_some());
+    assert_eq!(res.color_byte_size, (0..5).map(|i| u32_at(stsz + 12 + i * 4) as usize).sum::<usize>());
+
+    let single = AnimationEncoder::new(Encoder::new().with_speed(10));
+    assert!(single.finish().is_err());
+
+    // opaque 16-bit frames, without an alpha track
+    let mut anim = AnimationEncoder::new(Encoder::new().with_speed(10).with_bit_depth(BitDepth::Ten));
+    for f in &frames[..4] {
+        let f16 = imgref::ImgVec::new(f.pixels().map(|px| { let c = |c: u8| u16::from(c) * 257; RGBA16::new(c(px.r), c(px.g), c(px.b), c(px.a)) }).collect(), 48, 32);
+        anim.add_frame16(f16.as_ref(), std::time::Duration::from_millis(50)).unwrap();
+    }
+    let file = anim.finish().unwrap().avif_file;
+    assert_eq!(1, file.windows(4).filter(|w| w == b"trak").count());
+    assert!(avif_parse::read_avif(&mut [&file[..8], b"avif", &file[12..]].concat().as_slice()).unwrap().alpha_item.is_none());
+
+    let mut anim = AnimationEncoder::new(Encoder::new().with_speed(10));
+    anim.add_frame(frames[0].as_ref(), std::time::Duration::from_millis(50)).unwrap();
+    assert!(matches!(anim.add_frame(Img::new(&frames[0].buf()[..32 * 32], 32, 32), std::time::Duration::ZERO), Err(Error::Unsupported(_))));
+    let mut anim = AnimationEncoder::new(Encoder::new().with_speed(10).with_stats(true));
+    assert!(matches!(anim.add_frame(frames[0].as_ref(), std::time::Duration::ZERO), Err(Error::Unsupported(_))));
+    let mut anim = AnimationEncoder::new(Encoder::new().with_speed(10));
+    assert!(matches!(anim.add_frame(Img::new(&frames[0].buf()[..8 * 8], 8, 8), std::time::Duration::ZERO), Err(Error::Unsupported(_))));
+}
+
+#[test]
+fn encode_grid() {
+    // too wide for a single frame
+    let (width, height) = (16400usize, 16usize);
+    let pixels: Vec<_> = (0..width * height).map(|i| RGBA8::new((i % width / 64) as u8, (i / width * 16) as u8, 100, if i % width < 8000 { 255 } else { 200 })).collect();
+    let res = Encoder::new().with_quality(60.).with_speed(10).encode_rgba(Img::new(&pixels[..], width, height)).unwrap();
+    let file = &res.avif_file;
+    let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle).unwrap() + needle.len();
+    let count = |needle: &[u8]| file.windows(needle.len()).filter(|w| *w == needle).count();
+    let u16_at = |pos: usize| u16::from_be_bytes(file[pos..pos + 2].try_into().unwrap());
+
+    // color and alpha grids of 5 × 3280 px hidden tiles each, padded to the minimum height
+    assert_eq!(1, u16_at(find(b"pitm") + 4));
+    assert_eq!(2, count(b"grid"));
+    assert_eq!(10, count(b"av01"));
+    assert_eq!(2, count(b"dimg"));
+    let dimg = find(b"dimg");
+    assert_eq!([1, 5, 2, 3, 4, 5, 6], [0, 2, 4, 6, 8, 10, 12].map(|o| u16_at(dimg + o)));
+    let hidden: Vec<_> = file.windows(4).enumerate().filter(|(_, w)| w == b"infe").map(|(pos, _)| file[pos + 7] & 1 == 1).collect();
+    assert_eq!(hidden, [false, true, true, true, true, true, false, true, true, true, true, true]);
+    // the property is shared by all tiles
+    assert_eq!(1, count(&[b"ispe".as_slice(), &[0; 4], &3280u32.to_be_bytes(), &64u32.to_be_bytes()].concat()));
+
+    // version, flags, rows - 1, columns - 1, output size
+    assert_eq!(2, count(&[0, 0, 0, 4, 0x40, 0x10, 0, 16]));
+    assert!(res.alpha_byte_size > 0);
+}
+
+#[cfg(test)]
+struct TestRows<'a> {
+    img: imgref::ImgRef<'a, RGBA8>,
+    next_row: usize,
+}
+
+#[cfg(test)]
+impl RowSource<RGBA8> for TestRows<'_> {
+    fn width(&self) -> usize { self.img.width() }
+    fn height(&self) -> usize { self.img.height() }
+    fn read_rows(&mut self, rows: &mut [RGBA8]) -> Result<(), Error> {
+        for row in rows.chunks_exact_mut(self.img.width()) {
+            row.copy_from_slice(self.img.rows().nth(self.next_row).ok_or(Error::TooFewPixels)?);
+            self.next_row += 1;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn encode_rows() {
+    let img = imgref::ImgVec::new((0..40 * 50).map(|i| RGBA8::new((i % 40 * 6) as u8, (i / 40 * 5) as u8, 100, if i < 200 { 0 } else { 255 })).collect(), 40, 50);
+    let enc = Encoder::new().with_quality(70.).with_speed(10);
+    let res = enc.clone().with_reconstruction(true).encode_rgba_rows(TestRows { img: img.as_ref(), next_row: 0 }).unwrap();
+    let parsed = avif_parse::read_avif(&mut res.avif_file.as_slice()).unwrap();
+    assert!(parsed.alpha_item.is_some());
+    let rec = res.reconstruction.unwrap().to_rgba8();
+    assert!(rec[(20usize, 2usize)].a < 8 && rec[(20usize, 40usize)].a > 247);
+    let md = parsed.primary_item_metadata().unwrap();
+    assert_eq!((md.max_frame_width.get(), md.max_frame_height.get()), (40, 50));
+
+    // opaque rows don't need alpha
+    let res = enc.encode_rgba_rows(TestRows { img: img.sub_image(0, 10, 40, 40), next_row: 0 }).unwrap();
+    assert_eq!(0, res.alpha_byte_size);
+    // the source runs out of rows
+    assert!(enc.encode_rgba_rows(TestRows { img: img.as_ref(), next_row: 10 }).is_err());
+    assert!(enc.with_thumbnail(8).encode_rgba_rows(TestRows { img: img.as_ref(), next_row: 0 }).is_err());
+}
+
+#[test]
+fn encode_grid_rows() {
+    let (width, height) = (16400usize, 16usize);
+    // only the last tile has alpha
+    let pixels: Vec<_> = (0..width * height).map(|i| RGBA8::new((i % width / 64) as u8, (i / width * 16) as u8, 100, if i % width < 16000 { 255 } else { 200 })).collect();
+    let res = Encoder::new().with_quality(60.).with_speed(10).with_stats(true).with_reconstruction(true).encode_rgba_rows(TestRows { img: Img::new(&pixels[..], width, height), next_row: 0 }).unwrap();
+    let stats = res.stats.as_ref().unwrap();
+    assert_eq!(5, stats.grid_cells);
+    assert!(stats.tiles >= 5);
+    assert!(stats.alpha_plane.is_some());
+    let rec = res.reconstruction.as_ref().unwrap().to_rgba8();
+    assert_eq!((width, height), (rec.width(), rec.height()));
+    assert_eq!(255, rec[(100usize, 8usize)].a);
+    assert!(rec[(width - 1, 8)].a.abs_diff(200) < 8);
+    let file = &res.avif_file;
+    let count = |needle: &[u8]| file.windows(needle.len()).filter(|w| *w == needle).count();
+    // color and alpha grids of 5 tiles, and the 4 opaque alpha tiles are identical
+    assert_eq!(2, count(b"grid"));
+    assert_eq!(10, count(b"av01"));
+    assert!(res.alpha_byte_size > 0);
+}
+
+#[test]
+fn encode_thumbnail() {
+    let img = imgref::ImgVec::new((0..64 * 48).map(|i| RGBA8::new((i % 64 * 4) as u8, (i / 64 * 5) as u8, 50, if i % 64 < 32 { 255 } else { 100 })).collect(), 64, 48);
+    let res = Encoder::new().with_quality(70.).with_speed(10).with_thumbnail(16).encode_rgba(img.as_ref()).unwrap();
+    let file = &res.avif_file;
+    let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle).unwrap() + needle.len();
+    let u16_at = |pos: usize| u16::from_be_bytes(file[pos..pos + 2].try_into().unwrap());
+
+    // color and alpha of the thumbnail follow the main image's items
+    let thmb = find(b"thmb");
+    assert_eq!([3, 1, 1], [0, 2, 4].map(|o| u16_at(thmb + o)));
+    let auxl: Vec<_> = file.windows(4).enumerate().filter(|(_, w)| w == b"auxl").map(|(pos, _)| [4, 6, 8].map(|o| u16_at(pos + o))).collect();
+    assert_eq!(auxl, [[2, 1, 1], [4, 1, 3]]);
+    find(&[b"ispe".as_slice(), &[0; 4], &16u32.to_be_bytes(), &12u32.to_be_bytes()].concat());
+
+    let parsed = avif_parse::read_avif(&mut file.as_slice()).unwrap();
+    assert_eq!(res.color_byte_size, parsed.primary_item.len());
+    assert!(res.thumbnail_byte_size > 0);
+    assert!(res.thumbnail_byte_size < res.color_byte_size + res.alpha_byte_size);
+
+    // it's not bigger than the image
+    let res = Encoder::new().with_speed(10).with_thumbnail(64).encode_rgba(img.as_ref()).unwrap();
+    assert_eq!(0, res.thumbnail_byte_size);
+}
+
+#[test]
+fn encode_depth_and_gain_map() {
+    let img = imgref::ImgVec::new((0..32 * 32).map(|i| RGB8::new((i % 32 * 8) as u8, (i / 32 * 8) as u8, 90)).collect(), 32, 32);
+    let depth = AuxiliaryImage { pixels: imgref::ImgVec::new((0..16 * 16).map(|i| i as u16).collect(), 16, 16), bit_depth: 8, quality: 90. };
+    let gain_map = AuxiliaryImage { pixels: imgref::ImgVec::new((0..16 * 8).map(|i| i as u16 * 8).collect(), 16, 8), bit_depth: 10, quality: 60. };
+    let res = Encoder::new().with_speed(10)
+        .with_depth_map(depth)
+        .with_gain_map(gain_map, GainMapMetadata { alternate_hdr_headroom: 2.5, ..GainMapMetadata::default() })
+        .encode_rgb(img.as_ref()).unwrap();
+    let file = &res.avif_file;
+    let find = |needle: &[u8]| file.windows(needle.len()).position(|w| w == needle).unwrap() + needle.len();
+    let u16_at = |pos: usize| u16::from_be_bytes(file[pos..pos + 2].try_into().unwrap());
+    let u32_at = |pos: usize| u32::from_be_bytes(file[pos..pos + 4].try_into().unwrap());
+
+    // depth map is item 2, gain map 3 (hidden), and tmap 4
+    assert!(file[..32].windows(4).any(|w| w == b"tmap"));
+    find(b"urn:mpeg:mpegB:cicp:systems:auxiliary:depth\0");
+    let auxl = find(b"auxl");
+    assert_eq!([2, 1, 1], [0, 2, 4].map(|o| u16_at(auxl + o)));
+    let dimg = find(b"dimg");
+    assert_eq!([4, 2, 1, 3], [0, 2, 4, 6].map(|o| u16_at(dimg + o)));
+    let altr = find(b"altr");
+    assert_eq!([2, 4, 1], [8, 12, 16].map(|o| u32_at(altr + o)));
+    find(&[b"ispe".as_slice(), &[0; 4], &16u32.to_be_bytes(), &8u32.to_be_bytes()].concat());
+    find(&[b"pixi".as_slice(), &[0; 4], &[1, 10]].concat());
+    // base headroom 0, alternate 2.5
+    let tmap_data = [&[0, 0, 0, 0, 0, 0x40][..], &0u32.to_be_bytes(), &1_000_000u32.to_be_bytes(), &2_500_000u32.to_be_bytes(), &1_000_000u32.to_be_bytes()].concat();
+    find(&tmap_data);
+    // tmap is in BT.2020 PQ, and the base image in sRGB
+    find(b"colrnclx\0\x09\0\x10\0\x06\x80");
+    find(b"colrnclx\0\x01\0\x0d\0\x06\x80");
+
+    let parsed = avif_parse::read_avif(&mut file.as_slice()).unwrap();
+    assert!(parsed.alpha_item.is_none());
+    assert_eq!(res.color_byte_size, parsed.primary_item.len());
+
+    // values must fit in the bit depth
+    let depth = AuxiliaryImage { pixels: imgref::ImgVec::new((0..16 * 16).map(|i| i as u16 * 2).collect(), 16, 16), bit_depth: 8, quality: 90. };
+    assert!(matches!(Encoder::new().with_speed(10).with_depth_map(depth).encode_rgb(img.as_ref()), Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn encode_transforms() {
+    let img = imgref::ImgVec::new((0..32 * 24).map(|i| RGBA8::new((i % 32 * 8) as u8, (i / 32 * 10) as u8, 0, if i < 32 { 0 } else { 255 })).collect(), 32, 24);
+    let enc = Encoder::new().with_speed(10)
+        .with_crop(4, 2, 20, 16)
+        .with_rotation(3)
+        .with_mirror(Mirror::LeftRight)
+        .with_pixel_aspect_ratio(4, 3)
+        .with_thumbnail(16);
+    let res = enc.encode_rgba(img.as_ref()).unwrap();
+    let file = &res.avif_file;
+    let contains = |needle: &[u8]| file.windows(needle.len()).any(|w| w == needle);
+    let clap = |values: [i32; 8]| [b"clap".as_slice(), &values.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<_>>()].concat();
+
+    // centered 20×16 crop, offset by (-2,-2), and the same in the 16×12 thumbnail
+    assert!(contains(&clap([20, 1, 16, 1, -2, 1, -2, 1])));
+    assert!(contains(&clap([10, 1, 8, 1, -1, 1, -1, 1])));
+    assert!(contains(&[b"irot".as_slice(), &[3]].concat()));
+    assert!(contains(&[b"imir".as_slice(), &[1]].concat()));
+    assert!(contains(&[b"pasp".as_slice(), &4u32.to_be_bytes(), &3u32.to_be_bytes()].concat()));
+    avif_parse::read_avif(&mut file.as_slice()).unwrap();
+
+    // 21×15 at (5,3) covers 10.5×7.5 pixels of the thumbnail, which are rounded out to 11×8 at (2,1)
+    let file = enc.clone().with_crop(5, 3, 21, 15).encode_rgba(img.as_ref()).unwrap().avif_file;
+    let contains = |needle: &[u8]| file.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(&clap([21, 1, 15, 1, -1, 2, -3, 2])));
+    assert!(contains(&clap([11, 1, 8, 1, -1, 2, -1, 1])));
+
+    assert!(enc.with_crop(30, 0, 3, 1).encode_rgba(img.as_ref()).is_err());
+}
+
+#[test]
+fn encode_film_grain() {
+    // smooth gradient with a lot of noise
+    let mut seed = 1u32;
+    let img = imgref::ImgVec::new((0..64 * 48).map(|i| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let noise = (seed >> 16) as u8 & 15;
+        RGBA8::new((i % 64 * 3) as u8 + noise, (i / 64 * 4) as u8 + noise, 100 + noise, 255)
+    }).collect(), 64, 48);
+    let enc = Encoder::new().with_quality(80.).with_speed(10).with_num_threads(Some(1));
+    let noisy = enc.clone().encode_rgba(img.as_ref()).unwrap();
+    let grain = enc.with_film_grain(10).encode_rgba(img.as_ref()).unwrap();
+    assert!(grain.color_byte_size < noisy.color_byte_size, "{} < {}", grain.color_byte_size, noisy.color_byte_size);
+    avif_parse::read_avif(&mut grain.avif_file.as_slice()).unwrap();
+}
+
+#[test]
+fn encode_stats() {
+    let img = imgref::ImgVec::new((0..64 * 48).map(|i| RGBA8::new((i % 64 * 4) as u8, (i / 64 * 5) as u8, 50, if i % 64 < 32 { 255 } else { 100 })).collect(), 64, 48);
+    let res = Encoder::new().with_quality(80.).with_speed(10).encode_rgba(img.as_ref()).unwrap();
+    assert!(res.stats.is_none());
+
+    let res = Encoder::new().with_quality(80.).with_speed(10).with_stats(true).encode_rgba(img.as_ref()).unwrap();
+    let stats = res.stats.unwrap();
+    assert_eq!((1, 1), (stats.tiles, stats.grid_cells));
+    assert_eq!(3, stats.color_planes.len());
+    assert!(stats.color_planes.iter().chain(&stats.alpha_plane).all(|p| p.psnr > 30. && p.ssim > 0.9 && p.ssim <= 1.), "{stats:?}");
+    assert!(stats.alpha_quantizer.is_some());
+    assert!(stats.color_time > std::time::Duration::ZERO);
+
+    let gray = imgref::ImgVec::new((0..64 * 48).map(|i| RGBA8::new(i as u8, i as u8, i as u8, 255)).collect(), 64, 48);
+    let stats = Encoder::new().with_speed(10).with_stats(true).encode_rgba(gray.as_ref()).unwrap().stats.unwrap();
+    assert!(stats.alpha_plane.is_none() && stats.alpha_quantizer.is_none());
+
+    // rav1e splits larger frames into tiles for its threads
+    let large = imgref::ImgVec::new((0..512 * 512).map(|i| RGB8::new((i % 512) as u8, (i / 512) as u8, 50)).collect(), 512, 512);
+    let stats = Encoder::new().with_speed(10).with_num_threads(Some(4)).with_stats(true).encode_rgb(large.as_ref()).unwrap().stats.unwrap();
+    assert_eq!(1, stats.grid_cells);
+    assert!(stats.tiles > 1, "{stats:?}");
+
+    // stats are of the encode that has been kept, not of the trials
+    let res = Encoder::new().with_speed(6).with_target_ssim(0.95).with_stats(true).with_reconstruction(true).encode_rgba(img.as_ref()).unwrap();
+    let stats = res.stats.unwrap();
+    assert!(stats.color_quantizer > Encoder::new().with_speed(6).with_stats(true).encode_rgba(img.as_ref()).unwrap().stats.unwrap().color_quantizer);
+    assert!(res.reconstruction.is_some());
+}
+
+#[test]
+fn encode_reconstruction() {
+    let img = imgref::ImgVec::new((0..64 * 48).map(|i| RGBA8::new((i % 64 * 4) as u8, (i / 64 * 5) as u8, 50, if i % 64 < 32 { 255 } else { 100 })).collect(), 64, 48);
+    let max_diff = |rec: &Reconstruction, img: imgref::ImgRef<RGBA8>| {
+        let rec = rec.to_rgba8();
+        assert_eq!((rec.width(), rec.height()), (img.width(), img.height()));
+        rec.pixels().zip(img.pixels()).map(|(a, b)| [a.r.abs_diff(b.r), a.g.abs_diff(b.g), a.b.abs_diff(b.b), a.a.abs_diff(b.a)].into_iter().max().unwrap()).max().unwrap()
+    };
+    let enc = Encoder::new().with_quality(95.).with_speed(10).with_alpha_color_mode(AlphaColorMode::UnassociatedDirty);
+    assert!(enc.encode_rgba(img.as_ref()).unwrap().reconstruction.is_none());
+
+    let res = enc.clone().with_reconstruction(true).encode_rgba(img.as_ref()).unwrap();
+    let rec = res.reconstruction.unwrap();
+    assert!(matches!(rec, Reconstruction::Rgba16(_)));
+    assert!(max_diff(&rec, img.as_ref()) < 12);
+
+    for enc in [enc.clone().with_bit_depth(BitDepth::Eight), enc.clone().with_internal_color_model(ColorModel::RGB)] {
+        let rec = enc.with_reconstruction(true).encode_rgba(img.as_ref()).unwrap().reconstruction.unwrap();
+        assert!(max_diff(&rec, img.as_ref()) < 12);
+    }
+    let rec = enc.clone().with_bit_depth(BitDepth::Eight).with_reconstruction(true).encode_rgba(img.as_ref()).unwrap().reconstruction.unwrap();
+    assert!(matches!(rec, Reconstruction::Rgba8(_)));
+
+    let gray = imgref::ImgVec::new((0..64 * 48).map(|i| RGBA8::new(i as u8, i as u8, i as u8, 255)).collect(), 64, 48);
+    let rec = enc.with_reconstruction(true).encode_rgba(gray.as_ref()).unwrap().reconstruction.unwrap();
+    assert!(max_diff(&rec, gray.as_ref()) < 12);
+}
+
+#[test]
+fn encode_progress() {
+    use std::sync::{Arc, Mutex};
+
+    let img = imgref::ImgVec::new((0..64 * 48).map(|i| RGBA8::new((i % 64 * 4) as u8, (i / 64 * 5) as u8, 50, if i % 64 < 32 { 255 } else { 100 })).collect(), 64, 48);
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let reports2 = reports.clone();
+    Encoder::new().with_speed(10).with_thumbnail(16)
+        .with_progress(move |p| reports2.lock().unwrap().push(p))
+        .encode_rgba(img.as_ref()).unwrap();
+
+    let reports = reports.lock().unwrap();
+    assert!(reports.iter().all(|p| (0. ..=1.).contains(&p.fraction)));
+    let phases = [EncodingPhase::Preprocessing, EncodingPhase::FrameFill, EncodingPhase::ColorEncode, EncodingPhase::AlphaEncode, EncodingPhase::Muxing];
+    for phase in phases {
+        let fractions: Vec<_> = reports.iter().filter(|p| p.phase == phase).map(|p| p.fraction).collect();
+        // the thumbnail isn't reported separately
+        assert_eq!(fractions, [0., 1.], "{phase:?}");
+    }
+    assert_eq!(reports.last(), Some(&Progress { phase: EncodingPhase::Muxing, fraction: 1. }));
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn encode_async() {
+    let img = imgref::ImgVec::new((0..32 * 32).map(|i| RGBA8::new(i as u8, (i / 32) as u8, 50, 255)).collect(), 32, 32);
+    let res = futures_lite::future::block_on(Encoder::new().with_speed(10).encode_rgba_async(img.clone())).unwrap();
+    avif_parse::read_avif(&mut res.avif_file.as_slice()).unwrap();
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let res = futures_lite::future::block_on(Encoder::new().with_cancellation_token(token).encode_rgba_async(img));
+    assert!(matches!(res, Err(Error::Cancelled)));
+}
+
+#[test]
+#[cfg(feature = "async")]
+fn encode_async_cancels_on_drop() {
+    let img = imgref::ImgVec::new((0..256 * 256).map(|i| RGBA8::new((i * 7919 % 251) as u8, (i / 256) as u8, (i % 256) as u8, 255)).collect(), 256, 256);
+    let (tx, rx) = std::sync::mpsc::channel();
+    let tx = std::sync::Mutex::new(tx);
+    let mut future = Box::pin(Encoder::new().with_speed(1)
+        .with_progress(move |p| { let _ = tx.lock().unwrap().send(p); })
+        .encode_rgba_async(img));
+    assert!(futures_lite::future::block_on(futures_lite::future::poll_once(&mut future)).is_none());
+    // the encoder is running
+    while rx.recv().unwrap().phase != EncodingPhase::ColorEncode {}
+    drop(future);
+    // the callback is dropped when the encoding stops
+    assert!(rx.iter().all(|p| p.phase == EncodingPhase::ColorEncode && p.fraction < 1.));
+}
+
+#[test]
+fn invalid_config() {
+    assert!(matches!(Encoder::new().try_with_quality(0.), Err(Error::InvalidConfig(_))));
+    assert!(matches!(Encoder::new().try_with_alpha_quality(101.), Err(Error::InvalidConfig(_))));
+    assert!(matches!(Encoder::new().try_with_speed(11), Err(Error::InvalidConfig(_))));
+    assert!(matches!(Encoder::new().try_with_num_threads(Some(0)), Err(Error::InvalidConfig(_))));
+    assert!(matches!(Encoder::new().try_with_thumbnail(0), Err(Error::InvalidConfig(_))));
+    assert!(matches!(Encoder::new().try_with_film_grain(65), Err(Error::InvalidConfig(_))));
+    let aux = AuxiliaryImage { pixels: imgref::ImgVec::new(vec![0; 16 * 16], 16, 16), bit_depth: 9, quality: 90. };
+    assert!(matches!(Encoder::new().try_with_depth_map(aux.clone()), Err(Error::InvalidConfig(_))));
+    assert!(matches!(Encoder::new().try_with_gain_map(AuxiliaryImage { bit_depth: 8, quality: 0., ..aux.clone() }, GainMapMetadata::default()), Err(Error::InvalidConfig(_))));
+    Encoder::new().try_with_thumbnail(64).and_then(|e| e.try_with_film_grain(64))
+        .and_then(|e| e.try_with_depth_map(AuxiliaryImage { bit_depth: 10, ..aux })).unwrap();
+    let enc = Encoder::new().try_with_quality(50.).and_then(|e| e.try_with_speed(10)).and_then(|e| e.try_with_num_threads(None)).unwrap();
+
+    enc.validate(100, 100).unwrap();
+    assert!(matches!(enc.validate(0, 100), Err(Error::InvalidConfig(_))));
+    assert!(matches!(enc.validate(100, 2_000_000), Err(Error::InvalidConfig(_))));
+    assert!(matches!(enc.clone().with_crop(50, 0, 51, 100).validate(100, 100), Err(Error::InvalidConfig(_))));
+    assert!(matches!(enc.clone().with_internal_color_model(ColorModel::RGB).with_chroma_subsampling(ChromaSubsampling::Cs420).validate(100, 100), Err(Error::InvalidConfig(_))));
+
+    let res = enc.encode_raw_planes_8_bit(0, 0, std::iter::empty(), None::<[u8; 0]>, rav1e::prelude::PixelRange::Full, MatrixCoefficients::BT601);
+    assert!(matches!(res, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn encoder_config_text() {
+    let config: EncoderConfig = "q=80,s=4,depth=10,alpha=dirty".parse().unwrap();
+    assert_eq!((config.quality, config.speed, config.bit_depth, config.alpha_color_mode), (80., 4, BitDepth::Ten, AlphaColorMode::UnassociatedDirty));
+    assert_eq!(config.to_string(), "q=80,s=4,depth=10,color=ycbcr,chroma=444,alpha=dirty");
+    assert_eq!(config, config.to_string().parse().unwrap());
+
+    // settings after a preset override it
+    let config: EncoderConfig = "# comment\nthumbnail\n quality = 70 # lower\ngrain=8,thumbnail=128".parse().unwrap();
+    assert_eq!((config.quality, config.speed, config.film_grain, config.thumbnail), (70., 8, 8, Some(128)));
+    assert_eq!(config, config.to_string().parse().unwrap());
+    for name in EncoderConfig::PRESETS {
+        let preset = EncoderConfig::preset(name).unwrap();
+        assert_eq!(preset, name.parse().unwrap());
+        Encoder::new().try_with_config(&preset).unwrap();
+    }
+
+    for bad in ["q=0", "s=11", "depth=9", "alpha=yes", "x=1", "fast", "threads=0", "q=high", "q=70,web"] {
+        assert!(matches!(bad.parse::<EncoderConfig>(), Err(Error::InvalidConfig(_))), "{bad}");
+    }
+    let config = EncoderConfig { film_grain: 65, ..EncoderConfig::default() };
+    assert!(matches!(Encoder::new().try_with_config(&config), Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn encoder_config_serde() {
+    let config = EncoderConfig::preset("photo-archive").unwrap();
+    let json = serde_json::to_string(&config).unwrap();
+    assert!(json.contains(r#""bit-depth":"12""#), "{json}");
+    assert_eq!(config, serde_json::from_str(&json).unwrap());
+    let config: EncoderConfig = serde_json::from_str(r#"{"quality": 50, "alpha-color-mode": "premultiplied"}"#).unwrap();
+    assert_eq!((config.quality, config.speed, config.alpha_color_mode), (50., 5, AlphaColorMode::Premultiplied));
+    assert!(serde_json::from_str::<EncoderConfig>(r#"{"chroma-subsampling": "411"}"#).is_err());
+}
+
+#[test]
+fn encoding_error_detail() {
+    use std::error::Error as _;
+
+    // rav1e doesn't allow limited range with the identity matrix
+    let planes = [[128u8; 3]; 32 * 16];
+    let Err(err) = Encoder::new().with_speed(10)
+        .encode_raw_planes_8_bit(32, 16, planes, None::<[u8; 0]>, rav1e::prelude::PixelRange::Limited, MatrixCoefficients::Identity) else { panic!() };
+    let Error::EncodingError(detail) = &err else { panic!("{err:?}") };
+    assert_eq!((Stage::Color, 32, 16, 8, ChromaSampling::Cs444), (detail.stage, detail.width, detail.height, detail.bit_depth, detail.chroma_sampling));
+    let msg = err.to_string();
+    assert!(msg.contains("color of 32x16, 8-bit, Cs444"), "{msg}");
+    let cause = err.source().unwrap();
+    assert_eq!(Some(&Rav1eError::InvalidConfig(rav1e::InvalidConfig::ColorConfigurationMismatch)), cause.downcast_ref::<Rav1eError>());
+    assert_eq!(Some(&rav1e::InvalidConfig::ColorConfigurationMismatch), cause.source().unwrap().downcast_ref::<rav1e::InvalidConfig>());
+
+    // without context
+    let err = Error::from(rav1e::EncoderStatus::Failure);
+    assert_eq!("Encoding error reported by rav1e: Encoder status: failure", err.to_string());
+    assert!(matches!(err.source().unwrap().downcast_ref::<Rav1eError>(), Some(Rav1eError::Status(rav1e::EncoderStatus::Failure))));
+}
+
 #[test]
 fn encode8_cleans_alpha() {
     let img = imgref::ImgVec::new((0..200).flat_map(|y| (0..256).map(move |x| {
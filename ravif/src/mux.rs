@@ -0,0 +1,762 @@
+//! HEIF container (`meta` with items, properties and references) for AVIF files,
+//! and `moov` with tracks for animated AVIF (image sequences).
+//!
+//! avif-serialize only knows about a color image with optional alpha, but AVIF files can carry
+//! many more items and properties (ICC profiles, grids, thumbnails, auxiliary images, tracks),
+//! so ravif writes the boxes itself.
+
+/// Image item or metadata item in the `meta` box
+struct Item {
+    id: u16,
+    typ: [u8; 4],
+    /// Required for `mime` items
+    content_type: Option<&'static str>,
+    /// Not meant to be displayed on its own, e.g. a tile of a grid
+    hidden: bool,
+    data: ItemData,
+    /// 1-based indices into `ipco` + essential flag
+    props: Vec<(u16, bool)>,
+}
+
+enum ItemData {
+    Bytes(Vec<u8>),
+    /// Shares the data with a sample of a track, e.g. the first frame of an animation
+    Sample { track: usize, sample: usize },
+}
+
+/// Video track of an image sequence. Samples are stored in one chunk.
+pub(crate) struct Track {
+    pub width: u32,
+    pub height: u32,
+    /// Boxes appended to the `av01` sample entry, e.g. from [`av1c`]
+    pub sample_entry: Vec<Vec<u8>>,
+    /// Id of the track that this track is the alpha channel of
+    pub alpha_of: Option<u32>,
+    pub samples: Vec<Sample>,
+}
+
+pub(crate) struct Sample {
+    pub data: Vec<u8>,
+    /// In units of the timescale
+    pub duration: u32,
+    /// Key frame
+    pub sync: bool,
+}
+
+/// Builder for the AVIF/HEIF file
+pub(crate) struct HeifFile {
+    items: Vec<Item>,
+    /// Serialized property boxes
+    ipco: Vec<Vec<u8>>,
+    /// type, from, to
+    irefs: Vec<([u8; 4], u16, Vec<u16>)>,
+    /// Entity groups: type, item ids
+    groups: Vec<([u8; 4], Vec<u16>)>,
+    /// Added to compatible brands in `ftyp`
+    brands: Vec<[u8; 4]>,
+    tracks: Vec<Track>,
+    /// Units per second of sample durations
+    timescale: u32,
+    /// How many times to play again after the first time, `None` is forever
+    repetitions: Option<u32>,
+}
+
+/// Where the data of items and tracks is in the file
+struct Layout {
+    items: Vec<usize>,
+    tracks: Vec<usize>,
+    /// Uses 64-bit offsets
+    large: bool,
+}
+
+impl HeifFile {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            ipco: Vec::new(),
+            irefs: Vec::new(),
+            groups: Vec::new(),
+            brands: Vec::new(),
+            tracks: Vec::new(),
+            timescale: 1,
+            repetitions: None,
+        }
+    }
+
+    /// Returns id of the new item. The first item added is the primary one.
+    pub fn add_item(&mut self, typ: [u8; 4], data: Vec<u8>) -> u16 {
+        self.push_item(typ, ItemData::Bytes(data))
+    }
+
+    /// Item with the same data as a sample added with [`Self::add_track`]
+    pub fn add_sample_item(&mut self, typ: [u8; 4], track_id: u32, sample: usize) -> u16 {
+        self.push_item(typ, ItemData::Sample { track: track_id as usize - 1, sample })
+    }
+
+    fn push_item(&mut self, typ: [u8; 4], data: ItemData) -> u16 {
+        let id = self.items.len() as u16 + 1;
+        self.items.push(Item { id, typ, content_type: None, hidden: false, data, props: Vec::new() });
+        id
+    }
+
+    /// Returns id of the new track. All tracks share the `timescale`, and loop together.
+    pub fn add_track(&mut self, track: Track) -> u32 {
+        self.tracks.push(track);
+        self.tracks.len() as u32
+    }
+
+    pub fn set_timing(&mut self, timescale: u32, repetitions: Option<u32>) {
+        self.timescale = timescale;
+        self.repetitions = repetitions;
+    }
+
+    /// Metadata item, such as XMP (`application/rdf+xml`)
+    pub fn add_mime_item(&mut self, content_type: &'static str, data: Vec<u8>) -> u16 {
+        let id = self.add_item(*b"mime", data);
+        self.item_mut(id).content_type = Some(content_type);
+        id
+    }
+
+    pub fn set_hidden(&mut self, id: u16) {
+        self.item_mut(id).hidden = true;
+    }
+
+    /// `prop` is a complete box, e.g. from [`ispe`]. Identical properties are shared between items.
+    pub fn add_property(&mut self, id: u16, prop: Vec<u8>, essential: bool) {
+        let index = match self.ipco.iter().position(|p| *p == prop) {
+            Some(pos) => pos,
+            None => {
+                self.ipco.push(prop);
+                self.ipco.len() - 1
+            },
+        };
+        self.item_mut(id).props.push((index as u16 + 1, essential));
+    }
+
+    /// Adds reference of type `typ` (e.g. `auxl`, `cdsc`, `dimg`) from item `from` to item `to`
+    pub fn add_ref(&mut self, typ: [u8; 4], from: u16, to: u16) {
+        match self.irefs.iter_mut().find(|r| r.0 == typ && r.1 == from) {
+            Some(r) => r.2.push(to),
+            None => self.irefs.push((typ, from, vec![to])),
+        }
+    }
+
+    /// Entity group of type `typ`, e.g. `altr` for alternatives in order of preference
+    pub fn add_group(&mut self, typ: [u8; 4], ids: Vec<u16>) {
+        self.groups.push((typ, ids));
+    }
+
+    /// Compatible brand required by a feature used in the file, e.g. `tmap`
+    pub fn add_brand(&mut self, brand: [u8; 4]) {
+        if !self.brands.contains(&brand) {
+            self.brands.push(brand);
+        }
+    }
+
+    fn item_mut(&mut self, id: u16) -> &mut Item {
+        &mut self.items[usize::from(id) - 1]
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box(&mut out, b"ftyp", |out| {
+            if self.tracks.is_empty() {
+                out.extend_from_slice(b"avif");
+                out.extend_from_slice(&0u32.to_be_bytes());
+                out.extend_from_slice(b"mif1miaf");
+            } else {
+                out.extend_from_slice(b"avis");
+                out.extend_from_slice(&0u32.to_be_bytes());
+                out.extend_from_slice(b"avifavismsf1iso8mif1miaf");
+            }
+            for brand in &self.brands {
+                out.extend_from_slice(brand);
+            }
+        });
+
+        // Data of items added later (alpha, metadata) goes first,
+        // so that the primary image is complete when the end of the file arrives.
+        let data_len: usize = self.items.iter().map(|i| match &i.data {
+            ItemData::Bytes(data) => data.len(),
+            ItemData::Sample { .. } => 0,
+        }).sum::<usize>() + self.tracks.iter().flat_map(|t| &t.samples).map(|s| s.data.len()).sum::<usize>();
+        let large = data_len > (u32::MAX / 2) as usize;
+        let meta_start = out.len();
+        self.write_meta(&mut out, &self.layout(0, large));
+        self.write_moov(&mut out, &self.layout(0, large));
+        let mdat_header_len = if data_len + 8 > u32::MAX as usize { 16 } else { 8 };
+        let mdat_data_start = out.len() + mdat_header_len;
+        out.truncate(meta_start);
+        let layout = self.layout(mdat_data_start, large);
+        self.write_meta(&mut out, &layout);
+        self.write_moov(&mut out, &layout);
+
+        if mdat_header_len == 16 {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(b"mdat");
+            out.extend_from_slice(&(data_len as u64 + 16).to_be_bytes());
+        } else {
+            out.extend_from_slice(&(data_len as u32 + 8).to_be_bytes());
+            out.extend_from_slice(b"mdat");
+        }
+        out.reserve(data_len);
+        for item in self.items.iter().rev() {
+            if let ItemData::Bytes(data) = &item.data {
+                out.extend_from_slice(data);
+            }
+        }
+        for sample in self.tracks.iter().flat_map(|t| &t.samples) {
+            out.extend_from_slice(&sample.data);
+        }
+        out
+    }
+
+    /// Offsets of the data in the order written by [`Self::to_vec`]
+    fn layout(&self, mdat_data_start: usize, large: bool) -> Layout {
+        let mut offset = mdat_data_start;
+        let mut items = vec![0; self.items.len()];
+        for (item, item_offset) in self.items.iter().zip(&mut items).rev() {
+            if let ItemData::Bytes(data) = &item.data {
+                *item_offset = offset;
+                offset += data.len();
+            }
+        }
+        let tracks = self.tracks.iter().map(|t| {
+            let start = offset;
+            offset += t.samples.iter().map(|s| s.data.len()).sum::<usize>();
+            start
+        }).collect::<Vec<_>>();
+        for (item, item_offset) in self.items.iter().zip(&mut items) {
+            if let ItemData::Sample { track, sample } = item.data {
+                *item_offset = tracks[track] + self.tracks[track].samples[..sample].iter().map(|s| s.data.len()).sum::<usize>();
+            }
+        }
+        Layout { items, tracks, large }
+    }
+
+    fn item_len(&self, item: &Item) -> usize {
+        match &item.data {
+            ItemData::Bytes(data) => data.len(),
+            ItemData::Sample { track, sample } => self.tracks[*track].samples[*sample].data.len(),
+        }
+    }
+
+    fn write_meta(&self, out: &mut Vec<u8>, layout: &Layout) {
+        let large = layout.large;
+        write_full_box(out, b"meta", 0, 0, |out| {
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                out.extend_from_slice(&0u32.to_be_bytes()); // pre_defined
+                out.extend_from_slice(b"pict");
+                out.extend_from_slice(&[0; 12]); // reserved
+                out.push(0); // name
+            });
+
+            write_full_box(out, b"pitm", 0, 0, |out| {
+                out.extend_from_slice(&1u16.to_be_bytes());
+            });
+
+            write_full_box(out, b"iloc", 0, 0, |out| {
+                let offset_size = if large { 8 } else { 4 };
+                out.push((offset_size << 4) | offset_size); // offset_size, length_size
+                out.push(0); // base_offset_size, reserved
+                out.extend_from_slice(&(self.items.len() as u16).to_be_bytes());
+                for (item, &offset) in self.items.iter().zip(&layout.items) {
+                    out.extend_from_slice(&item.id.to_be_bytes());
+                    out.extend_from_slice(&0u16.to_be_bytes()); // data_reference_index
+                    out.extend_from_slice(&1u16.to_be_bytes()); // extent_count
+                    if large {
+                        out.extend_from_slice(&(offset as u64).to_be_bytes());
+                        out.extend_from_slice(&(self.item_len(item) as u64).to_be_bytes());
+                    } else {
+                        out.extend_from_slice(&(offset as u32).to_be_bytes());
+                        out.extend_from_slice(&(self.item_len(item) as u32).to_be_bytes());
+                    }
+                }
+            });
+
+            write_full_box(out, b"iinf", 0, 0, |out| {
+                out.extend_from_slice(&(self.items.len() as u16).to_be_bytes());
+                for item in &self.items {
+                    write_full_box(out, b"infe", 2, u32::from(item.hidden), |out| {
+                        out.extend_from_slice(&item.id.to_be_bytes());
+                        out.extend_from_slice(&0u16.to_be_bytes()); // protection_index
+                        out.extend_from_slice(&item.typ);
+                        out.push(0); // name
+                        if let Some(content_type) = item.content_type {
+                            out.extend_from_slice(content_type.as_bytes());
+                            out.push(0);
+                        }
+                    });
+                }
+            });
+
+            if !self.irefs.is_empty() {
+                write_full_box(out, b"iref", 0, 0, |out| {
+                    for (typ, from, to) in &self.irefs {
+                        write_box(out, typ, |out| {
+                            out.extend_from_slice(&from.to_be_bytes());
+                            out.extend_from_slice(&(to.len() as u16).to_be_bytes());
+                            for to in to {
+                                out.extend_from_slice(&to.to_be_bytes());
+                            }
+                        });
+                    }
+                });
+            }
+
+            if !self.groups.is_empty() {
+                write_box(out, b"grpl", |out| {
+                    // group ids share the number space with items and tracks
+                    let first_group_id = self.items.len().max(self.tracks.len()) as u32 + 1;
+                    for (group_id, (typ, ids)) in (first_group_id..).zip(&self.groups) {
+                        write_full_box(out, typ, 0, 0, |out| {
+                            out.extend_from_slice(&group_id.to_be_bytes());
+                            out.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+                            for &id in ids {
+                                out.extend_from_slice(&u32::from(id).to_be_bytes());
+                            }
+                        });
+                    }
+                });
+            }
+
+            write_box(out, b"iprp", |out| {
+                write_box(out, b"ipco", |out| {
+                    for prop in &self.ipco {
+                        out.extend_from_slice(prop);
+                    }
+                });
+                // 7-bit indices, unless there are too many properties
+                let wide = self.ipco.len() > 127;
+                write_full_box(out, b"ipma", 0, u32::from(wide), |out| {
+                    let items_with_props = self.items.iter().filter(|i| !i.props.is_empty());
+                    out.extend_from_slice(&(items_with_props.clone().count() as u32).to_be_bytes());
+                    for item in items_with_props {
+                        out.extend_from_slice(&item.id.to_be_bytes());
+                        out.push(item.props.len() as u8);
+                        for &(index, essential) in &item.props {
+                            if wide {
+                                out.extend_from_slice(&(index | (u16::from(essential) << 15)).to_be_bytes());
+                            } else {
+                                out.push(index as u8 | (u8::from(essential) << 7));
+                            }
+                        }
+                    }
+                });
+            });
+        });
+    }
+
+    fn write_moov(&self, out: &mut Vec<u8>, layout: &Layout) {
+        if self.tracks.is_empty() {
+            return;
+        }
+        let media_duration = self.tracks.iter()
+            .map(|t| t.samples.iter().map(|s| u64::from(s.duration)).sum::<u64>())
+            .max().unwrap_or(0);
+        // Looping is done by an edit list that repeats the whole track
+        let duration = match self.repetitions {
+            Some(repetitions) => media_duration * (u64::from(repetitions) + 1),
+            None => u64::MAX,
+        };
+        let unity_matrix = |out: &mut Vec<u8>| {
+            for v in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+                out.extend_from_slice(&v.to_be_bytes());
+            }
+        };
+
+        write_box(out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 1, 0, |out| {
+                out.extend_from_slice(&[0; 16]); // creation and modification time
+                out.extend_from_slice(&self.timescale.to_be_bytes());
+                out.extend_from_slice(&duration.to_be_bytes());
+                out.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate
+                out.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+                out.extend_from_slice(&[0; 10]); // reserved
+                unity_matrix(out);
+                out.extend_from_slice(&[0; 24]); // pre_defined
+                out.extend_from_slice(&(self.tracks.len() as u32 + 1).to_be_bytes()); // next_track_ID
+            });
+
+            for ((track, id), &chunk_offset) in self.tracks.iter().zip(1u32..).zip(&layout.tracks) {
+                let track_duration: u64 = track.samples.iter().map(|s| u64::from(s.duration)).sum();
+                write_box(out, b"trak", |out| {
+                    write_full_box(out, b"tkhd", 1, 0b11, |out| { // enabled, in movie
+                        out.extend_from_slice(&[0; 16]); // creation and modification time
+                        out.extend_from_slice(&id.to_be_bytes());
+                        out.extend_from_slice(&[0; 4]); // reserved
+                        out.extend_from_slice(&duration.to_be_bytes());
+                        out.extend_from_slice(&[0; 16]); // reserved, layer, alternate_group, volume, reserved
+                        unity_matrix(out);
+                        out.extend_from_slice(&(track.width << 16).to_be_bytes());
+                        out.extend_from_slice(&(track.height << 16).to_be_bytes());
+                    });
+                    if let Some(color_id) = track.alpha_of {
+                        write_box(out, b"tref", |out| {
+                            write_box(out, b"auxl", |out| out.extend_from_slice(&color_id.to_be_bytes()));
+                        });
+                    }
+                    write_box(out, b"edts", |out| {
+                        write_full_box(out, b"elst", 1, u32::from(self.repetitions != Some(0)), |out| {
+                            out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+                            out.extend_from_slice(&track_duration.to_be_bytes()); // segment_duration
+                            out.extend_from_slice(&0u64.to_be_bytes()); // media_time
+                            out.extend_from_slice(&1u16.to_be_bytes()); // media_rate_integer
+                            out.extend_from_slice(&0u16.to_be_bytes()); // media_rate_fraction
+                        });
+                    });
+                    write_box(out, b"mdia", |out| {
+                        write_full_box(out, b"mdhd", 1, 0, |out| {
+                            out.extend_from_slice(&[0; 16]); // creation and modification time
+                            out.extend_from_slice(&self.timescale.to_be_bytes());
+                            out.extend_from_slice(&track_duration.to_be_bytes());
+                            out.extend_from_slice(&0x55C4u16.to_be_bytes()); // "und" language
+                            out.extend_from_slice(&0u16.to_be_bytes()); // pre_defined
+                        });
+                        write_full_box(out, b"hdlr", 0, 0, |out| {
+                            out.extend_from_slice(&0u32.to_be_bytes()); // pre_defined
+                            out.extend_from_slice(if track.alpha_of.is_some() { b"auxv" } else { b"pict" });
+                            out.extend_from_slice(&[0; 12]); // reserved
+                            out.push(0); // name
+                        });
+                        write_box(out, b"minf", |out| {
+                            write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                            write_box(out, b"dinf", |out| {
+                                write_full_box(out, b"dref", 0, 0, |out| {
+                                    out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+                                    write_full_box(out, b"url ", 0, 1, |_| {}); // data is in this file
+                                });
+                            });
+                            write_box(out, b"stbl", |out| self.write_stbl(out, track, chunk_offset, layout.large));
+                        });
+                    });
+                });
+            }
+        });
+    }
+
+    fn write_stbl(&self, out: &mut Vec<u8>, track: &Track, chunk_offset: usize, large: bool) {
+        write_full_box(out, b"stsd", 0, 0, |out| {
+            out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+            write_box(out, b"av01", |out| {
+                out.extend_from_slice(&[0; 6]); // reserved
+                out.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+                out.extend_from_slice(&[0; 16]); // pre_defined, reserved
+                out.extend_from_slice(&(track.width as u16).to_be_bytes());
+                out.extend_from_slice(&(track.height as u16).to_be_bytes());
+                out.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+                out.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+                out.extend_from_slice(&0u32.to_be_bytes()); // reserved
+                out.extend_from_slice(&1u16.to_be_bytes()); // frame_count
+                let mut compressor_name = [0; 32];
+                compressor_name[0] = 10;
+                compressor_name[1..11].copy_from_slice(b"AOM Coding");
+                out.extend_from_slice(&compressor_name);
+                out.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+                out.extend_from_slice(&(-1i16).to_be_bytes()); // pre_defined
+                for b in &track.sample_entry {
+                    out.extend_from_slice(b);
+                }
+                // Frames can use any number of references, and intra prediction
+                write_full_box(out, b"ccst", 0, 0, |out| out.extend_from_slice(&0x7C00_0000u32.to_be_bytes()));
+                if track.alpha_of.is_some() {
+                    write_full_box(out, b"auxi", 0, 0, |out| {
+                        out.extend_from_slice(b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0");
+                    });
+                }
+            });
+        });
+
+        // Durations are run-length encoded
+        let mut durations: Vec<(u32, u32)> = Vec::new();
+        for s in &track.samples {
+            match durations.last_mut() {
+                Some((count, duration)) if *duration == s.duration => *count += 1,
+                _ => durations.push((1, s.duration)),
+            }
+        }
+        write_full_box(out, b"stts", 0, 0, |out| {
+            out.extend_from_slice(&(durations.len() as u32).to_be_bytes());
+            for (count, duration) in durations {
+                out.extend_from_slice(&count.to_be_bytes());
+                out.extend_from_slice(&duration.to_be_bytes());
+            }
+        });
+        // No stss means that every sample is a sync sample
+        if track.samples.iter().any(|s| !s.sync) {
+            write_full_box(out, b"stss", 0, 0, |out| {
+                let sync = track.samples.iter().zip(1u32..).filter(|(s, _)| s.sync);
+                out.extend_from_slice(&(sync.clone().count() as u32).to_be_bytes());
+                for (_, n) in sync {
+                    out.extend_from_slice(&n.to_be_bytes());
+                }
+            });
+        }
+        write_full_box(out, b"stsc", 0, 0, |out| {
+            out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+            out.extend_from_slice(&1u32.to_be_bytes()); // first_chunk
+            out.extend_from_slice(&(track.samples.len() as u32).to_be_bytes()); // samples_per_chunk
+            out.extend_from_slice(&1u32.to_be_bytes()); // sample_description_index
+        });
+        write_full_box(out, b"stsz", 0, 0, |out| {
+            out.extend_from_slice(&0u32.to_be_bytes()); // sizes vary
+            out.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+            for s in &track.samples {
+                out.extend_from_slice(&(s.data.len() as u32).to_be_bytes());
+            }
+        });
+        if large {
+            write_full_box(out, b"co64", 0, 0, |out| {
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(&(chunk_offset as u64).to_be_bytes());
+            });
+        } else {
+            write_full_box(out, b"stco", 0, 0, |out| {
+                out.extend_from_slice(&1u32.to_be_bytes());
+                out.extend_from_slice(&(chunk_offset as u32).to_be_bytes());
+            });
+        }
+    }
+}
+
+/// Data of a `grid` item, which is made of `rows` × `columns` tiles referenced with `dimg`.
+/// Tiles on the right and bottom edges are cropped to the output size.
+/// There can be at most 256 rows and columns.
+pub(crate) fn image_grid(rows: u16, columns: u16, output_width: u32, output_height: u32) -> Vec<u8> {
+    debug_assert!((1..=256).contains(&rows) && (1..=256).contains(&columns));
+    let large = output_width > 0xFFFF || output_height > 0xFFFF;
+    let mut out = vec![0, u8::from(large), (rows - 1) as u8, (columns - 1) as u8];
+    if large {
+        out.extend_from_slice(&output_width.to_be_bytes());
+        out.extend_from_slice(&output_height.to_be_bytes());
+    } else {
+        out.extend_from_slice(&(output_width as u16).to_be_bytes());
+        out.extend_from_slice(&(output_height as u16).to_be_bytes());
+    }
+    out
+}
+
+/// Data of a `tmap` item (ISO 21496-1 gain map metadata) for a single-channel gain map, using the base image's color space.
+/// Values are fractions (numerator, denominator). The headrooms, min and max are log2.
+pub(crate) fn tone_map(
+    base_hdr_headroom: (u32, u32), alternate_hdr_headroom: (u32, u32),
+    gain_map_min: (i32, u32), gain_map_max: (i32, u32), gamma: (u32, u32), base_offset: (i32, u32), alternate_offset: (i32, u32),
+) -> Vec<u8> {
+    let mut out = vec![0]; // version
+    out.extend_from_slice(&0u16.to_be_bytes()); // minimum_version
+    out.extend_from_slice(&0u16.to_be_bytes()); // writer_version
+    out.push(1 << 6); // use_base_colour_space, not multichannel
+    for (n, d) in [base_hdr_headroom, alternate_hdr_headroom, (gain_map_min.0 as u32, gain_map_min.1), (gain_map_max.0 as u32, gain_map_max.1),
+        gamma, (base_offset.0 as u32, base_offset.1), (alternate_offset.0 as u32, alternate_offset.1)] {
+        out.extend_from_slice(&n.to_be_bytes());
+        out.extend_from_slice(&d.to_be_bytes());
+    }
+    out
+}
+
+/// Image size
+pub(crate) fn ispe(width: u32, height: u32) -> Vec<u8> {
+    full_box(b"ispe", 0, 0, |out| {
+        out.extend_from_slice(&width.to_be_bytes());
+        out.extend_from_slice(&height.to_be_bytes());
+    })
+}
+
+/// Bit depth of each channel
+pub(crate) fn pixi(channels: u8, depth: u8) -> Vec<u8> {
+    full_box(b"pixi", 0, 0, |out| {
+        out.push(channels);
+        out.extend((0..channels).map(|_| depth));
+    })
+}
+
+/// AV1 codec configuration. Must match the sequence header.
+pub(crate) struct Av1C {
+    pub seq_profile: u8,
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub monochrome: bool,
+    pub chroma_subsampling: (bool, bool),
+}
+
+pub(crate) fn av1c(c: &Av1C) -> Vec<u8> {
+    make_box(b"av1C", |out| {
+        out.push(0x81); // marker, version 1
+        out.push((c.seq_profile << 5) | 31); // seq_level_idx_0 = 31 (no level restrictions)
+        out.push((u8::from(c.high_bitdepth) << 6)
+            | (u8::from(c.twelve_bit) << 5)
+            | (u8::from(c.monochrome) << 4)
+            | (u8::from(c.chroma_subsampling.0) << 3)
+            | (u8::from(c.chroma_subsampling.1) << 2));
+        out.push(0); // no initial_presentation_delay
+    })
+}
+
+/// CICP color description, same as in AV1 sequence header
+pub(crate) fn colr_nclx(color_primaries: u16, transfer_characteristics: u16, matrix_coefficients: u16, full_range: bool) -> Vec<u8> {
+    make_box(b"colr", |out| {
+        out.extend_from_slice(b"nclx");
+        out.extend_from_slice(&color_primaries.to_be_bytes());
+        out.extend_from_slice(&transfer_characteristics.to_be_bytes());
+        out.extend_from_slice(&matrix_coefficients.to_be_bytes());
+        out.push(u8::from(full_range) << 7);
+    })
+}
+
+/// ICC color profile
+pub(crate) fn colr_icc(icc_profile: &[u8]) -> Vec<u8> {
+    make_box(b"colr", |out| {
+        out.extend_from_slice(b"prof");
+        out.extend_from_slice(icc_profile);
+    })
+}
+
+/// Content light level in cd/m²
+pub(crate) fn clli(max_content_light_level: u16, max_pic_average_light_level: u16) -> Vec<u8> {
+    make_box(b"clli", |out| {
+        out.extend_from_slice(&max_content_light_level.to_be_bytes());
+        out.extend_from_slice(&max_pic_average_light_level.to_be_bytes());
+    })
+}
+
+/// Mastering display in GBR order, in units of 0.00002 for chromaticity and 0.0001 cd/m² for luminance
+pub(crate) fn mdcv(primaries: [(u16, u16); 3], white_point: (u16, u16), max_luminance: u32, min_luminance: u32) -> Vec<u8> {
+    make_box(b"mdcv", |out| {
+        for (x, y) in primaries.into_iter().chain([white_point]) {
+            out.extend_from_slice(&x.to_be_bytes());
+            out.extend_from_slice(&y.to_be_bytes());
+        }
+        out.extend_from_slice(&max_luminance.to_be_bytes());
+        out.extend_from_slice(&min_luminance.to_be_bytes());
+    })
+}
+
+/// Pixel aspect ratio as relative width and height of a pixel
+pub(crate) fn pasp(h_spacing: u32, v_spacing: u32) -> Vec<u8> {
+    make_box(b"pasp", |out| {
+        out.extend_from_slice(&h_spacing.to_be_bytes());
+        out.extend_from_slice(&v_spacing.to_be_bytes());
+    })
+}
+
+/// Clean aperture (crop). Values are fractions (numerator, denominator), and offsets are from the center of the image.
+pub(crate) fn clap(width: (u32, u32), height: (u32, u32), horiz_off: (i32, u32), vert_off: (i32, u32)) -> Vec<u8> {
+    make_box(b"clap", |out| {
+        for (n, d) in [width, height, (horiz_off.0 as u32, horiz_off.1), (vert_off.0 as u32, vert_off.1)] {
+            out.extend_from_slice(&n.to_be_bytes());
+            out.extend_from_slice(&d.to_be_bytes());
+        }
+    })
+}
+
+/// Rotation anti-clockwise by `angle` × 90°
+pub(crate) fn irot(angle: u8) -> Vec<u8> {
+    make_box(b"irot", |out| out.push(angle & 3))
+}
+
+/// Mirroring. Axis 0 exchanges the top and bottom, 1 exchanges left and right.
+pub(crate) fn imir(axis: u8) -> Vec<u8> {
+    make_box(b"imir", |out| out.push(axis & 1))
+}
+
+/// Type of auxiliary image, e.g. alpha
+pub(crate) fn auxc(urn: &str) -> Vec<u8> {
+    full_box(b"auxC", 0, 0, |out| {
+        out.extend_from_slice(urn.as_bytes());
+        out.push(0);
+    })
+}
+
+fn make_box(typ: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_box(&mut out, typ, body);
+    out
+}
+
+fn full_box(typ: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_full_box(&mut out, typ, version, flags, body);
+    out
+}
+
+fn write_box(out: &mut Vec<u8>, typ: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(typ);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(out: &mut Vec<u8>, typ: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, typ, |out| {
+        out.extend_from_slice(&((u32::from(version) << 24) | flags).to_be_bytes());
+        body(out);
+    });
+}
+
+#[test]
+fn items_share_properties() {
+    let mut file = HeifFile::new();
+    let color = file.add_item(*b"av01", vec![1; 10]);
+    let alpha = file.add_item(*b"av01", vec![2; 5]);
+    for id in [color, alpha] {
+        file.add_property(id, ispe(16, 8), false);
+    }
+    file.add_property(color, colr_icc(b"profile"), false);
+    file.add_property(alpha, auxc("urn:mpeg:mpegB:cicp:systems:auxiliary:alpha"), true);
+    file.add_ref(*b"auxl", alpha, color);
+    let out = file.to_vec();
+    let count = |needle: &[u8]| out.windows(needle.len()).filter(|w| *w == needle).count();
+    assert_eq!(1, count(b"ispe"));
+    assert_eq!(1, count(b"colrprof"));
+
+    let parsed = avif_parse::read_avif(&mut out.as_slice()).unwrap();
+    assert_eq!(parsed.primary_item.as_slice(), [1; 10]);
+    assert_eq!(parsed.alpha_item.as_deref(), Some(&[2; 5][..]));
+    // items added later are stored first, and the primary item is last
+    assert!(out.ends_with(&[[2; 5].as_slice(), &[1; 10]].concat()));
+}
+
+#[test]
+fn box_layout() {
+    assert_eq!(colr_icc(b"abc"), b"\0\0\0\x0fcolrprofabc");
+    assert_eq!(ispe(0x1234, 1), b"\0\0\0\x14ispe\0\0\0\0\0\0\x12\x34\0\0\0\x01");
+    assert_eq!(pixi(3, 10), b"\0\0\0\x10pixi\0\0\0\0\x03\x0a\x0a\x0a");
+    assert_eq!(image_grid(2, 3, 100, 50), [0, 0, 1, 2, 0, 100, 0, 50]);
+    assert_eq!(image_grid(1, 1, 0x10000, 50), [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 50]);
+
+    // more than 127 properties need 15-bit indices in ipma
+    let mut file = HeifFile::new();
+    let id = file.add_item(*b"av01", vec![0; 4]);
+    for n in 0..130 {
+        file.add_property(id, pasp(n, 1), false);
+    }
+    let out = file.to_vec();
+    let ipma = out.windows(4).position(|w| w == b"ipma").unwrap();
+    assert_eq!(out[ipma + 4..ipma + 8], [0, 0, 0, 1]);
+    assert_eq!(out[ipma + 14..ipma + 17], [130, 0, 1]);
+}
+
+#[test]
+fn track_samples() {
+    let mut file = HeifFile::new();
+    file.set_timing(1000, Some(0));
+    let samples = [(10, true), (10, false), (20, true)].map(|(duration, sync)| Sample { data: vec![duration as u8; 3], duration, sync });
+    let track = file.add_track(Track { width: 16, height: 8, sample_entry: Vec::new(), alpha_of: None, samples: samples.into() });
+    file.add_sample_item(*b"av01", track, 0);
+    let out = file.to_vec();
+    let find = |needle: &[u8]| out.windows(needle.len()).position(|w| w == needle).unwrap() + needle.len();
+    assert!(out[8..12] == *b"avis");
+    // durations are run-length encoded
+    let stts = find(b"stts");
+    assert_eq!(out[stts..stts + 24], [0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 10, 0, 0, 0, 1, 0, 0, 0, 20]);
+    let stss = find(b"stss");
+    assert_eq!(out[stss..stss + 16], [0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 3]);
+    // the item shares the data of the first sample
+    let iloc = find(b"iloc");
+    let offset = u32::from_be_bytes(out[iloc + 14..iloc + 18].try_into().unwrap()) as usize;
+    let len = u32::from_be_bytes(out[iloc + 18..iloc + 22].try_into().unwrap()) as usize;
+    assert_eq!(out[offset..offset + len], [10; 3]);
+    assert!(out.ends_with(&[10, 10, 10, 10, 10, 10, 20, 20, 20]));
+}
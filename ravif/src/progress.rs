@@ -0,0 +1,38 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Stage of encoding, reported to [`Encoder::with_progress`](crate::Encoder::with_progress)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum EncodingPhase {
+    /// Checking the pixels, and cleaning or premultiplying alpha
+    Preprocessing,
+    /// Converting pixels into the encoder's frame
+    FrameFill,
+    /// AV1 compression of the color channels. This is the slowest part.
+    ColorEncode,
+    /// AV1 compression of the alpha channel, which runs at the same time as the color
+    AlphaEncode,
+    /// Writing the AVIF file
+    Muxing,
+}
+
+/// Reported to [`Encoder::with_progress`](crate::Encoder::with_progress)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Progress {
+    pub phase: EncodingPhase,
+    /// How much of the phase is done, from 0 to 1.
+    ///
+    /// Every phase starts with 0 and ends with 1. Values in between are reported only where the encoder can tell,
+    /// e.g. for tiles of huge images, or frames of animations.
+    pub fraction: f32,
+}
+
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(pub Arc<dyn Fn(Progress) + Send + Sync>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
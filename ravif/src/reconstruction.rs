@@ -0,0 +1,142 @@
+use imgref::{ImgRef, ImgVec};
+use rav1e::prelude::{ChromaSampling, Frame, MatrixCoefficients, Pixel, PixelRange};
+use rgb::{ComponentMap, RGBA16, RGBA8};
+
+/// Pixels decoded by the encoder itself, from [`Encoder::with_reconstruction`](crate::Encoder::with_reconstruction)
+///
+/// They're non-premultiplied RGBA, like the input, and show what AVIF decoders are going to display
+/// (except chroma upsampling, which is done by repeating pixels, and may be smoother in decoders,
+/// and film grain, which decoders synthesize on top of these pixels).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Reconstruction {
+    /// Image encoded with 8-bit depth
+    Rgba8(ImgVec<RGBA8>),
+    /// Image encoded with 10- or 12-bit depth, scaled to the full 16-bit range
+    Rgba16(ImgVec<RGBA16>),
+}
+
+impl Reconstruction {
+    /// `pixels` are in the range of `bit_depth`
+    pub(crate) fn new(pixels: ImgVec<RGBA16>, bit_depth: u8) -> Self {
+        let (width, height) = (pixels.width(), pixels.height());
+        if bit_depth == 8 {
+            Self::Rgba8(ImgVec::new(pixels.into_buf().into_iter().map(|px| px.map(|c| c as u8)).collect(), width, height))
+        } else {
+            let max = (1u32 << bit_depth) - 1;
+            let scale = |c: u16| ((u32::from(c) * 0xFFFF + max / 2) / max) as u16;
+            Self::Rgba16(ImgVec::new(pixels.into_buf().into_iter().map(|px| px.map(scale)).collect(), width, height))
+        }
+    }
+
+    /// Rounds 16-bit pixels to 8 bits if necessary
+    #[must_use]
+    pub fn to_rgba8(&self) -> ImgVec<RGBA8> {
+        match self {
+            Self::Rgba8(img) => img.clone(),
+            Self::Rgba16(img) => {
+                let pixels = img.pixels().map(|px| px.map(|c| ((u32::from(c) * 255 + 0x7FFF) / 0xFFFF) as u8)).collect();
+                ImgVec::new(pixels, img.width(), img.height())
+            },
+        }
+    }
+
+    #[must_use]
+    pub fn width(&self) -> usize {
+        match self {
+            Self::Rgba8(img) => img.width(),
+            Self::Rgba16(img) => img.width(),
+        }
+    }
+
+    #[must_use]
+    pub fn height(&self) -> usize {
+        match self {
+            Self::Rgba8(img) => img.height(),
+            Self::Rgba16(img) => img.height(),
+        }
+    }
+}
+
+/// Converts `width`x`height` of the decoded frames back to RGBA, in the range of `bit_depth`.
+///
+/// `None` if the matrix coefficients aren't supported.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn frame_to_rgba<P: Pixel>(
+    color: &Frame<P>, alpha: Option<&Frame<P>>, width: usize, height: usize, bit_depth: u8,
+    pixel_range: PixelRange, matrix_coefficients: MatrixCoefficients, chroma_sampling: ChromaSampling, premultiplied: bool,
+) -> Option<ImgVec<RGBA16>> {
+    let max = ((1u32 << bit_depth) - 1) as f32;
+    // offsets and ranges of luma and chroma
+    let (y_offset, y_range, c_offset, c_range) = match pixel_range {
+        PixelRange::Full => (0., max, (max * 0.5).round(), max),
+        PixelRange::Limited => {
+            let unit = (1u32 << (bit_depth - 8)) as f32;
+            (16. * unit, 219. * unit, 128. * unit, 224. * unit)
+        },
+    };
+    // Kr and Kb
+    let matrix = match matrix_coefficients {
+        MatrixCoefficients::Identity => None,
+        MatrixCoefficients::BT709 => Some((0.2126, 0.0722)),
+        MatrixCoefficients::Unspecified | MatrixCoefficients::BT470BG | MatrixCoefficients::BT601 => Some((0.299, 0.114)),
+        MatrixCoefficients::FCC => Some((0.30, 0.11)),
+        MatrixCoefficients::SMPTE240 => Some((0.212, 0.087)),
+        MatrixCoefficients::BT2020NCL => Some((0.2627, 0.0593)),
+        _ => return None,
+    };
+    let to_rgb = |y: f32, u: f32, v: f32| -> [f32; 3] {
+        match matrix {
+            // planes are G, B, R
+            None => [v, y, u].map(|c| (c - y_offset) / y_range),
+            Some((kr, kb)) => {
+                let y = (y - y_offset) / y_range;
+                let (cb, cr) = ((u - c_offset) / c_range, (v - c_offset) / c_range);
+                let r = 2. * (1. - kr) * cr + y;
+                let b = 2. * (1. - kb) * cb + y;
+                let g = (y - kr * r - kb * b) / (1. - kr - kb);
+                [r, g, b]
+            },
+        }
+    };
+    let to_u16 = |c: f32| (c.clamp(0., 1.) * max).round() as u16;
+
+    let rows = |frame: &Frame<P>, plane: usize, height: usize| -> Vec<Vec<u32>> {
+        frame.planes[plane].rows_iter().take(height).map(|row| row.iter().map(|&px| px.into()).collect()).collect()
+    };
+    let luma = rows(color, 0, height);
+    let decimation = chroma_sampling.get_decimation();
+    let chroma = decimation.map(|(_, ydec)| [1, 2].map(|plane| rows(color, plane, (height + ydec) >> ydec)));
+    let alpha = alpha.map(|alpha| rows(alpha, 0, height));
+    let (xdec, ydec) = decimation.unwrap_or((0, 0));
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let luma = luma[y][x] as f32;
+            let [r, g, b] = match &chroma {
+                Some([u, v]) => to_rgb(luma, u[y >> ydec][x >> xdec] as f32, v[y >> ydec][x >> xdec] as f32),
+                None => [(luma - y_offset) / y_range; 3],
+            };
+            let mut px = RGBA16::new(to_u16(r), to_u16(g), to_u16(b), max as u16);
+            if let Some(alpha) = &alpha {
+                px.a = alpha[y][x] as u16;
+                if premultiplied {
+                    let unpremultiply = |c: u16| if px.a == 0 { 0 } else { (u32::from(c) * max as u32 / u32::from(px.a)).min(max as u32) as u16 };
+                    px = RGBA16::new(unpremultiply(px.r), unpremultiply(px.g), unpremultiply(px.b), px.a);
+                }
+            }
+            pixels.push(px);
+        }
+    }
+    Some(ImgVec::new(pixels, width, height))
+}
+
+/// Copies the part of the tile that fits in `dst`
+pub(crate) fn paste(dst: &mut ImgVec<RGBA16>, tile: ImgRef<'_, RGBA16>, left: usize, top: usize) {
+    let width = tile.width().min(dst.width() - left);
+    let height = tile.height().min(dst.height() - top);
+    for (dst_row, row) in dst.sub_image_mut(left, top, width, height).rows_mut().zip(tile.rows()) {
+        dst_row.copy_from_slice(&row[..width]);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::error::Error;
+
+/// Image that is read incrementally, from top to bottom, so that the whole image doesn't have to be in memory at once.
+///
+/// Used by [`Encoder::encode_rgba_rows`](crate::Encoder::encode_rgba_rows) and [`Encoder::encode_rgba16_rows`](crate::Encoder::encode_rgba16_rows).
+///
+/// ```rust
+/// use ravif::*;
+/// use rgb::ComponentSlice;
+/// use std::io::Read;
+///
+/// /// Raw RGBA pixels from a file or a pipe
+/// struct RawReader<R> { reader: R, width: usize, height: usize }
+///
+/// impl<R: Read> RowSource<RGBA8> for RawReader<R> {
+///     fn width(&self) -> usize { self.width }
+///     fn height(&self) -> usize { self.height }
+///     fn read_rows(&mut self, rows: &mut [RGBA8]) -> Result<(), Error> {
+///         self.reader.read_exact(rows.as_mut_slice()).map_err(|_| Error::TooFewPixels)
+///     }
+/// }
+/// ```
+pub trait RowSource<T> {
+    /// Width of the image in pixels
+    fn width(&self) -> usize;
+    /// Height of the image in pixels
+    fn height(&self) -> usize;
+    /// Fills `rows` with the next rows of the image (non-premultiplied, alpha last).
+    ///
+    /// `rows.len()` is always a multiple of the width. Every row is read exactly once.
+    fn read_rows(&mut self, rows: &mut [T]) -> Result<(), Error>;
+}
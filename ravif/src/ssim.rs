@@ -0,0 +1,93 @@
+use rav1e::prelude::{ChromaSampling, Frame, Pixel, Plane};
+
+/// Size of the square window for local statistics
+const WINDOW: usize = 8;
+/// Windows overlap by half
+const STEP: usize = WINDOW / 2;
+
+/// SSIM of all planes of the frame, with luma weighed 6:1:1 against chroma
+pub(crate) fn frame_ssim<P: Pixel>(source: &Frame<P>, rec: &Frame<P>, width: usize, height: usize, bit_depth: u8, chroma_sampling: ChromaSampling) -> f64 {
+    let y = plane_ssim(&source.planes[0], &rec.planes[0], width, height, bit_depth);
+    let Some((xdec, ydec)) = chroma_sampling.get_decimation() else {
+        return y;
+    };
+    let (cw, ch) = ((width + xdec) >> xdec, (height + ydec) >> ydec);
+    let u = plane_ssim(&source.planes[1], &rec.planes[1], cw, ch, bit_depth);
+    let v = plane_ssim(&source.planes[2], &rec.planes[2], cw, ch, bit_depth);
+    y.mul_add(6. / 8., (u + v) / 8.)
+}
+
+/// Mean SSIM of `width`x`height` area of the planes. Empty areas are identical.
+pub(crate) fn plane_ssim<P: Pixel>(a: &Plane<P>, b: &Plane<P>, width: usize, height: usize, bit_depth: u8) -> f64 {
+    if width == 0 || height == 0 {
+        return 1.;
+    }
+    let max = f64::from((1u32 << bit_depth) - 1);
+    let c1 = (0.01 * max).powi(2);
+    let c2 = (0.03 * max).powi(2);
+
+    let a: Vec<&[P]> = a.rows_iter().take(height).map(|row| &row[..width]).collect();
+    let b: Vec<&[P]> = b.rows_iter().take(height).map(|row| &row[..width]).collect();
+
+    let (win_w, win_h) = (WINDOW.min(width), WINDOW.min(height));
+    let mut sum = 0.;
+    let mut windows = 0;
+    for y in positions(height, win_h) {
+        for x in positions(width, win_w) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0u64, 0u64, 0u64, 0u64, 0u64);
+            for (ra, rb) in a[y..y + win_h].iter().zip(&b[y..y + win_h]) {
+                for (&pa, &pb) in ra[x..x + win_w].iter().zip(&rb[x..x + win_w]) {
+                    let (pa, pb) = (u64::from(Into::<u32>::into(pa)), u64::from(Into::<u32>::into(pb)));
+                    sa += pa;
+                    sb += pb;
+                    saa += pa * pa;
+                    sbb += pb * pb;
+                    sab += pa * pb;
+                }
+            }
+            let n = (win_w * win_h) as f64;
+            let (ma, mb) = (sa as f64 / n, sb as f64 / n);
+            let va = (saa as f64 / n - ma * ma).max(0.);
+            let vb = (sbb as f64 / n - mb * mb).max(0.);
+            let cov = sab as f64 / n - ma * mb;
+            sum += ((2. * ma).mul_add(mb, c1) * 2f64.mul_add(cov, c2)) / (ma.mul_add(ma, mb * mb + c1) * (va + vb + c2));
+            windows += 1;
+        }
+    }
+    sum / f64::from(windows)
+}
+
+/// Starts of windows `STEP` apart, and of the last one that ends at the edge, so that the whole `len` is covered
+fn positions(len: usize, win: usize) -> impl Iterator<Item = usize> {
+    let last = len - win;
+    (0..last).step_by(STEP).chain(std::iter::once(last))
+}
+
+/// Sum of squared differences of `width`x`height` area of the planes, for PSNR
+pub(crate) fn plane_sse<P: Pixel>(a: &Plane<P>, b: &Plane<P>, width: usize, height: usize) -> u64 {
+    a.rows_iter().zip(b.rows_iter()).take(height).map(|(ra, rb)| {
+        ra[..width].iter().zip(&rb[..width]).map(|(&pa, &pb)| {
+            let d = u64::from(Into::<u32>::into(pa).abs_diff(pb.into()));
+            d * d
+        }).sum::<u64>()
+    }).sum()
+}
+
+#[test]
+fn ssim_of_same_and_different() {
+    let a = Plane::from_slice(&(0..64 * 64).map(|i| (i % 251) as u8).collect::<Vec<_>>(), 64);
+    let b = Plane::from_slice(&(0..64 * 64).map(|i| ((i % 251) as u8).saturating_add(((i * 37) % 61) as u8)).collect::<Vec<_>>(), 64);
+    assert!((plane_ssim(&a, &a, 64, 64, 8) - 1.).abs() < 1e-9);
+    let s = plane_ssim(&a, &b, 64, 64, 8);
+    assert!(s < 0.99 && s > 0.5, "{s}");
+    // odd sizes
+    assert!(plane_ssim(&a, &b, 13, 5, 8) < 1.);
+    assert_eq!(1., plane_ssim(&a, &b, 0, 5, 8));
+    // the last column isn't in the windows that are STEP apart
+    let mut edge = (0..64 * 64).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    edge.iter_mut().skip(12).step_by(64).for_each(|px| *px ^= 0xFF);
+    assert!(plane_ssim(&a, &Plane::from_slice(&edge, 64), 13, 64, 8) < 0.99);
+
+    assert_eq!(0, plane_sse(&a, &a, 64, 64));
+    assert_eq!(61 * 61, plane_sse(&Plane::from_slice(&[0u8, 1], 2), &Plane::from_slice(&[61u8, 1], 2), 2, 1));
+}
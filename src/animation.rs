@@ -0,0 +1,224 @@
+//! Decodes animated GIF and APNG into full frames, which load_image doesn't support
+
+use crate::BoxError;
+use imgref::ImgRef;
+use ravif::RGBA8;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::time::Duration;
+
+/// Animation decoded one frame at a time, so that the frames don't have to be kept in memory
+pub struct Animation {
+    frames: Frames,
+    canvas: Canvas,
+    /// What the last frame asked to be done before drawing the next one
+    dispose: Option<Dispose>,
+    /// How many times to play again after the first time, `None` is forever
+    pub repetitions: Option<u32>,
+}
+
+enum Frames {
+    Gif {
+        decoder: Box<gif::Decoder<Cursor<Vec<u8>>>>,
+        /// Frames read ahead to check that the file is animated
+        pending: VecDeque<gif::Frame<'static>>,
+    },
+    Apng {
+        reader: Box<png::Reader<Cursor<Vec<u8>>>>,
+        buf: Vec<u8>,
+        remaining: u32,
+        first: bool,
+    },
+}
+
+struct Dispose {
+    area: Area,
+    clear: bool,
+    /// Pixels to restore
+    previous: Option<Vec<RGBA8>>,
+}
+
+/// Returns `None` if the file is not an animation, or has only one frame
+pub fn decode(data: &[u8]) -> Result<Option<Animation>, BoxError> {
+    if data.starts_with(b"GIF8") {
+        return decode_gif(data);
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return decode_apng(data);
+    }
+    Ok(None)
+}
+
+fn decode_gif(data: &[u8]) -> Result<Option<Animation>, BoxError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(Cursor::new(data.to_vec()))?;
+    let mut pending = VecDeque::with_capacity(2);
+    while pending.len() < 2 {
+        match decoder.read_next_frame()? {
+            Some(frame) => pending.push_back(frame.clone()),
+            None => return Ok(None),
+        }
+    }
+    let canvas = Canvas::new(usize::from(decoder.width()), usize::from(decoder.height()));
+    let repetitions = match decoder.repeat() {
+        gif::Repeat::Infinite => None,
+        gif::Repeat::Finite(n) => Some(n.into()),
+    };
+    Ok(Some(Animation { frames: Frames::Gif { decoder: Box::new(decoder), pending }, canvas, dispose: None, repetitions }))
+}
+
+fn decode_apng(data: &[u8]) -> Result<Option<Animation>, BoxError> {
+    let mut decoder = png::Decoder::new(Cursor::new(data.to_vec()));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let Some(control) = reader.info().animation_control else {
+        return Ok(None);
+    };
+    if control.num_frames < 2 {
+        return Ok(None);
+    }
+    let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+    let mut buf = vec![0; reader.output_buffer_size().ok_or("PNG too large")?];
+    // the default image may be only for decoders that don't support APNG
+    if reader.info().frame_control.is_none() {
+        reader.next_frame(&mut buf)?;
+    }
+    let repetitions = control.num_plays.checked_sub(1);
+    let frames = Frames::Apng { reader: Box::new(reader), buf, remaining: control.num_frames, first: true };
+    Ok(Some(Animation { frames, canvas: Canvas::new(width, height), dispose: None, repetitions }))
+}
+
+impl Animation {
+    /// The next complete frame, composed on top of the previous ones
+    pub fn next_frame(&mut self) -> Result<Option<(ImgRef<'_, RGBA8>, Duration)>, BoxError> {
+        if let Some(Dispose { area, clear, previous }) = self.dispose.take() {
+            if clear {
+                self.canvas.clear(area);
+            }
+            if let Some(previous) = previous {
+                self.canvas.pixels = previous;
+            }
+        }
+        let canvas = &mut self.canvas;
+        let duration = match &mut self.frames {
+            Frames::Gif { decoder, pending } => {
+                let frame = match pending.pop_front() {
+                    Some(frame) => frame,
+                    None => match decoder.read_next_frame()? {
+                        Some(frame) => frame.clone(),
+                        None => return Ok(None),
+                    },
+                };
+                let area = Area::new(frame.left.into(), frame.top.into(), frame.width.into(), frame.height.into());
+                let previous = (frame.dispose == gif::DisposalMethod::Previous).then(|| canvas.pixels.clone());
+                let pixels = frame.buffer.chunks_exact(4).map(|px| RGBA8::new(px[0], px[1], px[2], px[3]));
+                // transparent pixels leave the previous frame visible
+                canvas.draw(area, pixels, |dst, src| if src.a != 0 { *dst = src });
+                self.dispose = Some(Dispose { area, clear: frame.dispose == gif::DisposalMethod::Background, previous });
+
+                // browsers show frames without delay for 100ms
+                let delay = if frame.delay <= 1 { 10 } else { frame.delay };
+                Duration::from_millis(u64::from(delay) * 10)
+            },
+            Frames::Apng { reader, buf, remaining, first } => {
+                if *remaining == 0 {
+                    return Ok(None);
+                }
+                *remaining -= 1;
+                let out = reader.next_frame(buf)?;
+                let fc = reader.info().frame_control.ok_or("APNG frame without fcTL")?;
+                let area = Area::new(fc.x_offset as usize, fc.y_offset as usize, fc.width as usize, fc.height as usize);
+                let dispose = match fc.dispose_op {
+                    // there's nothing to go back to before the first frame
+                    png::DisposeOp::Previous if *first => png::DisposeOp::Background,
+                    op => op,
+                };
+                *first = false;
+                let previous = (dispose == png::DisposeOp::Previous).then(|| canvas.pixels.clone());
+
+                let samples = out.color_type.samples();
+                let pixels = buf[..out.buffer_size()].chunks_exact(samples).map(|px| match *px {
+                    [r, g, b, a] => RGBA8::new(r, g, b, a),
+                    [r, g, b] => RGBA8::new(r, g, b, 255),
+                    [l, a] => RGBA8::new(l, l, l, a),
+                    [l] => RGBA8::new(l, l, l, 255),
+                    _ => unreachable!(),
+                });
+                match fc.blend_op {
+                    png::BlendOp::Source => canvas.draw(area, pixels, |dst, src| *dst = src),
+                    png::BlendOp::Over => canvas.draw(area, pixels, blend_over),
+                }
+                self.dispose = Some(Dispose { area, clear: dispose == png::DisposeOp::Background, previous });
+
+                let den = if fc.delay_den == 0 { 100 } else { fc.delay_den };
+                Duration::from_secs(u64::from(fc.delay_num)) / u32::from(den)
+            },
+        };
+        Ok(Some((self.canvas.as_img(), duration)))
+    }
+}
+
+/// Non-premultiplied alpha compositing
+fn blend_over(dst: &mut RGBA8, src: RGBA8) {
+    if src.a == 255 || dst.a == 0 {
+        *dst = src;
+        return;
+    }
+    let src_a = u32::from(src.a);
+    let dst_a = u32::from(dst.a) * (255 - src_a) / 255;
+    let out_a = src_a + dst_a;
+    if out_a == 0 {
+        *dst = RGBA8::default();
+        return;
+    }
+    let mix = |s: u8, d: u8| ((u32::from(s) * src_a + u32::from(d) * dst_a + out_a / 2) / out_a) as u8;
+    *dst = RGBA8::new(mix(src.r, dst.r), mix(src.g, dst.g), mix(src.b, dst.b), out_a as u8);
+}
+
+/// Rectangle of a frame, clipped to the canvas
+#[derive(Clone, Copy)]
+struct Area {
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Area {
+    fn new(left: usize, top: usize, width: usize, height: usize) -> Self {
+        Self { left, top, width, height }
+    }
+}
+
+struct Canvas {
+    pixels: Vec<RGBA8>,
+    width: usize,
+    height: usize,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Self { pixels: vec![RGBA8::default(); width * height], width, height }
+    }
+
+    /// `pixels` are `area.width` wide
+    fn draw(&mut self, area: Area, mut pixels: impl Iterator<Item = RGBA8>, mut op: impl FnMut(&mut RGBA8, RGBA8)) {
+        for y in area.top..area.top + area.height {
+            for (x, src) in (area.left..area.left + area.width).zip(pixels.by_ref()) {
+                if x < self.width && y < self.height {
+                    op(&mut self.pixels[y * self.width + x], src);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self, area: Area) {
+        let width = area.width;
+        self.draw(area, std::iter::repeat_n(RGBA8::default(), width * area.height), |dst, src| *dst = src);
+    }
+
+    fn as_img(&self) -> ImgRef<'_, RGBA8> {
+        ImgRef::new(&self.pixels, self.width, self.height)
+    }
+}
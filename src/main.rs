@@ -1,10 +1,15 @@
+use clap::parser::ValueSource;
 use clap::{value_parser, Arg, ArgAction, Command};
 use imgref::ImgVec;
-use ravif::{AlphaColorMode, BitDepth, ColorModel, EncodedImage, Encoder, RGBA8};
+use ravif::{AlphaColorMode, AnimationEncoder, ChromaSubsampling, ColorModel, EncodedImage, Encoder, EncoderConfig, EncodingPhase, Mirror, Progress, RGBA16, RGBA8};
 use rayon::prelude::*;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+mod animation;
+mod metadata;
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -25,6 +30,14 @@ enum MaybePath {
     Path(PathBuf),
 }
 
+/// Decoded input image. Deep images are kept at 16 bits per channel.
+enum Image {
+    Rgba8(ImgVec<RGBA8>),
+    Rgba16(ImgVec<RGBA16>),
+    Animation(animation::Animation),
+}
+
+#[allow(clippy::manual_range_contains)]
 fn parse_quality(arg: &str) -> Result<f32, String> {
     let q = arg.parse::<f32>().map_err(|e| e.to_string())?;
     if q < 1. || q > 100. {
@@ -33,14 +46,31 @@ fn parse_quality(arg: &str) -> Result<f32, String> {
     Ok(q)
 }
 
+#[allow(clippy::manual_range_contains)]
 fn parse_speed(arg: &str) -> Result<u8, String> {
     let s = arg.parse::<u8>().map_err(|e| e.to_string())?;
-    if s < 1 || s > 100 {
+    if s < 1 || s > 10 {
         return Err("speed must be in 1-10 range".into());
     }
     Ok(s)
 }
 
+fn parse_ssim(arg: &str) -> Result<f64, String> {
+    let s = arg.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(0. ..=1.).contains(&s) {
+        return Err("SSIM must be in 0-1 range".into());
+    }
+    Ok(s)
+}
+
+fn parse_grain(arg: &str) -> Result<u8, String> {
+    let g = arg.parse::<u8>().map_err(|e| e.to_string())?;
+    if !(1..=64).contains(&g) {
+        return Err("grain must be in 1-64 range".into());
+    }
+    Ok(g)
+}
+
 fn run() -> Result<(), BoxError> {
     let args = Command::new("cavif-rs")
         .version(clap::crate_version!())
@@ -67,6 +97,24 @@ fn run() -> Result<(), BoxError> {
             .default_value("0")
             .value_parser(value_parser!(u8))
             .help("Maximum threads to use (0 = one thread per host core)"))
+        .arg(Arg::new("max-size")
+            .long("max-size")
+            .value_name("bytes")
+            .value_parser(value_parser!(usize))
+            .help("Lower quality as much as needed to make files no larger than this. Tries multiple encodes, so it's slower"))
+        .arg(Arg::new("target-ssim")
+            .long("target-ssim")
+            .value_name("0-1")
+            .value_parser(parse_ssim)
+            .help("Pick the lowest quality that keeps SSIM at this level, e.g. 0.98. Tries multiple encodes, so it's slower"))
+        .arg(Arg::new("grain")
+            .long("grain")
+            .value_name("n")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("8")
+            .value_parser(parse_grain)
+            .help("Remove noise and let the decoder add back similar film grain, 1 (fine) to 64 (heavy), 8 if omitted. Makes noisy photos much smaller"))
         .arg(Arg::new("overwrite")
             .alias("force")
             .short('f')
@@ -91,6 +139,24 @@ fn run() -> Result<(), BoxError> {
             .action(ArgAction::SetTrue)
             .num_args(0)
             .help("Keep RGB data of fully-transparent pixels (makes larger, lower quality files)"))
+        .arg(Arg::new("keep-icc")
+            .long("keep-icc")
+            .action(ArgAction::SetTrue)
+            .num_args(0)
+            .overrides_with("strip")
+            .help("Embed the source image's RGB color profile instead of converting pixels to sRGB (keeps wide gamut of Display P3 or Adobe RGB images)"))
+        .arg(Arg::new("keep-metadata")
+            .long("keep-metadata")
+            .action(ArgAction::SetTrue)
+            .num_args(0)
+            .overrides_with("strip")
+            .help("Copy Exif and XMP metadata (camera info, capture date, copyright, GPS location) from the source image"))
+        .arg(Arg::new("strip")
+            .long("strip")
+            .action(ArgAction::SetTrue)
+            .num_args(0)
+            .overrides_with_all(["keep-metadata", "keep-icc"])
+            .help("Don't copy any metadata or color profile from the source image (default)"))
         .arg(Arg::new("color")
             .long("color")
             .default_value("ycbcr")
@@ -99,8 +165,24 @@ fn run() -> Result<(), BoxError> {
         .arg(Arg::new("depth")
             .long("depth")
             .default_value("auto")
-            .value_parser(["8", "10", "auto"])
-            .help("Write 8-bit (more compatible) or 10-bit (better quality) images"))
+            .value_parser(["8", "10", "12", "auto"])
+            .help("Write 8-bit (more compatible), 10-bit (better quality), or 12-bit (archival, least compatible) images"))
+        .arg(Arg::new("subsample")
+            .long("subsample")
+            .value_name("mode")
+            .default_value("444")
+            .value_parser(["444", "422", "420"])
+            .help("Chroma subsampling. Use 420 only for decoders that can't display full-resolution color"))
+        .arg(Arg::new("preset")
+            .long("preset")
+            .value_name("name")
+            .value_parser(EncoderConfig::PRESETS)
+            .help("Start from settings for a common use. Options given explicitly override the preset"))
+        .arg(Arg::new("config")
+            .long("config")
+            .value_name("file")
+            .value_parser(value_parser!(PathBuf))
+            .help("Load settings from a file with comma- or newline-separated key=value pairs and preset names, e.g. \"web,q=70,depth=10\""))
         .arg(Arg::new("IMAGES")
             .index(1)
             .num_args(1..)
@@ -112,25 +194,49 @@ fn run() -> Result<(), BoxError> {
         s if s.as_os_str() == "-" => MaybePath::Stdio,
         s => MaybePath::Path(PathBuf::from(s)),
     });
-    let quality = *args.get_one::<f32>("quality").expect("default");
-    let alpha_quality = ((quality + 100.) / 2.).min(quality + quality / 4. + 2.);
-    let speed: u8 = *args.get_one::<u8>("speed").expect("default");
     let overwrite = args.get_flag("overwrite");
     let quiet = args.get_flag("quiet");
-    let threads = args.get_one::<u8>("threads").copied();
-    let dirty_alpha = args.get_flag("dirty-alpha");
-
-    let color_model = match args.get_one::<String>("color").expect("default").as_str() {
-        "ycbcr" => ColorModel::YCbCr,
-        "rgb" => ColorModel::RGB,
-        x => Err(format!("bad color type: {x}"))?,
-    };
+    let keep_icc = args.get_flag("keep-icc");
+    let keep_metadata = args.get_flag("keep-metadata");
+    let max_size = args.get_one::<usize>("max-size").copied();
+    let target_ssim = args.get_one::<f64>("target-ssim").copied();
 
-    let depth = match args.get_one::<String>("depth").expect("default").as_str() {
-        "8" => BitDepth::Eight,
-        "10" => BitDepth::Ten,
-        _ => BitDepth::Auto,
-    };
+    // the preset and the file are applied first, and only the options given explicitly override them
+    let mut base = args.get_one::<String>("preset").cloned().unwrap_or_default();
+    if let Some(path) = args.get_one::<PathBuf>("config") {
+        let file = fs::read_to_string(path).map_err(|e| format!("Unable to read config {}: {e}", path.display()))?;
+        base = format!("{base}\n{file}");
+    }
+    let has_base = !base.is_empty();
+    let mut config: EncoderConfig = base.parse().map_err(|e| format!("Invalid config: {e}"))?;
+    let given = |id: &str| !has_base || args.value_source(id) == Some(ValueSource::CommandLine);
+    if given("quality") {
+        config.quality = *args.get_one::<f32>("quality").expect("default");
+    }
+    if given("speed") {
+        config.speed = *args.get_one::<u8>("speed").expect("default");
+    }
+    if given("threads") {
+        config.threads = args.get_one::<u8>("threads").copied().filter(|&n| n > 0).map(usize::from);
+    }
+    if given("color") {
+        config.color_model = args.get_one::<String>("color").expect("default").parse()?;
+    }
+    if given("depth") {
+        config.bit_depth = args.get_one::<String>("depth").expect("default").parse()?;
+    }
+    if given("subsample") {
+        config.chroma_subsampling = args.get_one::<String>("subsample").expect("default").parse()?;
+    }
+    if args.get_flag("dirty-alpha") {
+        config.alpha_color_mode = AlphaColorMode::UnassociatedDirty;
+    }
+    if let Some(&grain) = args.get_one::<u8>("grain") {
+        config.film_grain = grain;
+    }
+    if config.chroma_subsampling != ChromaSubsampling::Cs444 && config.color_model == ColorModel::RGB {
+        return Err("chroma subsampling requires --color=ycbcr".into());
+    }
 
     let files = args.get_many::<PathBuf>("IMAGES").ok_or("Please specify image paths to convert")?;
     let files: Vec<_> = files
@@ -175,9 +281,25 @@ fn run() -> Result<(), BoxError> {
         },
         _ => false,
     };
+    // bars of files converted in parallel would overwrite each other
+    let show_progress = !quiet && files.len() == 1 && std::io::stderr().is_terminal();
 
     let process = move |data: Vec<u8>, input_path: &MaybePath| -> Result<(), BoxError> {
-        let img = load_rgba(&data, false)?;
+        let orientation = metadata::exif(&data).and_then(|exif| metadata::exif_orientation(&exif)).unwrap_or(1);
+        let (img, icc_profile) = match animation::decode(&data)? {
+            // frames aren't color-managed, so the profile still applies to them
+            Some(anim) => (Image::Animation(anim), metadata::icc_profile(&data).filter(|icc| keep_icc && metadata::is_rgb_icc_profile(icc))),
+            None => load_rgba(&data, keep_icc, orientation)?,
+        };
+        let (exif, xmp) = if keep_metadata {
+            let exif = metadata::exif(&data).map(|mut exif| {
+                metadata::reset_exif_orientation(&mut exif);
+                exif
+            });
+            (exif, metadata::xmp(&data))
+        } else {
+            (None, None)
+        };
         drop(data);
         let out_path = match (&output, input_path) {
             (None, MaybePath::Path(input)) => MaybePath::Path(input.with_extension("avif")),
@@ -198,15 +320,39 @@ fn run() -> Result<(), BoxError> {
             },
             _ => {},
         }
-        let enc = Encoder::new()
-            .with_quality(quality)
-            .with_bit_depth(depth)
-            .with_speed(speed)
-            .with_alpha_quality(alpha_quality)
-            .with_internal_color_model(color_model)
-            .with_alpha_color_mode(if dirty_alpha { AlphaColorMode::UnassociatedDirty } else { AlphaColorMode::UnassociatedClean })
-            .with_num_threads(threads.filter(|&n| n > 0).map(usize::from));
-        let EncodedImage { avif_file, color_byte_size, alpha_byte_size , .. } = enc.encode_rgba(img.as_ref())?;
+        let enc = Encoder::new().try_with_config(&config)?;
+        let (rotation, mirror) = exif_orientation_transform(orientation);
+        let enc = enc.with_rotation(rotation);
+        let enc = if let Some(mirror) = mirror { enc.with_mirror(mirror) } else { enc };
+        let enc = if let Some(max_size) = max_size { enc.with_target_size(max_size) } else { enc };
+        let enc = if let Some(target_ssim) = target_ssim { enc.with_target_ssim(target_ssim) } else { enc };
+        let enc = if let Some(icc_profile) = icc_profile { enc.with_icc_profile(icc_profile) } else { enc };
+        let enc = if let Some(exif) = exif { enc.with_exif(exif) } else { enc };
+        let enc = if let Some(xmp) = xmp { enc.with_xmp(xmp) } else { enc };
+        let pixel_count = match &img {
+            Image::Rgba8(img) => img.width() * img.height(),
+            Image::Rgba16(img) => img.width() * img.height(),
+            // frames are decoded while encoding, so their number isn't known
+            Image::Animation(_) => 0,
+        };
+        let show_progress = show_progress && pixel_count >= PROGRESS_BAR_MIN_PIXELS;
+        let enc = if show_progress { enc.with_progress(progress_bar()) } else { enc };
+        let res = match img {
+            Image::Rgba8(img) => enc.encode_rgba(img.as_ref()),
+            Image::Rgba16(img) => enc.encode_rgba16(img.as_ref()),
+            Image::Animation(mut anim) => {
+                let mut anim_enc = AnimationEncoder::new(enc).with_repetitions(anim.repetitions);
+                while let Some((frame, duration)) = anim.next_frame()? {
+                    anim_enc.add_frame(frame, duration)?;
+                }
+                anim_enc.finish()
+            },
+        };
+        if show_progress {
+            // clears the line
+            eprint!("\r\x1b[K");
+        }
+        let EncodedImage { avif_file, color_byte_size, alpha_byte_size, .. } = res?;
         match out_path {
             MaybePath::Path(ref p) => {
                 if !quiet {
@@ -251,37 +397,106 @@ fn run() -> Result<(), BoxError> {
     Ok(())
 }
 
+/// Smaller images are encoded too quickly to need a progress bar
+const PROGRESS_BAR_MIN_PIXELS: usize = 4_000_000;
+
+/// Draws a bar on stderr. Phases are mapped to parts of the bar, and it never goes back.
+fn progress_bar() -> impl Fn(Progress) + Send + Sync {
+    let drawn = AtomicU32::new(0);
+    move |p: Progress| {
+        // alpha is encoded at the same time as color, and is usually faster
+        let (start, end, label) = match p.phase {
+            EncodingPhase::Preprocessing => (0., 5., "preparing"),
+            EncodingPhase::FrameFill => (5., 10., "converting"),
+            EncodingPhase::ColorEncode => (10., 95., "encoding"),
+            EncodingPhase::Muxing => (95., 100., "writing"),
+            _ => return,
+        };
+        let percent = (start + (end - start) * p.fraction) as u32;
+        if drawn.fetch_max(percent, Ordering::Relaxed) < percent {
+            let width = 40 * percent as usize / 100;
+            eprint!("\r[{:#<width$}{:<rest$}] {percent:3}% {label:<10}", "", "", rest = 40 - width);
+        }
+    }
+}
+
+/// Anti-clockwise quarter turns and mirroring (applied after rotation) that display the image in its Exif `orientation`
+fn exif_orientation_transform(orientation: u16) -> (u8, Option<Mirror>) {
+    match orientation {
+        2 => (0, Some(Mirror::LeftRight)),
+        3 => (2, None),
+        4 => (0, Some(Mirror::TopBottom)),
+        5 => (1, Some(Mirror::TopBottom)),
+        6 => (3, None),
+        7 => (3, Some(Mirror::TopBottom)),
+        8 => (1, None),
+        _ => (0, None),
+    }
+}
+
+/// Returns pixels to the order they're stored in the file, before they were displayed in the Exif `orientation`
 #[cfg(not(feature = "cocoa_image"))]
-fn load_rgba(data: &[u8], premultiplied_alpha: bool) -> Result<ImgVec<RGBA8>, BoxError> {
-    use rgb::prelude::*;
+fn unrotate<T: Copy>(img: ImgVec<T>, orientation: u16) -> ImgVec<T> {
+    if !(2..=8).contains(&orientation) {
+        return img;
+    }
+    let (width, height) = if orientation >= 5 { (img.height(), img.width()) } else { (img.width(), img.height()) };
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let displayed_at = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (height - 1 - y, x),
+                7 => (height - 1 - y, width - 1 - x),
+                _ => (y, width - 1 - x),
+            };
+            pixels.push(img[displayed_at]);
+        }
+    }
+    ImgVec::new(pixels, width, height)
+}
 
-    let img = load_image::load_data(data)?.into_imgvec();
+/// Returns the image and its ICC profile if `keep_icc_profile` is set and the pixels haven't been converted to sRGB.
+/// Pixels are in the stored orientation, not rotated according to Exif `orientation`.
+#[cfg(not(feature = "cocoa_image"))]
+fn load_rgba(data: &[u8], keep_icc_profile: bool, orientation: u16) -> Result<(Image, Option<Vec<u8>>), BoxError> {
+    use load_image::export::imgref::ImgVecKind;
+
+    let icc_profile = if keep_icc_profile {
+        metadata::icc_profile(data).filter(|icc| metadata::is_rgb_icc_profile(icc))
+    } else {
+        None
+    };
+    let img = load_image::Loader::new()
+        .profiles(if icc_profile.is_some() { load_image::Profiles::None } else { load_image::Profiles::NonsRGB })
+        .load_data(data)?
+        .into_imgvec();
     let mut img = match img {
-        load_image::export::imgref::ImgVecKind::RGB8(img) => img.map_buf(|buf| buf.into_iter().map(|px| px.with_alpha(255)).collect()),
-        load_image::export::imgref::ImgVecKind::RGBA8(img) => img,
-        load_image::export::imgref::ImgVecKind::RGB16(img) => img.map_buf(|buf| buf.into_iter().map(|px| px.map(|c| (c >> 8) as u8).with_alpha(255)).collect()),
-        load_image::export::imgref::ImgVecKind::RGBA16(img) => img.map_buf(|buf| buf.into_iter().map(|px| px.map(|c| (c >> 8) as u8)).collect()),
-        load_image::export::imgref::ImgVecKind::GRAY8(img) => img.map_buf(|buf| buf.into_iter().map(|g| { let c = g.0; RGBA8::new(c,c,c,255) }).collect()),
-        load_image::export::imgref::ImgVecKind::GRAY16(img) => img.map_buf(|buf| buf.into_iter().map(|g| { let c = (g.0>>8) as u8; RGBA8::new(c,c,c,255) }).collect()),
-        load_image::export::imgref::ImgVecKind::GRAYA8(img) => img.map_buf(|buf| buf.into_iter().map(|g| { let c = g.0; RGBA8::new(c,c,c,g.1) }).collect()),
-        load_image::export::imgref::ImgVecKind::GRAYA16(img) => img.map_buf(|buf| buf.into_iter().map(|g| { let c = (g.0>>8) as u8; RGBA8::new(c,c,c,(g.1>>8) as u8) }).collect()),
+        ImgVecKind::RGB8(img) => Image::Rgba8(img.map_buf(|buf| buf.into_iter().map(|px| px.with_alpha(255)).collect())),
+        ImgVecKind::RGBA8(img) => Image::Rgba8(img),
+        ImgVecKind::RGB16(img) => Image::Rgba16(img.map_buf(|buf| buf.into_iter().map(|px| px.with_alpha(0xFFFF)).collect())),
+        ImgVecKind::RGBA16(img) => Image::Rgba16(img),
+        ImgVecKind::GRAY8(img) => Image::Rgba8(img.map_buf(|buf| buf.into_iter().map(|g| { let c = g.value(); RGBA8::new(c,c,c,255) }).collect())),
+        ImgVecKind::GRAY16(img) => Image::Rgba16(img.map_buf(|buf| buf.into_iter().map(|g| { let c = g.value(); RGBA16::new(c,c,c,0xFFFF) }).collect())),
+        ImgVecKind::GRAYA8(img) => Image::Rgba8(img.map_buf(|buf| buf.into_iter().map(|g| { let c = g.v; RGBA8::new(c,c,c,g.a) }).collect())),
+        ImgVecKind::GRAYA16(img) => Image::Rgba16(img.map_buf(|buf| buf.into_iter().map(|g| { let c = g.v; RGBA16::new(c,c,c,g.a) }).collect())),
     };
-
-    if premultiplied_alpha {
-        img.pixels_mut().for_each(|px| {
-            px.r = (u16::from(px.r) * u16::from(px.a) / 255) as u8;
-            px.g = (u16::from(px.g) * u16::from(px.a) / 255) as u8;
-            px.b = (u16::from(px.b) * u16::from(px.a) / 255) as u8;
-        });
+    // load_image applies the Exif rotation to JPEGs, but the encoder stores it losslessly in irot/imir instead
+    if data.first() == Some(&0xFF) {
+        img = match img {
+            Image::Rgba8(img) => Image::Rgba8(unrotate(img, orientation)),
+            Image::Rgba16(img) => Image::Rgba16(unrotate(img, orientation)),
+            Image::Animation(_) => return Err("load_image doesn't decode animations".into()),
+        };
     }
-    Ok(img)
+    Ok((img, icc_profile))
 }
 
+/// The system decoder always converts to sRGB, so profiles are never kept. It doesn't apply the Exif orientation.
 #[cfg(feature = "cocoa_image")]
-fn load_rgba(data: &[u8], premultiplied_alpha: bool) -> Result<ImgVec<RGBA8>, BoxError> {
-    if premultiplied_alpha {
-        Ok(cocoa_image::decode_image_as_rgba_premultiplied(data)?)
-    } else {
-        Ok(cocoa_image::decode_image_as_rgba(data)?)
-    }
+fn load_rgba(data: &[u8], _keep_icc_profile: bool, _orientation: u16) -> Result<(Image, Option<Vec<u8>>), BoxError> {
+    Ok((Image::Rgba8(cocoa_image::decode_image_as_rgba(data)?), None))
 }
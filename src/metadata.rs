@@ -0,0 +1,131 @@
+//! Reads metadata embedded in PNG and JPEG files, which load_image doesn't expose in its original form
+
+use std::io::Read;
+
+/// ICC profile from PNG `iCCP` chunk or JPEG `APP2` segments
+pub fn icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let (_, iccp) = png_chunks(data).find(|(name, _)| name == b"iCCP")?;
+        // profile name, then compression method (always 0 = zlib)
+        let compressed = iccp.get(iccp.iter().position(|&b| b == 0)? + 2..)?;
+        let mut icc = Vec::new();
+        flate2::read::ZlibDecoder::new(compressed).read_to_end(&mut icc).ok()?;
+        return Some(icc);
+    }
+
+    // large profiles are split into numbered chunks
+    let mut chunks: Vec<_> = jpeg_segments(data)
+        .filter(|&(marker, _)| marker == 0xE2)
+        .filter_map(|(_, seg)| seg.strip_prefix(b"ICC_PROFILE\0"))
+        .filter_map(|seg| Some((*seg.first()?, seg.get(2..)?)))
+        .collect();
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(seq, _)| seq);
+    Some(chunks.into_iter().flat_map(|(_, seg)| seg).copied().collect())
+}
+
+/// TIFF-format Exif from JPEG `APP1` segment or PNG `eXIf` chunk
+pub fn exif(data: &[u8]) -> Option<Vec<u8>> {
+    let tiff = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_chunks(data).find(|(name, _)| name == b"eXIf")?.1
+    } else {
+        jpeg_segments(data)
+            .filter(|&(marker, _)| marker == 0xE1)
+            .find_map(|(_, seg)| seg.strip_prefix(b"Exif\0\0"))?
+    };
+    Some(tiff.to_vec())
+}
+
+/// XMP packet from JPEG `APP1` segment or PNG `iTXt` chunk
+pub fn xmp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let text = png_chunks(data)
+            .filter(|(name, _)| name == b"iTXt")
+            .find_map(|(_, itxt)| itxt.strip_prefix(b"XML:com.adobe.xmp\0"))?;
+        // compression flag and method, then language and translated keyword
+        let [compressed, _, ref rest @ ..] = *text else { return None };
+        let lang_end = rest.iter().position(|&b| b == 0)?;
+        let keyword_end = lang_end + 1 + rest[lang_end + 1..].iter().position(|&b| b == 0)?;
+        let text = &rest[keyword_end + 1..];
+        if compressed != 0 {
+            let mut xmp = Vec::new();
+            flate2::read::ZlibDecoder::new(text).read_to_end(&mut xmp).ok()?;
+            return Some(xmp);
+        }
+        return Some(text.to_vec());
+    }
+
+    jpeg_segments(data)
+        .filter(|&(marker, _)| marker == 0xE1)
+        .find_map(|(_, seg)| seg.strip_prefix(b"http://ns.adobe.com/xap/1.0/\0"))
+        .map(|xmp| xmp.to_vec())
+}
+
+/// The orientation is stored in `irot`/`imir` properties, so the Exif must not rotate the image again
+pub fn reset_exif_orientation(tiff: &mut [u8]) {
+    if let Some((pos, big_endian)) = exif_orientation_position(tiff) {
+        tiff[pos..pos + 2].copy_from_slice(&if big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() });
+    }
+}
+
+/// Exif orientation tag value, 1 to 8
+pub fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let (pos, big_endian) = exif_orientation_position(tiff)?;
+    let bytes = tiff[pos..pos + 2].try_into().ok()?;
+    Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }).filter(|o| (1..=8).contains(o))
+}
+
+/// Position of the value of the orientation tag, and whether the TIFF is big-endian
+fn exif_orientation_position(tiff: &[u8]) -> Option<(usize, bool)> {
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |pos: usize| -> Option<u16> {
+        let bytes = tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let ifd0 = tiff.get(4..8)?.try_into().ok()?;
+    let ifd0 = if big_endian { u32::from_be_bytes(ifd0) } else { u32::from_le_bytes(ifd0) } as usize;
+    let entries = read_u16(ifd0)?;
+    (0..usize::from(entries)).map(|i| ifd0 + 2 + i * 12).find_map(|entry| {
+        // orientation tag, SHORT type, value stored inline
+        (read_u16(entry) == Some(0x0112) && read_u16(entry + 2) == Some(3) && tiff.len() >= entry + 10).then_some((entry + 8, big_endian))
+    })
+}
+
+/// The profile can be kept only if it matches the RGB pixels given to the encoder
+pub fn is_rgb_icc_profile(icc: &[u8]) -> bool {
+    icc.get(16..20) == Some(b"RGB ")
+}
+
+/// Name and data of PNG chunks. Stops at the first malformed chunk.
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = data.get(8..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(rest.get(0..4)?.try_into().ok()?) as usize;
+        let name = rest.get(4..8)?.try_into().ok()?;
+        let chunk = rest.get(8..8 + len)?;
+        rest = rest.get(8 + len + 4..)?; // + CRC
+        Some((name, chunk))
+    })
+}
+
+/// Marker and payload of JPEG segments before the image data
+fn jpeg_segments(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = data.strip_prefix(b"\xFF\xD8").unwrap_or_default();
+    std::iter::from_fn(move || {
+        let [0xFF, marker, hi, lo, ..] = *rest else { return None };
+        // start of scan is followed by compressed data
+        if marker == 0xDA {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([hi, lo]));
+        let segment = rest.get(4..2 + len)?;
+        rest = &rest[2 + len..];
+        Some((marker, segment))
+    })
+}
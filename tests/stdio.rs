@@ -41,3 +41,208 @@ fn path_to_stdout() -> Result<(), std::io::Error> {
     avif_parse::read_avif(&mut data.as_slice()).unwrap();
     Ok(())
 }
+
+#[test]
+fn max_size() -> Result<(), std::io::Error> {
+    let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_cavif"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .arg("tests/testimage.png")
+        .arg("--speed=10")
+        .arg("--max-size=1500")
+        .arg("-o")
+        .arg("-")
+        .spawn()?;
+
+    let mut data = Vec::new();
+    cmd.stdout.take().unwrap().read_to_end(&mut data)?;
+    assert!(cmd.wait()?.success());
+    assert!(data.len() <= 1500);
+    avif_parse::read_avif(&mut data.as_slice()).unwrap();
+    Ok(())
+}
+
+#[test]
+fn invalid_speed() -> Result<(), std::io::Error> {
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_cavif"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .arg("tests/testimage.png")
+        .arg("--speed=11")
+        .arg("-o")
+        .arg("-")
+        .status()?;
+    assert!(!status.success());
+    Ok(())
+}
+
+/// Test image with extra chunks inserted after IHDR
+fn png_with_chunks(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let img = include_bytes!("testimage.png");
+    let ihdr_end = 8 + 8 + 13 + 4;
+    let mut png = img[..ihdr_end].to_vec();
+    for (name, data) in chunks {
+        let mut crc = flate2::Crc::new();
+        crc.update(*name);
+        crc.update(data);
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(*name);
+        png.extend_from_slice(data);
+        png.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+    png.extend_from_slice(&img[ihdr_end..]);
+    png
+}
+
+fn convert_stdio(input: Vec<u8>, args: &[&str]) -> Result<Vec<u8>, std::io::Error> {
+    let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_cavif"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .arg("-")
+        .arg("--speed=10")
+        .args(args)
+        .spawn()?;
+
+    let mut stdin = cmd.stdin.take().unwrap();
+    let _ = std::thread::spawn(move || {
+        stdin.write_all(&input).unwrap();
+    });
+
+    let mut data = Vec::new();
+    cmd.stdout.take().unwrap().read_to_end(&mut data)?;
+    assert!(cmd.wait()?.success());
+    // the parser rejects animations, but can check their still image
+    let mut still = data.clone();
+    if still[8..12] == *b"avis" {
+        still[8..12].copy_from_slice(b"avif");
+    }
+    avif_parse::read_avif(&mut still.as_slice()).unwrap();
+    Ok(data)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[test]
+fn keep_icc() -> Result<(), std::io::Error> {
+    let mut icc = vec![0u8; 132];
+    icc[16..20].copy_from_slice(b"RGB ");
+    icc[36..40].copy_from_slice(b"acsp");
+    let mut zlib = flate2::write::ZlibEncoder::new(b"test\0\0".to_vec(), flate2::Compression::default());
+    zlib.write_all(&icc)?;
+    let png = png_with_chunks(&[(b"iCCP", &zlib.finish()?)]);
+
+    let data = convert_stdio(png.clone(), &["--keep-icc"])?;
+    let pos = find(&data, b"colrprof").expect("colr prof box");
+    assert_eq!(&data[pos + 8..pos + 8 + icc.len()], &icc[..]);
+
+    let data = convert_stdio(png, &["--keep-icc", "--strip"])?;
+    assert!(find(&data, b"colrprof").is_none());
+    Ok(())
+}
+
+#[test]
+fn keep_metadata() -> Result<(), std::io::Error> {
+    // orientation = 6, which must be reset, because irot rotates the image already
+    let exif = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0";
+    let xmp = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>";
+    let png = png_with_chunks(&[(b"eXIf", exif), (b"iTXt", &[&b"XML:com.adobe.xmp\0\0\0\0\0"[..], xmp].concat())]);
+
+    let data = convert_stdio(png.clone(), &["--keep-metadata"])?;
+    let pos = find(&data, &exif[..18]).expect("exif");
+    assert_eq!(&data[pos + 18..pos + 20], &[0, 1]);
+    assert!(find(&data, xmp).is_some());
+    assert!(find(&data, b"cdsc").is_some());
+
+    let data = convert_stdio(png, &[])?;
+    assert!(find(&data, &exif[..18]).is_none());
+    assert!(find(&data, xmp).is_none());
+    Ok(())
+}
+
+#[test]
+fn exif_orientation() -> Result<(), std::io::Error> {
+    // orientation = 5 (transposed)
+    let exif = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x05\0\0\0\0\0\0";
+    let data = convert_stdio(png_with_chunks(&[(b"eXIf", exif)]), &[])?;
+    assert!(find(&data, b"irot\x01").is_some());
+    assert!(find(&data, b"imir\x00").is_some());
+
+    let data = convert_stdio(include_bytes!("testimage.png").to_vec(), &[])?;
+    assert!(find(&data, b"irot").is_none());
+    assert!(find(&data, b"imir").is_none());
+    Ok(())
+}
+
+#[test]
+fn preset_and_config() -> Result<(), std::io::Error> {
+    let img = include_bytes!("testimage.png").to_vec();
+    let bits = |data: &[u8]| data[find(data, b"pixi").expect("pixi") + 9];
+    assert_eq!(10, bits(&convert_stdio(img.clone(), &[])?));
+    assert_eq!(8, bits(&convert_stdio(img.clone(), &["--preset=thumbnail"])?));
+
+    let config = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("preset_and_config.conf");
+    std::fs::write(&config, "# archival\nphoto-archive\nq=90\n")?;
+    let config = config.to_str().unwrap();
+    assert_eq!(12, bits(&convert_stdio(img.clone(), &["--config", config])?));
+    // options given explicitly override the config
+    assert_eq!(8, bits(&convert_stdio(img, &["--config", config, "--depth=8"])?));
+    Ok(())
+}
+
+fn u32_after(haystack: &[u8], needle: &[u8], offset: usize) -> u32 {
+    let pos = find(haystack, needle).expect("box") + needle.len() + offset;
+    u32::from_be_bytes(haystack[pos..pos + 4].try_into().unwrap())
+}
+
+#[test]
+fn animated_gif() -> Result<(), std::io::Error> {
+    let mut gif = Vec::new();
+    let mut enc = gif::Encoder::new(&mut gif, 16, 16, &[0, 0, 0, 255, 255, 255, 255, 0, 0]).unwrap();
+    enc.set_repeat(gif::Repeat::Finite(2)).unwrap();
+    for n in 0..3u8 {
+        // red square moving over the background kept from the first frame
+        let mut frame = if n == 0 {
+            gif::Frame::from_indexed_pixels(16, 16, (0..256).map(|i| (i % 3 == 0) as u8).collect::<Vec<_>>(), None)
+        } else {
+            gif::Frame::from_indexed_pixels(4, 4, vec![2; 16], None)
+        };
+        frame.left = u16::from(n) * 4;
+        frame.delay = 5;
+        enc.write_frame(&frame).unwrap();
+    }
+    drop(enc);
+
+    let data = convert_stdio(gif, &[])?;
+    assert_eq!(&data[8..12], b"avis");
+    assert_eq!(3, u32_after(&data, b"stsz", 8));
+    // 3 × 50ms, played 3 times
+    assert_eq!(3 * 150, u32_after(&data, b"mvhd", 28));
+    assert!(find(&data, b"auxv").is_none());
+    Ok(())
+}
+
+#[test]
+fn animated_png() -> Result<(), std::io::Error> {
+    let mut apng = Vec::new();
+    let mut enc = png::Encoder::new(&mut apng, 16, 16);
+    enc.set_color(png::ColorType::Rgba);
+    enc.set_animated(2, 1).unwrap();
+    enc.set_frame_delay(1, 10).unwrap();
+    let mut writer = enc.write_header().unwrap();
+    writer.write_image_data(&[255, 0, 0, 255].repeat(256)).unwrap();
+    writer.write_image_data(&[0, 0, 255, 128].repeat(256)).unwrap();
+    writer.finish().unwrap();
+
+    let data = convert_stdio(apng, &[])?;
+    assert_eq!(&data[8..12], b"avis");
+    assert_eq!(2, u32_after(&data, b"stsz", 8));
+    // played once, without the repeat flag in the edit list
+    assert_eq!(200, u32_after(&data, b"mvhd", 28));
+    assert_eq!(0, u32_after(&data, b"elst", 0) & 0xFF_FFFF);
+    // the second frame has alpha
+    assert!(find(&data, b"auxv").is_some());
+    Ok(())
+}